@@ -0,0 +1,259 @@
+use bevy::math::{Quat, Vec2, Vec3};
+
+use crate::{BitError, BitReader, BitWriter, NetQuantize, NetRanged, NetSerialize};
+
+impl NetSerialize for bool {
+    fn net_serialize(&self, writer: &mut BitWriter) {
+        writer.write_bool(*self);
+    }
+
+    fn net_deserialize(reader: &mut BitReader) -> Result<Self, BitError> {
+        reader.read_bool()
+    }
+}
+
+macro_rules! impl_unsigned {
+    ($($ty:ty),*) => {$(
+        impl NetSerialize for $ty {
+            fn net_serialize(&self, writer: &mut BitWriter) {
+                writer.write_u64(*self as u64, <$ty>::BITS);
+            }
+
+            fn net_deserialize(reader: &mut BitReader) -> Result<Self, BitError> {
+                Ok(reader.read_u64(<$ty>::BITS)? as $ty)
+            }
+        }
+
+        impl NetRanged for $ty {
+            fn write_ranged(&self, writer: &mut BitWriter, min: i64, max: i64) {
+                writer.write_ranged(*self as i64, min, max);
+            }
+
+            fn read_ranged(reader: &mut BitReader, min: i64, max: i64) -> Result<Self, BitError> {
+                Ok(reader.read_ranged(min, max)? as $ty)
+            }
+        }
+    )*};
+}
+
+macro_rules! impl_signed {
+    ($($ty:ty => $unsigned:ty),*) => {$(
+        impl NetSerialize for $ty {
+            fn net_serialize(&self, writer: &mut BitWriter) {
+                writer.write_u64(*self as $unsigned as u64, <$ty>::BITS);
+            }
+
+            fn net_deserialize(reader: &mut BitReader) -> Result<Self, BitError> {
+                Ok(reader.read_u64(<$ty>::BITS)? as $unsigned as $ty)
+            }
+        }
+
+        impl NetRanged for $ty {
+            fn write_ranged(&self, writer: &mut BitWriter, min: i64, max: i64) {
+                writer.write_ranged(*self as i64, min, max);
+            }
+
+            fn read_ranged(reader: &mut BitReader, min: i64, max: i64) -> Result<Self, BitError> {
+                Ok(reader.read_ranged(min, max)? as $ty)
+            }
+        }
+    )*};
+}
+
+impl_unsigned!(u8, u16, u32, u64);
+impl_signed!(i8 => u8, i16 => u16, i32 => u32, i64 => u64);
+
+impl NetSerialize for f32 {
+    fn net_serialize(&self, writer: &mut BitWriter) {
+        writer.write_bits(self.to_bits(), 32);
+    }
+
+    fn net_deserialize(reader: &mut BitReader) -> Result<Self, BitError> {
+        Ok(f32::from_bits(reader.read_bits(32)?))
+    }
+}
+
+impl NetQuantize for f32 {
+    fn write_quantized(&self, writer: &mut BitWriter, min: f32, max: f32, precision: f32) {
+        writer.write_quantized(*self, min, max, precision);
+    }
+
+    fn read_quantized(
+        reader: &mut BitReader,
+        min: f32,
+        max: f32,
+        precision: f32,
+    ) -> Result<Self, BitError> {
+        reader.read_quantized(min, max, precision)
+    }
+}
+
+impl NetSerialize for Vec2 {
+    fn net_serialize(&self, writer: &mut BitWriter) {
+        self.x.net_serialize(writer);
+        self.y.net_serialize(writer);
+    }
+
+    fn net_deserialize(reader: &mut BitReader) -> Result<Self, BitError> {
+        Ok(Vec2::new(
+            f32::net_deserialize(reader)?,
+            f32::net_deserialize(reader)?,
+        ))
+    }
+}
+
+impl NetQuantize for Vec2 {
+    fn write_quantized(&self, writer: &mut BitWriter, min: f32, max: f32, precision: f32) {
+        writer.write_quantized(self.x, min, max, precision);
+        writer.write_quantized(self.y, min, max, precision);
+    }
+
+    fn read_quantized(
+        reader: &mut BitReader,
+        min: f32,
+        max: f32,
+        precision: f32,
+    ) -> Result<Self, BitError> {
+        Ok(Vec2::new(
+            reader.read_quantized(min, max, precision)?,
+            reader.read_quantized(min, max, precision)?,
+        ))
+    }
+}
+
+impl NetSerialize for Vec3 {
+    fn net_serialize(&self, writer: &mut BitWriter) {
+        self.x.net_serialize(writer);
+        self.y.net_serialize(writer);
+        self.z.net_serialize(writer);
+    }
+
+    fn net_deserialize(reader: &mut BitReader) -> Result<Self, BitError> {
+        Ok(Vec3::new(
+            f32::net_deserialize(reader)?,
+            f32::net_deserialize(reader)?,
+            f32::net_deserialize(reader)?,
+        ))
+    }
+}
+
+impl NetQuantize for Vec3 {
+    fn write_quantized(&self, writer: &mut BitWriter, min: f32, max: f32, precision: f32) {
+        writer.write_quantized(self.x, min, max, precision);
+        writer.write_quantized(self.y, min, max, precision);
+        writer.write_quantized(self.z, min, max, precision);
+    }
+
+    fn read_quantized(
+        reader: &mut BitReader,
+        min: f32,
+        max: f32,
+        precision: f32,
+    ) -> Result<Self, BitError> {
+        Ok(Vec3::new(
+            reader.read_quantized(min, max, precision)?,
+            reader.read_quantized(min, max, precision)?,
+            reader.read_quantized(min, max, precision)?,
+        ))
+    }
+}
+
+impl NetSerialize for Quat {
+    fn net_serialize(&self, writer: &mut BitWriter) {
+        for c in self.to_array() {
+            c.net_serialize(writer);
+        }
+    }
+
+    fn net_deserialize(reader: &mut BitReader) -> Result<Self, BitError> {
+        Ok(Quat::from_array(<[f32; 4]>::net_deserialize(reader)?))
+    }
+}
+
+impl<T: NetSerialize> NetSerialize for Option<T> {
+    fn net_serialize(&self, writer: &mut BitWriter) {
+        writer.write_bool(self.is_some());
+        if let Some(value) = self {
+            value.net_serialize(writer);
+        }
+    }
+
+    fn net_deserialize(reader: &mut BitReader) -> Result<Self, BitError> {
+        if reader.read_bool()? {
+            Ok(Some(T::net_deserialize(reader)?))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl<T: NetSerialize> NetSerialize for Vec<T> {
+    fn net_serialize(&self, writer: &mut BitWriter) {
+        writer.write_len(self.len());
+        for value in self.iter() {
+            value.net_serialize(writer);
+        }
+    }
+
+    fn net_deserialize(reader: &mut BitReader) -> Result<Self, BitError> {
+        let len = reader.read_len()?;
+        (0..len).map(|_| T::net_deserialize(reader)).collect()
+    }
+}
+
+impl<T: NetSerialize> NetSerialize for Box<[T]> {
+    fn net_serialize(&self, writer: &mut BitWriter) {
+        writer.write_len(self.len());
+        for value in self.iter() {
+            value.net_serialize(writer);
+        }
+    }
+
+    fn net_deserialize(reader: &mut BitReader) -> Result<Self, BitError> {
+        Ok(Vec::net_deserialize(reader)?.into_boxed_slice())
+    }
+}
+
+impl<T: NetSerialize, const N: usize> NetSerialize for [T; N] {
+    fn net_serialize(&self, writer: &mut BitWriter) {
+        for value in self {
+            value.net_serialize(writer);
+        }
+    }
+
+    fn net_deserialize(reader: &mut BitReader) -> Result<Self, BitError> {
+        let values = (0..N)
+            .map(|_| T::net_deserialize(reader))
+            .collect::<Result<Vec<_>, _>>()?;
+        values.try_into().map_err(|_| BitError::UnexpectedEnd)
+    }
+}
+
+impl NetSerialize for () {
+    fn net_serialize(&self, _writer: &mut BitWriter) {}
+
+    fn net_deserialize(_reader: &mut BitReader) -> Result<Self, BitError> {
+        Ok(())
+    }
+}
+
+macro_rules! impl_tuple {
+    ($($name:ident),+) => {
+        impl<$($name: NetSerialize),+> NetSerialize for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn net_serialize(&self, writer: &mut BitWriter) {
+                let ($($name,)+) = self;
+                $($name.net_serialize(writer);)+
+            }
+
+            fn net_deserialize(reader: &mut BitReader) -> Result<Self, BitError> {
+                Ok(($($name::net_deserialize(reader)?,)+))
+            }
+        }
+    };
+}
+
+impl_tuple!(A);
+impl_tuple!(A, B);
+impl_tuple!(A, B, C);
+impl_tuple!(A, B, C, D);
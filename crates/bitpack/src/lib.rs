@@ -0,0 +1,82 @@
+use std::fmt;
+
+mod impls;
+mod reader;
+mod writer;
+
+pub use bitpack_derive::NetSerialize;
+pub use reader::*;
+pub use writer::*;
+
+/// Types that can be written to and read from a bit stream.
+///
+/// The derive writes fields in declaration order and enum variants as an index
+/// in as few bits as the variant count needs. Fields can be narrowed with
+/// `#[net(range(min = .., max = ..))]` for integers implementing [`NetRanged`],
+/// or `#[net(float(min = .., max = .., precision = ..))]` for floats and vectors
+/// implementing [`NetQuantize`].
+///
+/// `Vec` and `Box<[T]>` hold at most [`MAX_LEN`] elements, and panic when
+/// written with more.
+pub trait NetSerialize: Sized {
+    fn net_serialize(&self, writer: &mut BitWriter);
+    fn net_deserialize(reader: &mut BitReader) -> Result<Self, BitError>;
+}
+
+/// Integers that can be written in just enough bits for `min..=max`.
+pub trait NetRanged: Sized {
+    fn write_ranged(&self, writer: &mut BitWriter, min: i64, max: i64);
+    fn read_ranged(reader: &mut BitReader, min: i64, max: i64) -> Result<Self, BitError>;
+}
+
+/// Floats (and vectors of them) that can be written as fixed-point within
+/// `min..=max`, off by at most `precision / 2`.
+pub trait NetQuantize: Sized {
+    fn write_quantized(&self, writer: &mut BitWriter, min: f32, max: f32, precision: f32);
+    fn read_quantized(
+        reader: &mut BitReader,
+        min: f32,
+        max: f32,
+        precision: f32,
+    ) -> Result<Self, BitError>;
+}
+
+/// Most elements a serialized collection can hold.
+pub const MAX_LEN: usize = u16::MAX as usize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitError {
+    UnexpectedEnd,
+    OutOfRange,
+    InvalidVariant,
+}
+
+impl fmt::Display for BitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BitError::UnexpectedEnd => f.write_str("unexpected end of bit stream"),
+            BitError::OutOfRange => f.write_str("value out of range"),
+            BitError::InvalidVariant => f.write_str("invalid enum variant"),
+        }
+    }
+}
+
+impl std::error::Error for BitError {}
+
+/// Number of bits needed to write any value in `0..=range`.
+pub fn bits_required(range: u64) -> u32 {
+    u64::BITS - range.leading_zeros()
+}
+
+/// Serializes `value` into a new byte buffer.
+pub fn to_bytes<T: NetSerialize>(value: &T) -> Vec<u8> {
+    let mut writer = BitWriter::new();
+    value.net_serialize(&mut writer);
+    writer.finish()
+}
+
+/// Deserializes a `T` from `bytes`. Any padding left in the last byte is ignored.
+pub fn from_bytes<T: NetSerialize>(bytes: &[u8]) -> Result<T, BitError> {
+    let mut reader = BitReader::new(bytes);
+    T::net_deserialize(&mut reader)
+}
@@ -0,0 +1,82 @@
+use crate::{bits_required, writer::quantization_steps, BitError};
+
+pub struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, position: 0 }
+    }
+
+    pub fn remaining_bits(&self) -> usize {
+        self.bytes.len() * 8 - self.position
+    }
+
+    /// Reads `bits` (at most 32) bits.
+    pub fn read_bits(&mut self, bits: u32) -> Result<u32, BitError> {
+        debug_assert!(bits <= 32);
+        if bits as usize > self.remaining_bits() {
+            return Err(BitError::UnexpectedEnd);
+        }
+
+        let mut value = 0u64;
+        let mut read = 0;
+        while read < bits {
+            let byte = self.bytes[self.position / 8];
+            let offset = (self.position % 8) as u32;
+            let take = (8 - offset).min(bits - read);
+            let chunk = (byte >> offset) as u64 & ((1 << take) - 1);
+            value |= chunk << read;
+            read += take;
+            self.position += take as usize;
+        }
+
+        Ok(value as u32)
+    }
+
+    pub fn read_u64(&mut self, bits: u32) -> Result<u64, BitError> {
+        debug_assert!(bits <= 64);
+        let low = self.read_bits(bits.min(32))? as u64;
+        let high = if bits > 32 {
+            self.read_bits(bits - 32)? as u64
+        } else {
+            0
+        };
+        Ok(low | high << 32)
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, BitError> {
+        Ok(self.read_bits(1)? != 0)
+    }
+
+    pub fn read_ranged(&mut self, min: i64, max: i64) -> Result<i64, BitError> {
+        let range = max.wrapping_sub(min) as u64;
+        let value = self.read_u64(bits_required(range))?;
+        if value > range {
+            return Err(BitError::OutOfRange);
+        }
+        Ok(min.wrapping_add(value as i64))
+    }
+
+    pub fn read_quantized(&mut self, min: f32, max: f32, precision: f32) -> Result<f32, BitError> {
+        let steps = quantization_steps(min, max, precision);
+        let value = self.read_u64(bits_required(steps))?;
+        if value > steps {
+            return Err(BitError::OutOfRange);
+        }
+        Ok(min + (value as f64 / steps as f64) as f32 * (max - min))
+    }
+
+    /// Reads a collection length, rejecting lengths that couldn't possibly fit
+    /// in what's left of the stream so a crafted length can't force a huge
+    /// allocation.
+    pub fn read_len(&mut self) -> Result<usize, BitError> {
+        let len = self.read_bits(16)? as usize;
+        if len > self.remaining_bits() {
+            return Err(BitError::UnexpectedEnd);
+        }
+        Ok(len)
+    }
+}
@@ -0,0 +1,96 @@
+use crate::bits_required;
+
+#[derive(Default)]
+pub struct BitWriter {
+    bytes: Vec<u8>,
+    scratch: u64,
+    scratch_bits: u32,
+}
+
+impl BitWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Continues writing after the bytes already in `bytes`.
+    pub fn with_bytes(bytes: Vec<u8>) -> Self {
+        Self {
+            bytes,
+            ..Default::default()
+        }
+    }
+
+    pub fn bits_written(&self) -> usize {
+        self.bytes.len() * 8 + self.scratch_bits as usize
+    }
+
+    /// Writes the low `bits` (at most 32) bits of `value`.
+    pub fn write_bits(&mut self, value: u32, bits: u32) {
+        debug_assert!(bits <= 32);
+        let mask = (1u64 << bits) - 1;
+        self.scratch |= (value as u64 & mask) << self.scratch_bits;
+        self.scratch_bits += bits;
+
+        while self.scratch_bits >= 8 {
+            self.bytes.push(self.scratch as u8);
+            self.scratch >>= 8;
+            self.scratch_bits -= 8;
+        }
+    }
+
+    pub fn write_u64(&mut self, value: u64, bits: u32) {
+        debug_assert!(bits <= 64);
+        self.write_bits(value as u32, bits.min(32));
+        if bits > 32 {
+            self.write_bits((value >> 32) as u32, bits - 32);
+        }
+    }
+
+    /// Writes a collection length.
+    ///
+    /// # Panics
+    ///
+    /// If `len` is over [`MAX_LEN`](crate::MAX_LEN). Cutting the collection
+    /// short would silently lose data.
+    pub fn write_len(&mut self, len: usize) {
+        assert!(
+            len <= crate::MAX_LEN,
+            "collection of {} elements is over the limit of {}",
+            len,
+            crate::MAX_LEN
+        );
+        self.write_bits(len as u32, 16);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_bits(value as u32, 1);
+    }
+
+    /// Writes `value` in just enough bits for `min..=max`, clamping it first.
+    pub fn write_ranged(&mut self, value: i64, min: i64, max: i64) {
+        debug_assert!(min <= max);
+        let range = max.wrapping_sub(min) as u64;
+        let value = value.clamp(min, max).wrapping_sub(min) as u64;
+        self.write_u64(value, bits_required(range));
+    }
+
+    /// Writes `value` as fixed-point within `min..=max`, clamping it first.
+    pub fn write_quantized(&mut self, value: f32, min: f32, max: f32, precision: f32) {
+        let steps = quantization_steps(min, max, precision);
+        let normalized = ((value - min) / (max - min)).clamp(0.0, 1.0);
+        let value = (normalized as f64 * steps as f64).round() as u64;
+        self.write_u64(value.min(steps), bits_required(steps));
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        if self.scratch_bits > 0 {
+            self.bytes.push(self.scratch as u8);
+        }
+        self.bytes
+    }
+}
+
+pub(crate) fn quantization_steps(min: f32, max: f32, precision: f32) -> u64 {
+    debug_assert!(min < max && precision > 0.0);
+    ((max - min) / precision).ceil() as u64
+}
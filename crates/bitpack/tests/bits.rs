@@ -0,0 +1,245 @@
+use bevy::math::{Vec2, Vec3};
+use bitpack::{
+    bits_required, from_bytes, to_bytes, BitError, BitReader, BitWriter, NetQuantize, MAX_LEN,
+};
+
+#[test]
+fn bits_round_trip() {
+    let values = [
+        (0b1, 1),
+        (0b101, 3),
+        (0xab, 8),
+        (0x1234, 13),
+        (u32::MAX, 32),
+        (0, 7),
+    ];
+
+    let mut writer = BitWriter::new();
+    for (value, bits) in values {
+        writer.write_bits(value, bits);
+    }
+    writer.write_u64(u64::MAX - 1, 64);
+    writer.write_u64(0x1_2345_6789, 40);
+    writer.write_bool(true);
+    let total = values.iter().map(|(_, bits)| bits).sum::<u32>() as usize + 64 + 40 + 1;
+    assert_eq!(writer.bits_written(), total);
+
+    let bytes = writer.finish();
+    assert!(bytes.len() * 8 >= total && bytes.len() * 8 < total + 8);
+
+    let mut reader = BitReader::new(&bytes);
+    for (value, bits) in values {
+        assert_eq!(
+            reader.read_bits(bits),
+            Ok(value & (u64::MAX >> (64 - bits)) as u32)
+        );
+    }
+    assert_eq!(reader.read_u64(64), Ok(u64::MAX - 1));
+    assert_eq!(reader.read_u64(40), Ok(0x1_2345_6789));
+    assert_eq!(reader.read_bool(), Ok(true));
+    assert_eq!(reader.remaining_bits(), bytes.len() * 8 - total);
+}
+
+#[test]
+fn write_bits_masks_high_bits() {
+    let mut writer = BitWriter::new();
+    writer.write_bits(0xff, 4);
+    writer.write_bits(0, 4);
+    assert_eq!(writer.finish(), vec![0x0f]);
+}
+
+#[test]
+fn with_bytes_continues_after_them() {
+    let mut writer = BitWriter::with_bytes(vec![0xaa]);
+    writer.write_bits(0x5, 3);
+    let bytes = writer.finish();
+
+    let mut reader = BitReader::new(&bytes);
+    assert_eq!(reader.read_bits(8), Ok(0xaa));
+    assert_eq!(reader.read_bits(3), Ok(0x5));
+}
+
+#[test]
+fn reading_past_the_end_fails() {
+    let mut reader = BitReader::new(&[0xff]);
+    assert_eq!(reader.read_bits(6), Ok(0x3f));
+    assert_eq!(reader.read_bits(3), Err(BitError::UnexpectedEnd));
+    assert_eq!(reader.read_bits(2), Ok(0x3));
+    assert_eq!(reader.read_bool(), Err(BitError::UnexpectedEnd));
+}
+
+#[test]
+fn read_len_limits() {
+    // A length that fits in what's left.
+    let mut writer = BitWriter::new();
+    writer.write_bits(3, 16);
+    writer.write_bits(0b101, 3);
+    let bytes = writer.finish();
+    let mut reader = BitReader::new(&bytes);
+    assert_eq!(reader.read_len(), Ok(3));
+
+    // Each element takes at least a bit, so more than that can't be real.
+    let mut writer = BitWriter::new();
+    writer.write_bits(u16::MAX as u32, 16);
+    writer.write_bits(0, 8);
+    let bytes = writer.finish();
+    let mut reader = BitReader::new(&bytes);
+    assert_eq!(reader.read_len(), Err(BitError::UnexpectedEnd));
+
+    assert_eq!(from_bytes::<Vec<u8>>(&bytes), Err(BitError::UnexpectedEnd));
+    assert_eq!(
+        BitReader::new(&[0]).read_len(),
+        Err(BitError::UnexpectedEnd)
+    );
+}
+
+#[test]
+fn longest_collections_round_trip() {
+    let values = vec![true; MAX_LEN];
+    assert_eq!(from_bytes::<Vec<bool>>(&to_bytes(&values)), Ok(values));
+}
+
+#[test]
+#[should_panic]
+fn over_long_collections_are_not_cut_short() {
+    to_bytes(&vec![0u8; MAX_LEN + 1]);
+}
+
+#[test]
+fn collections_round_trip() {
+    let values = vec![1u16, 2, 3, u16::MAX];
+    assert_eq!(
+        from_bytes::<Vec<u16>>(&to_bytes(&values)),
+        Ok(values.clone())
+    );
+
+    let boxed = values.into_boxed_slice();
+    assert_eq!(from_bytes::<Box<[u16]>>(&to_bytes(&boxed)), Ok(boxed));
+
+    let tuple = (Some(5u8), None::<u32>, -7i16, true);
+    assert_eq!(from_bytes(&to_bytes(&tuple)), Ok(tuple));
+
+    let array = [1.5f32, -0.25, f32::MAX];
+    assert_eq!(from_bytes(&to_bytes(&array)), Ok(array));
+}
+
+#[test]
+fn ranged_round_trip() {
+    assert_eq!(bits_required(0), 0);
+    assert_eq!(bits_required(1), 1);
+    assert_eq!(bits_required(255), 8);
+    assert_eq!(bits_required(256), 9);
+
+    let ranges = [
+        (0, 0),
+        (-8, 7),
+        (-1000, 1000),
+        (100, 355),
+        (i64::MIN, i64::MAX),
+    ];
+    for (min, max) in ranges {
+        let values = [min, max, min / 2 + max / 2];
+        let mut writer = BitWriter::new();
+        for value in values {
+            writer.write_ranged(value, min, max);
+        }
+        let bits = bits_required(max.wrapping_sub(min) as u64) as usize;
+        assert_eq!(writer.bits_written(), bits * values.len());
+
+        let bytes = writer.finish();
+        let mut reader = BitReader::new(&bytes);
+        for value in values {
+            assert_eq!(reader.read_ranged(min, max), Ok(value));
+        }
+    }
+}
+
+#[test]
+fn ranged_values_are_clamped() {
+    let mut writer = BitWriter::new();
+    writer.write_ranged(-50, -8, 7);
+    writer.write_ranged(50, -8, 7);
+    let bytes = writer.finish();
+
+    let mut reader = BitReader::new(&bytes);
+    assert_eq!(reader.read_ranged(-8, 7), Ok(-8));
+    assert_eq!(reader.read_ranged(-8, 7), Ok(7));
+}
+
+#[test]
+fn ranged_rejects_values_past_max() {
+    // 0..=4 takes three bits, which can also hold 5 to 7.
+    let mut writer = BitWriter::new();
+    writer.write_bits(6, 3);
+    let bytes = writer.finish();
+    assert_eq!(
+        BitReader::new(&bytes).read_ranged(0, 4),
+        Err(BitError::OutOfRange)
+    );
+}
+
+#[test]
+fn quantized_round_trip() {
+    let (min, max, precision) = (-32.0, 32.0, 0.001);
+    let mut writer = BitWriter::new();
+    let values = (0..=200)
+        .map(|i| min + (max - min) * i as f32 / 200.0 + 0.000_37)
+        .map(|value: f32| value.min(max))
+        .collect::<Vec<_>>();
+    for value in &values {
+        writer.write_quantized(*value, min, max, precision);
+    }
+    let bytes = writer.finish();
+
+    let mut reader = BitReader::new(&bytes);
+    for value in &values {
+        let read = reader.read_quantized(min, max, precision).unwrap();
+        assert!(
+            (read - value).abs() <= precision * 0.5 + 1e-5,
+            "{} read as {}",
+            value,
+            read
+        );
+    }
+}
+
+#[test]
+fn quantized_values_are_clamped() {
+    let mut writer = BitWriter::new();
+    writer.write_quantized(-100.0, -1.0, 1.0, 0.01);
+    writer.write_quantized(100.0, -1.0, 1.0, 0.01);
+    let bytes = writer.finish();
+
+    let mut reader = BitReader::new(&bytes);
+    assert_eq!(reader.read_quantized(-1.0, 1.0, 0.01), Ok(-1.0));
+    assert_eq!(reader.read_quantized(-1.0, 1.0, 0.01), Ok(1.0));
+}
+
+#[test]
+fn quantized_rejects_values_past_max() {
+    // 0..=1 in steps of 0.2 is five steps, in three bits.
+    let mut writer = BitWriter::new();
+    writer.write_bits(7, 3);
+    let bytes = writer.finish();
+    assert_eq!(
+        BitReader::new(&bytes).read_quantized(0.0, 1.0, 0.2),
+        Err(BitError::OutOfRange)
+    );
+}
+
+#[test]
+fn quantized_vectors_round_trip() {
+    let vec2 = Vec2::new(0.123, -0.987);
+    let vec3 = Vec3::new(-15.5, 0.0, 15.999);
+
+    let mut writer = BitWriter::new();
+    vec2.write_quantized(&mut writer, -1.0, 1.0, 0.01);
+    vec3.write_quantized(&mut writer, -16.0, 16.0, 0.001);
+    let bytes = writer.finish();
+
+    let mut reader = BitReader::new(&bytes);
+    let read2 = Vec2::read_quantized(&mut reader, -1.0, 1.0, 0.01).unwrap();
+    let read3 = Vec3::read_quantized(&mut reader, -16.0, 16.0, 0.001).unwrap();
+    assert!((read2 - vec2).abs().max_element() <= 0.005 + 1e-5);
+    assert!((read3 - vec3).abs().max_element() <= 0.0005 + 1e-5);
+}
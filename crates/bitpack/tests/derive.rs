@@ -0,0 +1,147 @@
+use bevy::math::{Quat, Vec3};
+use bitpack::{from_bytes, to_bytes, BitError, BitWriter, NetSerialize};
+
+const LIMIT: f32 = 8.0;
+
+#[derive(Debug, Clone, PartialEq, NetSerialize)]
+struct Named {
+    id: u32,
+    #[net(range(min = -3, max = 3))]
+    offset: i8,
+    #[net(float(min = -LIMIT, max = LIMIT, precision = 0.01))]
+    position: Vec3,
+    rotation: Quat,
+    tags: Box<[u16]>,
+    parent: Option<u64>,
+}
+
+impl Named {
+    /// Everything else survives exactly, positions are quantized.
+    fn assert_round_trip(&self) {
+        let read = from_bytes::<Named>(&to_bytes(self)).unwrap();
+        assert!((read.position - self.position).abs().max_element() <= 0.005 + 1e-5);
+        assert_eq!(
+            Named {
+                position: self.position,
+                ..read
+            },
+            *self
+        );
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, NetSerialize)]
+struct Tuple(u8, #[net(range(min = 0, max = 15))] u32, bool);
+
+#[derive(Debug, Clone, Copy, PartialEq, NetSerialize)]
+struct Unit;
+
+#[derive(Debug, Clone, PartialEq, NetSerialize)]
+enum Message {
+    Empty,
+    Tuple(u8, #[net(float(min = 0.0, max = 1.0, precision = 0.1))] f32),
+    Named { tuple: Tuple, unit: Unit },
+}
+
+/// Field names the derive's generated code would trip over if it weren't
+/// careful with its own identifiers.
+#[derive(Debug, Clone, Copy, PartialEq, NetSerialize)]
+struct Clashing {
+    writer: u8,
+    reader: u8,
+    #[net(range(min = 0, max = limit() as i64))]
+    field_0: u8,
+    #[net(float(min = 0.0, max = limit(), precision = 0.5))]
+    limit: f32,
+}
+
+fn limit() -> f32 {
+    LIMIT
+}
+
+#[derive(Debug, Clone, PartialEq, NetSerialize)]
+struct Generic<T> {
+    value: T,
+}
+
+#[test]
+fn struct_round_trip() {
+    let named = Named {
+        id: 42,
+        offset: -3,
+        position: Vec3::new(1.0, -2.5, 7.99),
+        rotation: Quat::from_rotation_y(1.0),
+        tags: vec![1, 2, 3].into_boxed_slice(),
+        parent: Some(u64::MAX),
+    };
+    named.assert_round_trip();
+    Named {
+        position: Vec3::new(-LIMIT, 0.0, LIMIT),
+        tags: Box::new([]),
+        parent: None,
+        ..named
+    }
+    .assert_round_trip();
+
+    let tuple = Tuple(7, 15, true);
+    assert_eq!(from_bytes(&to_bytes(&tuple)), Ok(tuple));
+
+    assert!(to_bytes(&Unit).is_empty());
+    assert_eq!(from_bytes(&[]), Ok(Unit));
+
+    let generic = Generic {
+        value: (1u8, -1i32),
+    };
+    assert_eq!(from_bytes(&to_bytes(&generic)), Ok(generic));
+}
+
+#[test]
+fn attributes_narrow_fields() {
+    let mut writer = BitWriter::new();
+    Tuple(0, 15, false).net_serialize(&mut writer);
+    // u8, then four bits for 0..=15, then a bool.
+    assert_eq!(writer.bits_written(), 8 + 4 + 1);
+}
+
+#[test]
+fn enum_round_trip() {
+    let messages = [
+        Message::Empty,
+        Message::Tuple(3, 1.0),
+        Message::Named {
+            tuple: Tuple(1, 2, false),
+            unit: Unit,
+        },
+    ];
+    for message in messages {
+        assert_eq!(from_bytes(&to_bytes(&message)), Ok(message));
+    }
+
+    // Three variants take two bits, so the fourth value is invalid.
+    let mut writer = BitWriter::new();
+    writer.write_bits(3, 2);
+    assert_eq!(
+        from_bytes::<Message>(&writer.finish()),
+        Err(BitError::InvalidVariant)
+    );
+}
+
+#[test]
+fn generated_code_is_hygienic() {
+    let clashing = Clashing {
+        writer: 1,
+        reader: 2,
+        field_0: 3,
+        limit: 4.5,
+    };
+    assert_eq!(from_bytes(&to_bytes(&clashing)), Ok(clashing));
+}
+
+#[test]
+fn truncated_input_fails() {
+    let bytes = to_bytes(&Tuple(1, 2, true));
+    assert_eq!(
+        from_bytes::<Tuple>(&bytes[..1]),
+        Err(BitError::UnexpectedEnd)
+    );
+}
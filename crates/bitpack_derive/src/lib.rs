@@ -0,0 +1,290 @@
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote, ToTokens};
+use syn::{
+    parenthesized,
+    parse::{Parse, ParseStream},
+    parse_macro_input,
+    punctuated::Punctuated,
+    Data, DeriveInput, Error, Expr, Fields, Ident, Result, Token,
+};
+
+#[proc_macro_derive(NetSerialize, attributes(net))]
+pub fn derive_net_serialize(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+fn expand(input: DeriveInput) -> Result<TokenStream2> {
+    let name = &input.ident;
+    let mut generics = input.generics.clone();
+    for param in generics.type_params_mut() {
+        param
+            .bounds
+            .push(syn::parse_quote!(::bitpack::NetSerialize));
+    }
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    let (serialize, deserialize) = match &input.data {
+        Data::Struct(data) => expand_struct(&data.fields)?,
+        Data::Enum(data) => {
+            let variants = data.variants.iter().collect::<Vec<_>>();
+            expand_enum(&variants)?
+        }
+        Data::Union(_) => {
+            return Err(Error::new(
+                Span::call_site(),
+                "NetSerialize can't be derived for unions",
+            ))
+        }
+    };
+
+    Ok(quote! {
+        impl #impl_generics ::bitpack::NetSerialize for #name #ty_generics #where_clause {
+            #[allow(unused_variables)]
+            fn net_serialize(&self, __writer: &mut ::bitpack::BitWriter) {
+                #serialize
+            }
+
+            #[allow(unused_variables)]
+            fn net_deserialize(
+                __reader: &mut ::bitpack::BitReader,
+            ) -> ::std::result::Result<Self, ::bitpack::BitError> {
+                #deserialize
+            }
+        }
+    })
+}
+
+fn expand_struct(fields: &Fields) -> Result<(TokenStream2, TokenStream2)> {
+    let bindings = field_bindings(fields);
+    let writes = write_fields(fields, &bindings)?;
+    let construct = construct(quote!(Self), fields)?;
+
+    let pattern = pattern(quote!(Self), fields, &bindings);
+    let serialize = quote! {
+        let #pattern = self;
+        #(#writes)*
+    };
+    let deserialize = quote! {
+        ::std::result::Result::Ok(#construct)
+    };
+
+    Ok((serialize, deserialize))
+}
+
+fn expand_enum(variants: &[&syn::Variant]) -> Result<(TokenStream2, TokenStream2)> {
+    let count = variants.len() as u64;
+    let bits = u64::BITS - count.saturating_sub(1).leading_zeros();
+
+    let mut serialize_arms = Vec::new();
+    let mut deserialize_arms = Vec::new();
+    for (index, variant) in variants.iter().enumerate() {
+        let ident = &variant.ident;
+        let index = index as u32;
+        let bindings = field_bindings(&variant.fields);
+        let pattern = pattern(quote!(Self::#ident), &variant.fields, &bindings);
+        let writes = write_fields(&variant.fields, &bindings)?;
+        let construct = construct(quote!(Self::#ident), &variant.fields)?;
+
+        serialize_arms.push(quote! {
+            #pattern => {
+                __writer.write_bits(#index, #bits);
+                #(#writes)*
+            }
+        });
+        deserialize_arms.push(quote! {
+            #index => ::std::result::Result::Ok(#construct),
+        });
+    }
+
+    let serialize = if variants.is_empty() {
+        quote!(match *self {})
+    } else {
+        quote! {
+            match self {
+                #(#serialize_arms)*
+            }
+        }
+    };
+    let deserialize = quote! {
+        match __reader.read_bits(#bits)? {
+            #(#deserialize_arms)*
+            _ => ::std::result::Result::Err(::bitpack::BitError::InvalidVariant),
+        }
+    };
+
+    Ok((serialize, deserialize))
+}
+
+/// Names for the fields in patterns, prefixed so they can't shadow anything
+/// the `#[net(..)]` arguments refer to.
+fn field_bindings(fields: &Fields) -> Vec<Ident> {
+    fields
+        .iter()
+        .enumerate()
+        .map(|(i, field)| match &field.ident {
+            Some(ident) => format_ident!("__{}", ident),
+            None => format_ident!("__field_{}", i),
+        })
+        .collect()
+}
+
+fn field_names(fields: &Fields) -> Vec<&Ident> {
+    fields
+        .iter()
+        .filter_map(|field| field.ident.as_ref())
+        .collect()
+}
+
+fn pattern(path: TokenStream2, fields: &Fields, bindings: &[Ident]) -> TokenStream2 {
+    let names = field_names(fields);
+    match fields {
+        Fields::Named(_) => quote!(#path { #(#names: #bindings),* }),
+        Fields::Unnamed(_) => quote!(#path ( #(#bindings),* )),
+        Fields::Unit => quote!(#path),
+    }
+}
+
+fn write_fields(fields: &Fields, bindings: &[Ident]) -> Result<Vec<TokenStream2>> {
+    fields
+        .iter()
+        .zip(bindings)
+        .map(|(field, binding)| {
+            Ok(match FieldEncoding::from_attrs(&field.attrs)? {
+                FieldEncoding::Plain => {
+                    quote!(::bitpack::NetSerialize::net_serialize(#binding, __writer);)
+                }
+                FieldEncoding::Range { min, max } => quote! {
+                    ::bitpack::NetRanged::write_ranged(
+                        #binding, __writer, (#min) as i64, (#max) as i64,
+                    );
+                },
+                FieldEncoding::Float {
+                    min,
+                    max,
+                    precision,
+                } => quote! {
+                    ::bitpack::NetQuantize::write_quantized(
+                        #binding, __writer, (#min) as f32, (#max) as f32, (#precision) as f32,
+                    );
+                },
+            })
+        })
+        .collect()
+}
+
+fn construct(path: TokenStream2, fields: &Fields) -> Result<TokenStream2> {
+    let reads = fields
+        .iter()
+        .map(|field| {
+            let ty = &field.ty;
+            Ok(match FieldEncoding::from_attrs(&field.attrs)? {
+                FieldEncoding::Plain => {
+                    quote!(<#ty as ::bitpack::NetSerialize>::net_deserialize(__reader)?)
+                }
+                FieldEncoding::Range { min, max } => quote! {
+                    <#ty as ::bitpack::NetRanged>::read_ranged(
+                        __reader, (#min) as i64, (#max) as i64,
+                    )?
+                },
+                FieldEncoding::Float {
+                    min,
+                    max,
+                    precision,
+                } => quote! {
+                    <#ty as ::bitpack::NetQuantize>::read_quantized(
+                        __reader, (#min) as f32, (#max) as f32, (#precision) as f32,
+                    )?
+                },
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let names = field_names(fields);
+    Ok(match fields {
+        Fields::Named(_) => quote!(#path { #(#names: #reads),* }),
+        Fields::Unnamed(_) => quote!(#path ( #(#reads),* )),
+        Fields::Unit => quote!(#path),
+    })
+}
+
+enum FieldEncoding {
+    Plain,
+    Range {
+        min: TokenStream2,
+        max: TokenStream2,
+    },
+    Float {
+        min: TokenStream2,
+        max: TokenStream2,
+        precision: TokenStream2,
+    },
+}
+
+impl FieldEncoding {
+    fn from_attrs(attrs: &[syn::Attribute]) -> Result<Self> {
+        let mut encoding = FieldEncoding::Plain;
+        for attr in attrs.iter().filter(|attr| attr.path.is_ident("net")) {
+            let NetAttr { kind, args } = attr.parse_args()?;
+            let get = |name: &str| {
+                args.iter()
+                    .find(|arg| arg.name == name)
+                    .map(|arg| arg.value.to_token_stream())
+                    .ok_or_else(|| Error::new(kind.span(), format!("missing `{}`", name)))
+            };
+
+            encoding = match kind.to_string().as_str() {
+                "range" => FieldEncoding::Range {
+                    min: get("min")?,
+                    max: get("max")?,
+                },
+                "float" => FieldEncoding::Float {
+                    min: get("min")?,
+                    max: get("max")?,
+                    precision: get("precision")?,
+                },
+                _ => {
+                    return Err(Error::new(
+                        kind.span(),
+                        "expected `range(min = .., max = ..)` or \
+                         `float(min = .., max = .., precision = ..)`",
+                    ))
+                }
+            };
+        }
+        Ok(encoding)
+    }
+}
+
+/// `kind(name = value, ...)`
+struct NetAttr {
+    kind: Ident,
+    args: Punctuated<NetArg, Token![,]>,
+}
+
+impl Parse for NetAttr {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let kind = input.parse()?;
+        let content;
+        parenthesized!(content in input);
+        let args = content.parse_terminated(NetArg::parse)?;
+        Ok(Self { kind, args })
+    }
+}
+
+struct NetArg {
+    name: Ident,
+    value: Expr,
+}
+
+impl Parse for NetArg {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let name = input.parse()?;
+        input.parse::<Token![=]>()?;
+        let value = input.parse()?;
+        Ok(Self { name, value })
+    }
+}
@@ -16,6 +16,6 @@ impl Plugin for PhysicsPlugin {
 #[path = "."]
 pub mod prelude {
     mod move_towards;
-    pub use bevy_rapier3d::prelude::*;
+    pub use bevy_rapier3d::{physics::step_world_system, prelude::*, rapier::parry};
     pub use move_towards::*;
 }
@@ -0,0 +1,322 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use laminar::{Config, DeliveryGuarantee, Packet, Socket, SocketEvent};
+use transport::{PeerTag, RelayPacket, RoomCode};
+
+const DEFAULT_ADDR: &str = "127.0.0.1:12346";
+
+/// Random room codes tried before giving up on finding a free one.
+const ROOM_CODE_ATTEMPTS: usize = 100;
+
+fn main() {
+    let addr: SocketAddr = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| DEFAULT_ADDR.into())
+        .parse()
+        .expect("invalid relay address");
+
+    let cfg = Config {
+        heartbeat_interval: Some(Duration::from_secs_f32(1.0)),
+        ..Default::default()
+    };
+    let socket = Socket::bind_with_config(addr, cfg).unwrap();
+
+    println!("[R] Listening on {}", addr);
+
+    let mut relay = Relay::new(socket);
+    loop {
+        relay.update();
+        std::thread::sleep(Duration::from_millis(1));
+    }
+}
+
+struct Room {
+    host: SocketAddr,
+    peers: HashMap<PeerTag, SocketAddr>,
+}
+
+#[derive(Clone, Copy)]
+enum Delivery {
+    Reliable,
+    Unreliable,
+}
+
+struct Relay {
+    socket: Socket,
+    rooms: HashMap<RoomCode, Room>,
+    hosts: HashMap<SocketAddr, RoomCode>,
+    peers: HashMap<SocketAddr, (RoomCode, PeerTag)>,
+    tag_counter: PeerTag,
+}
+
+impl Relay {
+    fn new(socket: Socket) -> Self {
+        Self {
+            socket,
+            rooms: HashMap::new(),
+            hosts: HashMap::new(),
+            peers: HashMap::new(),
+            tag_counter: 0,
+        }
+    }
+
+    fn update(&mut self) {
+        self.socket.manual_poll(Instant::now());
+
+        while let Some(socket_event) = self.socket.recv() {
+            match socket_event {
+                SocketEvent::Connect(_) => {}
+                SocketEvent::Disconnect(addr) | SocketEvent::Timeout(addr) => {
+                    self.remove(addr);
+                }
+                SocketEvent::Packet(packet) => {
+                    let delivery = match packet.delivery_guarantee() {
+                        DeliveryGuarantee::Reliable => Delivery::Reliable,
+                        DeliveryGuarantee::Unreliable => Delivery::Unreliable,
+                    };
+                    if let Ok(relay_packet) = bincode::deserialize(packet.payload()) {
+                        self.handle(packet.addr(), relay_packet, delivery);
+                    }
+                }
+            }
+        }
+    }
+
+    fn handle(&mut self, addr: SocketAddr, packet: RelayPacket, delivery: Delivery) {
+        match packet {
+            RelayPacket::Host => {
+                let room = match self.hosts.get(&addr) {
+                    Some(room) => *room,
+                    None => {
+                        let room = match self.generate_room_code() {
+                            Some(room) => room,
+                            None => {
+                                println!("[R] No free room for {}", addr);
+                                self.send(addr, &RelayPacket::HostRejected, Delivery::Reliable);
+                                return;
+                            }
+                        };
+                        self.rooms.insert(
+                            room,
+                            Room {
+                                host: addr,
+                                peers: HashMap::new(),
+                            },
+                        );
+                        self.hosts.insert(addr, room);
+                        println!("[R] Room {} hosted by {}", room, addr);
+                        room
+                    }
+                };
+                self.send(addr, &RelayPacket::HostAccepted(room), Delivery::Reliable);
+            }
+            RelayPacket::Join(room) => {
+                if self.peers.contains_key(&addr) || self.hosts.contains_key(&addr) {
+                    return;
+                }
+                let host = match self.rooms.get_mut(&room) {
+                    Some(r) => {
+                        let tag = self.tag_counter;
+                        self.tag_counter = self.tag_counter.wrapping_add(1);
+                        r.peers.insert(tag, addr);
+                        self.peers.insert(addr, (room, tag));
+                        println!("[R] {} joined room {} as peer {}", addr, room, tag);
+                        Some((r.host, tag))
+                    }
+                    None => None,
+                };
+                match host {
+                    Some((host, tag)) => {
+                        self.send(host, &RelayPacket::PeerJoined(tag), Delivery::Reliable);
+                    }
+                    None => {
+                        self.send(addr, &RelayPacket::JoinRejected, Delivery::Reliable);
+                    }
+                }
+            }
+            RelayPacket::ToPeer(tag, payload) => {
+                let peer = self
+                    .hosts
+                    .get(&addr)
+                    .and_then(|room| self.rooms.get(room))
+                    .and_then(|room| room.peers.get(&tag))
+                    .copied();
+                if let Some(peer) = peer {
+                    self.send(peer, &RelayPacket::FromHost(payload), delivery);
+                }
+            }
+            RelayPacket::Kick(tag) => {
+                let peer = self
+                    .hosts
+                    .get(&addr)
+                    .and_then(|room| self.rooms.get_mut(room))
+                    .and_then(|room| room.peers.remove(&tag));
+                if let Some(peer) = peer {
+                    println!("[R] Peer {} kicked", tag);
+                    self.peers.remove(&peer);
+                    self.send(peer, &RelayPacket::HostLeft, Delivery::Reliable);
+                }
+            }
+            RelayPacket::ToHost(payload) => {
+                let host = self
+                    .peers
+                    .get(&addr)
+                    .and_then(|(room, tag)| self.rooms.get(room).map(|r| (r.host, *tag)));
+                if let Some((host, tag)) = host {
+                    self.send(host, &RelayPacket::FromPeer(tag, payload), delivery);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn remove(&mut self, addr: SocketAddr) {
+        if let Some(room) = self.hosts.remove(&addr) {
+            println!("[R] Room {} closed", room);
+            if let Some(room) = self.rooms.remove(&room) {
+                for peer in room.peers.values() {
+                    self.peers.remove(peer);
+                    self.send(*peer, &RelayPacket::HostLeft, Delivery::Reliable);
+                }
+            }
+        } else if let Some((room, tag)) = self.peers.remove(&addr) {
+            println!("[R] Peer {} left room {}", tag, room);
+            let host = self.rooms.get_mut(&room).map(|r| {
+                r.peers.remove(&tag);
+                r.host
+            });
+            if let Some(host) = host {
+                self.send(host, &RelayPacket::PeerLeft(tag), Delivery::Reliable);
+            }
+        }
+    }
+
+    fn generate_room_code(&self) -> Option<RoomCode> {
+        (0..ROOM_CODE_ATTEMPTS)
+            .map(|_| RoomCode::from_fn(|| fastrand::digit(10)))
+            .find(|room| !self.rooms.contains_key(room))
+    }
+
+    fn send(&mut self, addr: SocketAddr, packet: &RelayPacket, delivery: Delivery) {
+        let bytes = bincode::serialize(packet).unwrap();
+        let packet = match delivery {
+            Delivery::Reliable => Packet::reliable_ordered(addr, bytes, None),
+            Delivery::Unreliable => Packet::unreliable_sequenced(addr, bytes, None),
+        };
+        self.socket.send(packet).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::*;
+
+    fn bind() -> Socket {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+        Socket::bind_with_config(addr, Config::default()).unwrap()
+    }
+
+    fn send(socket: &mut Socket, to: SocketAddr, packet: &RelayPacket) {
+        let bytes = bincode::serialize(packet).unwrap();
+        socket
+            .send(Packet::reliable_ordered(to, bytes, None))
+            .unwrap();
+        socket.manual_poll(Instant::now());
+    }
+
+    /// Runs the relay until `socket` is sent something, or for a second.
+    fn receive(relay: &mut Relay, socket: &mut Socket) -> Option<RelayPacket> {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(1) {
+            relay.update();
+            socket.manual_poll(Instant::now());
+            while let Some(event) = socket.recv() {
+                if let SocketEvent::Packet(packet) = event {
+                    return bincode::deserialize(packet.payload()).ok();
+                }
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        None
+    }
+
+    #[test]
+    fn relays_between_host_and_peer() {
+        let mut relay = Relay::new(bind());
+        let relay_addr = relay.socket.local_addr().unwrap();
+        let mut host = bind();
+        let mut peer = bind();
+
+        send(&mut host, relay_addr, &RelayPacket::Host);
+        let room = match receive(&mut relay, &mut host) {
+            Some(RelayPacket::HostAccepted(room)) => room,
+            other => panic!("expected a room, got {:?}", other),
+        };
+
+        send(&mut peer, relay_addr, &RelayPacket::Join(room));
+        let tag = match receive(&mut relay, &mut host) {
+            Some(RelayPacket::PeerJoined(tag)) => tag,
+            other => panic!("expected a peer, got {:?}", other),
+        };
+
+        send(&mut host, relay_addr, &RelayPacket::ToPeer(tag, vec![1, 2]));
+        assert!(matches!(
+            receive(&mut relay, &mut peer),
+            Some(RelayPacket::FromHost(bytes)) if bytes == [1, 2]
+        ));
+
+        send(&mut peer, relay_addr, &RelayPacket::ToHost(vec![3]));
+        assert!(matches!(
+            receive(&mut relay, &mut host),
+            Some(RelayPacket::FromPeer(from, bytes)) if from == tag && bytes == [3]
+        ));
+    }
+
+    #[test]
+    fn unknown_rooms_are_rejected() {
+        let mut relay = Relay::new(bind());
+        let relay_addr = relay.socket.local_addr().unwrap();
+        let mut peer = bind();
+
+        send(
+            &mut peer,
+            relay_addr,
+            &RelayPacket::Join("1234".parse().unwrap()),
+        );
+        assert!(matches!(
+            receive(&mut relay, &mut peer),
+            Some(RelayPacket::JoinRejected)
+        ));
+    }
+
+    #[test]
+    fn hosts_are_rejected_once_rooms_run_out() {
+        let mut relay = Relay::new(bind());
+        let relay_addr = relay.socket.local_addr().unwrap();
+        for n in 0..10_000 {
+            let room = format!("{:04}", n).parse().unwrap();
+            relay.rooms.insert(
+                room,
+                Room {
+                    host: relay_addr,
+                    peers: HashMap::new(),
+                },
+            );
+        }
+        assert!(relay.generate_room_code().is_none());
+
+        let mut host = bind();
+        send(&mut host, relay_addr, &RelayPacket::Host);
+        assert!(matches!(
+            receive(&mut relay, &mut host),
+            Some(RelayPacket::HostRejected)
+        ));
+        assert!(relay.hosts.is_empty());
+    }
+}
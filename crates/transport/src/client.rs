@@ -3,7 +3,7 @@ use std::net::SocketAddr;
 use bevy::prelude::*;
 use bytes::Bytes;
 
-use crate::{DeliveryMethod, NetId};
+use crate::{DeliveryMethod, NetId, ReconnectToken, RoomCode};
 
 pub(crate) struct ClientTransportPlugin;
 
@@ -19,7 +19,13 @@ where
 {
     fn get_id(&self) -> NetId;
     fn is_connected(&self) -> bool;
-    fn connect(&mut self, addr: SocketAddr);
+    fn connect(&mut self, target: ConnectTarget);
+    /// Connects asking to keep `id`, like after moving to another server, with
+    /// the token the previous server gave out for it. Transports that can't
+    /// ask just connect.
+    fn reconnect(&mut self, target: ConnectTarget, _id: NetId, _token: ReconnectToken) {
+        self.connect(target);
+    }
     fn poll(&mut self);
     fn receive(&mut self, client_evw: &mut EventWriter<ClientTransportEvent>);
     fn send(&mut self, bytes: Vec<u8>, delivery: DeliveryMethod);
@@ -30,3 +36,21 @@ pub enum ClientTransportEvent {
     Disconnected,
     Message(Bytes),
 }
+
+#[derive(Debug, Clone, Copy)]
+pub enum ConnectTarget {
+    Addr(SocketAddr),
+    Room(RoomCode),
+}
+
+impl From<SocketAddr> for ConnectTarget {
+    fn from(addr: SocketAddr) -> Self {
+        Self::Addr(addr)
+    }
+}
+
+impl From<RoomCode> for ConnectTarget {
+    fn from(room: RoomCode) -> Self {
+        Self::Room(room)
+    }
+}
@@ -3,21 +3,37 @@ use std::{
     time::{Duration, Instant},
 };
 
-use bevy::{prelude::EventWriter, utils::HashMap};
+use bevy::{
+    prelude::EventWriter,
+    utils::{HashMap, HashSet},
+};
 use bytes::Bytes;
 use laminar::{Config, Packet, Socket, SocketEvent};
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    client::{ClientTransport, ClientTransportEvent},
+    client::{ClientTransport, ClientTransportEvent, ConnectTarget},
     server::{ServerTransport, ServerTransportEvent},
-    DeliveryMethod, NetId,
+    DeliveryMethod, NetId, ReconnectToken,
 };
 
+/// The first packet a client sends.
+#[derive(Debug, Serialize, Deserialize)]
+struct ConnectRequest {
+    password: String,
+    /// The id the client had on a previous server and its token, to keep the
+    /// id if it was reserved with that token.
+    reconnect: Option<(NetId, ReconnectToken)>,
+}
+
 pub struct LaminarServer {
     socket: Socket,
     connecting: HashMap<SocketAddr, NetId>,
     connected: HashMap<SocketAddr, NetId>,
     id_to_addr: HashMap<NetId, SocketAddr>,
+    kicked: HashSet<SocketAddr>,
+    disconnected: Vec<NetId>,
+    reserved: HashMap<NetId, ReconnectToken>,
     id_counter: NetId,
 }
 
@@ -39,9 +55,28 @@ impl LaminarServer {
             connecting: HashMap::default(),
             connected: HashMap::default(),
             id_to_addr: HashMap::default(),
+            kicked: HashSet::default(),
+            disconnected: Vec::new(),
+            reserved: HashMap::default(),
             id_counter: 0,
         }
     }
+
+    /// The next id that isn't connected, connecting or reserved.
+    fn next_free_id(&mut self) -> Option<NetId> {
+        for _ in 0..=NetId::MAX {
+            let id = self.id_counter;
+            self.id_counter = self.id_counter.wrapping_add(1);
+
+            let taken = self.reserved.contains_key(&id)
+                || self.id_to_addr.contains_key(&id)
+                || self.connecting.values().any(|other| *other == id);
+            if !taken {
+                return Some(id);
+            }
+        }
+        None
+    }
 }
 
 impl ServerTransport for LaminarServer {
@@ -50,38 +85,65 @@ impl ServerTransport for LaminarServer {
     }
 
     fn receive(&mut self, server_evw: &mut EventWriter<ServerTransportEvent>) {
+        for id in self.disconnected.drain(..) {
+            server_evw.send(ServerTransportEvent::Disconnected(id));
+        }
+
         while let Some(socket_event) = self.socket.recv() {
             match socket_event {
                 SocketEvent::Connect(addr) => {
-                    let id = *self.connecting.get(&addr).unwrap();
-                    self.connected.insert(addr, id);
-                    self.id_to_addr.insert(id, addr);
-                    self.connecting.remove(&addr);
-                    server_evw.send(ServerTransportEvent::Connected(id))
+                    if let Some(id) = self.connecting.remove(&addr) {
+                        self.connected.insert(addr, id);
+                        self.id_to_addr.insert(id, addr);
+                        server_evw.send(ServerTransportEvent::Connected(id))
+                    }
                 }
                 SocketEvent::Disconnect(addr) => {
-                    let id = *self.connected.get(&addr).unwrap();
-                    self.id_to_addr.remove(&id);
-                    self.connected.remove(&addr);
-                    server_evw.send(ServerTransportEvent::Disconnected(id))
+                    self.connecting.remove(&addr);
+                    self.kicked.remove(&addr);
+                    if let Some(id) = self.connected.remove(&addr) {
+                        self.id_to_addr.remove(&id);
+                        server_evw.send(ServerTransportEvent::Disconnected(id))
+                    }
                 }
                 SocketEvent::Timeout(_) => {
                     // TODO?
                 }
                 SocketEvent::Packet(packet) => {
+                    if self.kicked.contains(&packet.addr()) {
+                        continue;
+                    }
+
                     if !self.connected.contains_key(&packet.addr()) {
                         // TODO: check password.
-                        let _pw: String = bincode::deserialize(packet.payload()).unwrap();
-                        //println!("PW: {}", pw);
+                        let request: ConnectRequest = match bincode::deserialize(packet.payload()) {
+                            Ok(request) => request,
+                            Err(_) => continue,
+                        };
 
-                        self.connecting.insert(packet.addr(), self.id_counter);
+                        // A repeated request, like one resent while reconnecting,
+                        // gets the id it was already given. Its reservation is
+                        // used up by now.
+                        let id = match self.connecting.get(&packet.addr()) {
+                            Some(id) => *id,
+                            None => match request.reconnect {
+                                Some((id, token)) if self.reserved.get(&id) == Some(&token) => {
+                                    self.reserved.remove(&id);
+                                    id
+                                }
+                                _ => match self.next_free_id() {
+                                    Some(id) => id,
+                                    None => continue,
+                                },
+                            },
+                        };
+                        self.connecting.insert(packet.addr(), id);
                         send_packet(
                             &mut self.socket,
                             packet.addr(),
-                            vec![self.id_counter],
+                            vec![id],
                             DeliveryMethod::ReliableOrdered,
                         );
-                        self.id_counter += 1;
                         continue;
                     }
 
@@ -96,12 +158,9 @@ impl ServerTransport for LaminarServer {
     }
 
     fn send(&mut self, client_id: NetId, bytes: Vec<u8>, delivery: DeliveryMethod) {
-        send_packet(
-            &mut self.socket,
-            self.id_to_addr[&client_id],
-            bytes,
-            delivery,
-        );
+        if let Some(addr) = self.id_to_addr.get(&client_id) {
+            send_packet(&mut self.socket, *addr, bytes, delivery);
+        }
     }
 
     fn send_to_all(&mut self, bytes: Vec<u8>, delivery: DeliveryMethod) {
@@ -117,6 +176,29 @@ impl ServerTransport for LaminarServer {
             }
         }
     }
+
+    fn disconnect(&mut self, client_id: NetId) {
+        if let Some(addr) = self.id_to_addr.remove(&client_id) {
+            self.connected.remove(&addr);
+            self.kicked.insert(addr);
+            // An empty payload tells the client it was dropped.
+            send_packet(
+                &mut self.socket,
+                addr,
+                Vec::new(),
+                DeliveryMethod::ReliableOrdered,
+            );
+            self.disconnected.push(client_id);
+        }
+    }
+
+    fn reserve(&mut self, ids: &[(NetId, ReconnectToken)]) {
+        self.reserved = ids.iter().copied().collect();
+    }
+
+    fn peer_addr(&self, client_id: NetId) -> Option<SocketAddr> {
+        self.id_to_addr.get(&client_id).copied()
+    }
 }
 
 pub struct LaminarClient {
@@ -124,6 +206,7 @@ pub struct LaminarClient {
     server: Option<SocketAddr>,
     is_connecting: bool,
     is_connected: bool,
+    rejected: bool,
     id: NetId,
 }
 
@@ -145,9 +228,34 @@ impl LaminarClient {
             server: None,
             is_connecting: false,
             is_connected: false,
+            rejected: false,
             id: 0,
         }
     }
+
+    fn request(&mut self, target: ConnectTarget, reconnect: Option<(NetId, ReconnectToken)>) {
+        self.is_connecting = true;
+        match target {
+            ConnectTarget::Addr(addr) => {
+                // TODO: add password to argument.
+                let request = ConnectRequest {
+                    password: "password".into(),
+                    reconnect,
+                };
+                let bytes = bincode::serialize(&request).unwrap();
+                send_packet(
+                    &mut self.socket,
+                    addr,
+                    bytes,
+                    DeliveryMethod::ReliableOrdered,
+                );
+            }
+            ConnectTarget::Room(_) => {
+                // Room codes only make sense through a relay.
+                self.rejected = true;
+            }
+        }
+    }
 }
 
 impl ClientTransport for LaminarClient {
@@ -159,11 +267,12 @@ impl ClientTransport for LaminarClient {
         self.is_connected
     }
 
-    fn connect(&mut self, addr: SocketAddr) {
-        self.is_connecting = true;
-        // TODO: add password to argument.
-        let pw = bincode::serialize("password").unwrap();
-        send_packet(&mut self.socket, addr, pw, DeliveryMethod::ReliableOrdered);
+    fn connect(&mut self, target: ConnectTarget) {
+        self.request(target, None);
+    }
+
+    fn reconnect(&mut self, target: ConnectTarget, id: NetId, token: ReconnectToken) {
+        self.request(target, Some((id, token)));
     }
 
     fn poll(&mut self) {
@@ -171,6 +280,12 @@ impl ClientTransport for LaminarClient {
     }
 
     fn receive(&mut self, client_evw: &mut EventWriter<ClientTransportEvent>) {
+        if self.rejected {
+            self.rejected = false;
+            self.is_connecting = false;
+            client_evw.send(ClientTransportEvent::Disconnected);
+        }
+
         while let Some(socket_event) = self.socket.recv() {
             match socket_event {
                 SocketEvent::Connect(addr) => {
@@ -194,9 +309,18 @@ impl ClientTransport for LaminarClient {
                 }
                 SocketEvent::Packet(packet) => {
                     if self.is_connecting && self.is_connected {
-                        self.is_connecting = false;
-                        self.id = packet.payload()[0];
-                        client_evw.send(ClientTransportEvent::Connected(self.id));
+                        if let Some(id) = packet.payload().first() {
+                            self.is_connecting = false;
+                            self.id = *id;
+                            client_evw.send(ClientTransportEvent::Connected(self.id));
+                        }
+                        continue;
+                    }
+
+                    if packet.payload().is_empty() {
+                        self.is_connected = false;
+                        self.server = None;
+                        client_evw.send(ClientTransportEvent::Disconnected);
                         continue;
                     }
 
@@ -215,7 +339,12 @@ impl ClientTransport for LaminarClient {
     }
 }
 
-fn send_packet(socket: &mut Socket, addr: SocketAddr, bytes: Vec<u8>, delivery: DeliveryMethod) {
+pub(crate) fn send_packet(
+    socket: &mut Socket,
+    addr: SocketAddr,
+    bytes: Vec<u8>,
+    delivery: DeliveryMethod,
+) {
     match delivery {
         DeliveryMethod::ReliableOrdered => {
             socket
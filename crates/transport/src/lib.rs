@@ -4,10 +4,12 @@ use bevy::prelude::*;
 
 mod client;
 mod laminar;
+mod relay;
 mod server;
 
 pub use self::laminar::*;
 pub use client::*;
+pub use relay::*;
 pub use server::*;
 
 pub struct TransportPlugin;
@@ -21,21 +23,28 @@ impl Plugin for TransportPlugin {
 
 pub type NetId = u8;
 
+/// A secret a client is given by its server, to prove an id is its own when
+/// reconnecting somewhere else.
+pub type ReconnectToken = u64;
+
 #[derive(Debug, Clone, Copy)]
 pub enum Transport {
     Laminar,
+    Relay(SocketAddr),
 }
 
 impl Transport {
     pub fn server(&self, addr: Option<SocketAddr>) -> Box<dyn ServerTransport> {
         match self {
             Transport::Laminar => Box::new(LaminarServer::bind(addr)),
+            Transport::Relay(relay) => Box::new(RelayServer::bind(addr, *relay)),
         }
     }
 
     pub fn client(&self, addr: Option<SocketAddr>) -> Box<dyn ClientTransport> {
         match self {
             Transport::Laminar => Box::new(LaminarClient::bind(addr)),
+            Transport::Relay(relay) => Box::new(RelayClient::bind(addr, *relay)),
         }
     }
 }
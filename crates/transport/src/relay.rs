@@ -0,0 +1,561 @@
+use std::{
+    fmt,
+    net::SocketAddr,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use bevy::{prelude::EventWriter, utils::HashMap};
+use bytes::Bytes;
+use laminar::{Config, Socket, SocketEvent};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    client::{ClientTransport, ClientTransportEvent, ConnectTarget},
+    laminar::send_packet,
+    server::{ServerTransport, ServerTransportEvent},
+    DeliveryMethod, NetId,
+};
+
+pub type PeerTag = u32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct RoomCode([u8; RoomCode::LEN]);
+
+impl RoomCode {
+    pub const LEN: usize = 4;
+
+    pub fn from_fn(mut digit: impl FnMut() -> char) -> Self {
+        let mut code = [b'0'; Self::LEN];
+        for c in code.iter_mut() {
+            *c = digit() as u8;
+        }
+        Self(code)
+    }
+}
+
+impl fmt::Display for RoomCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for c in self.0 {
+            write!(f, "{}", c as char)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for RoomCode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.len() != Self::LEN || !s.chars().all(|c| c.is_ascii_digit()) {
+            return Err(());
+        }
+
+        let mut chars = s.chars();
+        Ok(Self::from_fn(|| chars.next().unwrap()))
+    }
+}
+
+/// Datagrams exchanged between the relay and the hosts/clients that use it.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum RelayPacket {
+    // Host -> Relay
+    Host,
+    ToPeer(PeerTag, Vec<u8>),
+    Kick(PeerTag),
+    // Relay -> Host
+    HostAccepted(RoomCode),
+    /// Every room code is taken, so the host gets no room.
+    HostRejected,
+    PeerJoined(PeerTag),
+    PeerLeft(PeerTag),
+    FromPeer(PeerTag, Vec<u8>),
+    // Client -> Relay
+    Join(RoomCode),
+    ToHost(Vec<u8>),
+    // Relay -> Client
+    JoinRejected,
+    HostLeft,
+    FromHost(Vec<u8>),
+}
+
+fn bind_socket(addr: Option<SocketAddr>) -> Socket {
+    let cfg = Config {
+        heartbeat_interval: Some(Duration::from_secs_f32(1.0)),
+        ..Default::default()
+    };
+
+    match addr {
+        Some(addr) => Socket::bind_with_config(addr, cfg).unwrap(),
+        None => Socket::bind_any_with_config(cfg).unwrap(),
+    }
+}
+
+fn send_relay_packet(
+    socket: &mut Socket,
+    relay: SocketAddr,
+    packet: &RelayPacket,
+    delivery: DeliveryMethod,
+) {
+    let bytes = bincode::serialize(packet).unwrap();
+    send_packet(socket, relay, bytes, delivery);
+}
+
+pub struct RelayServer {
+    socket: Socket,
+    relay: SocketAddr,
+    room: Option<RoomCode>,
+    peer_to_id: HashMap<PeerTag, NetId>,
+    id_to_peer: HashMap<NetId, PeerTag>,
+    disconnected: Vec<NetId>,
+    id_counter: NetId,
+}
+
+impl RelayServer {
+    // TODO: Return Result<Self, Error>
+    pub fn bind(addr: Option<SocketAddr>, relay: SocketAddr) -> Self {
+        let mut socket = bind_socket(addr);
+        send_relay_packet(
+            &mut socket,
+            relay,
+            &RelayPacket::Host,
+            DeliveryMethod::ReliableOrdered,
+        );
+
+        Self {
+            socket,
+            relay,
+            room: None,
+            peer_to_id: HashMap::default(),
+            id_to_peer: HashMap::default(),
+            disconnected: Vec::new(),
+            id_counter: 0,
+        }
+    }
+
+    /// The next id that isn't given to a peer.
+    fn next_free_id(&mut self) -> Option<NetId> {
+        for _ in 0..=NetId::MAX {
+            let id = self.id_counter;
+            self.id_counter = self.id_counter.wrapping_add(1);
+
+            if !self.id_to_peer.contains_key(&id) {
+                return Some(id);
+            }
+        }
+        None
+    }
+
+    fn send_to_peer(&mut self, peer: PeerTag, bytes: Vec<u8>, delivery: DeliveryMethod) {
+        send_relay_packet(
+            &mut self.socket,
+            self.relay,
+            &RelayPacket::ToPeer(peer, bytes),
+            delivery,
+        );
+    }
+}
+
+impl ServerTransport for RelayServer {
+    fn poll(&mut self) {
+        self.socket.manual_poll(Instant::now());
+    }
+
+    fn receive(&mut self, server_evw: &mut EventWriter<ServerTransportEvent>) {
+        for id in self.disconnected.drain(..) {
+            server_evw.send(ServerTransportEvent::Disconnected(id));
+        }
+
+        while let Some(socket_event) = self.socket.recv() {
+            match socket_event {
+                SocketEvent::Connect(_) => {}
+                SocketEvent::Disconnect(addr) | SocketEvent::Timeout(addr) => {
+                    if addr != self.relay {
+                        continue;
+                    }
+                    self.room = None;
+                    self.peer_to_id.clear();
+                    for (id, _) in self.id_to_peer.drain() {
+                        server_evw.send(ServerTransportEvent::Disconnected(id));
+                    }
+                }
+                SocketEvent::Packet(packet) => {
+                    if packet.addr() != self.relay {
+                        continue;
+                    }
+                    let relay_packet: RelayPacket = match bincode::deserialize(packet.payload()) {
+                        Ok(relay_packet) => relay_packet,
+                        Err(_) => continue,
+                    };
+                    match relay_packet {
+                        RelayPacket::HostAccepted(room) => {
+                            self.room = Some(room);
+                        }
+                        RelayPacket::PeerJoined(peer) => {
+                            let id = match self.next_free_id() {
+                                Some(id) => id,
+                                None => {
+                                    send_relay_packet(
+                                        &mut self.socket,
+                                        self.relay,
+                                        &RelayPacket::Kick(peer),
+                                        DeliveryMethod::ReliableOrdered,
+                                    );
+                                    continue;
+                                }
+                            };
+                            self.peer_to_id.insert(peer, id);
+                            self.id_to_peer.insert(id, peer);
+                            self.send_to_peer(peer, vec![id], DeliveryMethod::ReliableOrdered);
+                            server_evw.send(ServerTransportEvent::Connected(id));
+                        }
+                        RelayPacket::PeerLeft(peer) => {
+                            if let Some(id) = self.peer_to_id.remove(&peer) {
+                                self.id_to_peer.remove(&id);
+                                server_evw.send(ServerTransportEvent::Disconnected(id));
+                            }
+                        }
+                        RelayPacket::FromPeer(peer, payload) => {
+                            if let Some(id) = self.peer_to_id.get(&peer) {
+                                server_evw
+                                    .send(ServerTransportEvent::Message(*id, Bytes::from(payload)));
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            };
+        }
+    }
+
+    fn send(&mut self, client_id: NetId, bytes: Vec<u8>, delivery: DeliveryMethod) {
+        if let Some(peer) = self.id_to_peer.get(&client_id).copied() {
+            self.send_to_peer(peer, bytes, delivery);
+        }
+    }
+
+    fn send_to_all(&mut self, bytes: Vec<u8>, delivery: DeliveryMethod) {
+        let peers = self.id_to_peer.values().copied().collect::<Vec<_>>();
+        for peer in peers {
+            self.send_to_peer(peer, bytes.clone(), delivery);
+        }
+    }
+
+    fn send_to_all_except(&mut self, client_id: NetId, bytes: Vec<u8>, delivery: DeliveryMethod) {
+        let peers = self
+            .id_to_peer
+            .iter()
+            .filter(|(id, _)| **id != client_id)
+            .map(|(_, peer)| *peer)
+            .collect::<Vec<_>>();
+        for peer in peers {
+            self.send_to_peer(peer, bytes.clone(), delivery);
+        }
+    }
+
+    fn disconnect(&mut self, client_id: NetId) {
+        if let Some(peer) = self.id_to_peer.remove(&client_id) {
+            self.peer_to_id.remove(&peer);
+            send_relay_packet(
+                &mut self.socket,
+                self.relay,
+                &RelayPacket::Kick(peer),
+                DeliveryMethod::ReliableOrdered,
+            );
+            self.disconnected.push(client_id);
+        }
+    }
+
+    fn room_code(&self) -> Option<RoomCode> {
+        self.room
+    }
+}
+
+pub struct RelayClient {
+    socket: Socket,
+    relay: SocketAddr,
+    is_connecting: bool,
+    is_connected: bool,
+    rejected: bool,
+    id: NetId,
+}
+
+impl RelayClient {
+    // TODO: Return Result<Self, Error>
+    pub fn bind(addr: Option<SocketAddr>, relay: SocketAddr) -> Self {
+        Self {
+            socket: bind_socket(addr),
+            relay,
+            is_connecting: false,
+            is_connected: false,
+            rejected: false,
+            id: 0,
+        }
+    }
+
+    fn reset(&mut self, client_evw: &mut EventWriter<ClientTransportEvent>) {
+        if self.is_connecting || self.is_connected {
+            client_evw.send(ClientTransportEvent::Disconnected);
+        }
+        self.is_connecting = false;
+        self.is_connected = false;
+    }
+}
+
+impl ClientTransport for RelayClient {
+    fn get_id(&self) -> NetId {
+        self.id
+    }
+
+    fn is_connected(&self) -> bool {
+        self.is_connected
+    }
+
+    fn connect(&mut self, target: ConnectTarget) {
+        self.is_connecting = true;
+        match target {
+            ConnectTarget::Room(room) => {
+                send_relay_packet(
+                    &mut self.socket,
+                    self.relay,
+                    &RelayPacket::Join(room),
+                    DeliveryMethod::ReliableOrdered,
+                );
+            }
+            ConnectTarget::Addr(_) => {
+                self.rejected = true;
+            }
+        }
+    }
+
+    fn poll(&mut self) {
+        self.socket.manual_poll(Instant::now());
+    }
+
+    fn receive(&mut self, client_evw: &mut EventWriter<ClientTransportEvent>) {
+        if self.rejected {
+            self.rejected = false;
+            self.reset(client_evw);
+        }
+
+        while let Some(socket_event) = self.socket.recv() {
+            match socket_event {
+                SocketEvent::Connect(_) => {}
+                SocketEvent::Disconnect(addr) | SocketEvent::Timeout(addr) => {
+                    if addr == self.relay {
+                        self.reset(client_evw);
+                    }
+                }
+                SocketEvent::Packet(packet) => {
+                    if packet.addr() != self.relay {
+                        continue;
+                    }
+                    let relay_packet: RelayPacket = match bincode::deserialize(packet.payload()) {
+                        Ok(relay_packet) => relay_packet,
+                        Err(_) => continue,
+                    };
+                    match relay_packet {
+                        RelayPacket::JoinRejected | RelayPacket::HostLeft => {
+                            self.reset(client_evw);
+                        }
+                        RelayPacket::FromHost(payload) => {
+                            if self.is_connecting && !self.is_connected {
+                                if let Some(id) = payload.first() {
+                                    self.is_connecting = false;
+                                    self.is_connected = true;
+                                    self.id = *id;
+                                    client_evw.send(ClientTransportEvent::Connected(self.id));
+                                }
+                                continue;
+                            }
+
+                            client_evw.send(ClientTransportEvent::Message(Bytes::from(payload)));
+                        }
+                        _ => {}
+                    }
+                }
+            };
+        }
+    }
+
+    fn send(&mut self, bytes: Vec<u8>, delivery: DeliveryMethod) {
+        if self.is_connected {
+            send_relay_packet(
+                &mut self.socket,
+                self.relay,
+                &RelayPacket::ToHost(bytes),
+                delivery,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use bevy::{
+        ecs::system::SystemState,
+        prelude::{Events, World},
+    };
+
+    use super::*;
+
+    fn localhost() -> SocketAddr {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)
+    }
+
+    /// Stands in for the relay, to see what a transport sends it.
+    struct FakeRelay {
+        socket: Socket,
+    }
+
+    impl FakeRelay {
+        fn new() -> Self {
+            Self {
+                socket: bind_socket(Some(localhost())),
+            }
+        }
+
+        fn addr(&self) -> SocketAddr {
+            self.socket.local_addr().unwrap()
+        }
+
+        fn send(&mut self, to: SocketAddr, packet: &RelayPacket) {
+            send_relay_packet(
+                &mut self.socket,
+                to,
+                packet,
+                DeliveryMethod::ReliableOrdered,
+            );
+            self.socket.manual_poll(Instant::now());
+        }
+
+        /// Waits for the next packet, along with where it came from.
+        fn receive(&mut self) -> (SocketAddr, RelayPacket) {
+            let start = Instant::now();
+            while start.elapsed() < Duration::from_secs(1) {
+                self.socket.manual_poll(Instant::now());
+                while let Some(event) = self.socket.recv() {
+                    if let SocketEvent::Packet(packet) = event {
+                        let relay_packet = bincode::deserialize(packet.payload()).unwrap();
+                        return (packet.addr(), relay_packet);
+                    }
+                }
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            panic!("nothing was sent to the relay");
+        }
+    }
+
+    /// Runs `receive` until `done` holds for the events it raised, or for a
+    /// second.
+    fn receive_until<T: Send + Sync + 'static>(
+        mut receive: impl FnMut(&mut EventWriter<T>),
+        mut done: impl FnMut(&[T]) -> bool,
+    ) -> Vec<T> {
+        let mut world = World::new();
+        world.insert_resource(Events::<T>::default());
+        let mut state = SystemState::<EventWriter<T>>::new(&mut world);
+
+        let mut received = Vec::new();
+        let start = Instant::now();
+        while !done(&received) && start.elapsed() < Duration::from_secs(1) {
+            receive(&mut state.get_mut(&mut world));
+            received.extend(world.get_resource_mut::<Events<T>>().unwrap().drain());
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        received
+    }
+
+    #[test]
+    fn server_hosts_a_room_and_gives_peers_ids() {
+        let mut relay = FakeRelay::new();
+        let mut server = RelayServer::bind(Some(localhost()), relay.addr());
+        server.poll();
+        let (host, packet) = relay.receive();
+        assert!(matches!(packet, RelayPacket::Host));
+
+        let room = "1234".parse().unwrap();
+        relay.send(host, &RelayPacket::HostAccepted(room));
+        relay.send(host, &RelayPacket::PeerJoined(7));
+        let events = receive_until(
+            |evw| {
+                server.poll();
+                server.receive(evw);
+            },
+            |events| !events.is_empty(),
+        );
+        assert_eq!(server.room_code(), Some(room));
+        assert!(matches!(&events[..], [ServerTransportEvent::Connected(0)]));
+
+        server.poll();
+        assert!(matches!(relay.receive().1, RelayPacket::ToPeer(7, id) if id == [0]));
+
+        relay.send(host, &RelayPacket::FromPeer(7, vec![5]));
+        let events = receive_until(
+            |evw| {
+                server.poll();
+                server.receive(evw);
+            },
+            |events| !events.is_empty(),
+        );
+        assert!(matches!(
+            &events[..],
+            [ServerTransportEvent::Message(0, bytes)] if bytes[..] == [5]
+        ));
+    }
+
+    #[test]
+    fn server_ids_skip_taken_ones_until_none_are_left() {
+        let relay = FakeRelay::new();
+        let mut server = RelayServer::bind(Some(localhost()), relay.addr());
+        for id in (0..=NetId::MAX).filter(|id| *id != 3) {
+            server.id_to_peer.insert(id, id as PeerTag);
+        }
+        server.id_counter = 250;
+
+        assert_eq!(server.next_free_id(), Some(3));
+        server.id_to_peer.insert(3, 3);
+        assert_eq!(server.next_free_id(), None);
+    }
+
+    #[test]
+    fn client_joins_a_room_through_the_relay() {
+        let mut relay = FakeRelay::new();
+        let mut client = RelayClient::bind(Some(localhost()), relay.addr());
+        let room = "0042".parse().unwrap();
+        client.connect(ConnectTarget::Room(room));
+        client.poll();
+        let (peer, packet) = relay.receive();
+        assert!(matches!(packet, RelayPacket::Join(joined) if joined == room));
+
+        relay.send(peer, &RelayPacket::FromHost(vec![4]));
+        relay.send(peer, &RelayPacket::FromHost(vec![9, 9]));
+        let events = receive_until(
+            |evw| {
+                client.poll();
+                client.receive(evw);
+            },
+            |events| events.len() >= 2,
+        );
+        assert!(matches!(
+            &events[..],
+            [ClientTransportEvent::Connected(4), ClientTransportEvent::Message(bytes)]
+                if bytes[..] == [9, 9]
+        ));
+        assert!(client.is_connected());
+
+        relay.send(peer, &RelayPacket::HostLeft);
+        let events = receive_until(
+            |evw| {
+                client.poll();
+                client.receive(evw);
+            },
+            |events| !events.is_empty(),
+        );
+        assert!(matches!(&events[..], [ClientTransportEvent::Disconnected]));
+        assert!(!client.is_connected());
+    }
+}
@@ -1,7 +1,9 @@
+use std::net::SocketAddr;
+
 use bevy::prelude::*;
 use bytes::Bytes;
 
-use crate::{DeliveryMethod, NetId};
+use crate::{DeliveryMethod, NetId, ReconnectToken, RoomCode};
 
 pub(crate) struct ServerTransportPlugin;
 
@@ -20,6 +22,20 @@ where
     fn send(&mut self, client_id: NetId, bytes: Vec<u8>, delivery: DeliveryMethod);
     fn send_to_all(&mut self, bytes: Vec<u8>, delivery: DeliveryMethod);
     fn send_to_all_except(&mut self, client_id: NetId, bytes: Vec<u8>, delivery: DeliveryMethod);
+    fn disconnect(&mut self, client_id: NetId);
+
+    fn room_code(&self) -> Option<RoomCode> {
+        None
+    }
+
+    /// Holds back ids from new connections, except ones asking for them back
+    /// with the matching token. Replaces any ids reserved before.
+    fn reserve(&mut self, _ids: &[(NetId, ReconnectToken)]) {}
+
+    /// The address a client connects from, if the transport sees it directly.
+    fn peer_addr(&self, _client_id: NetId) -> Option<SocketAddr> {
+        None
+    }
 }
 
 pub enum ServerTransportEvent {
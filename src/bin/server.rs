@@ -0,0 +1,5 @@
+use bevy_snapshot_interpolation::{config::Config, dedicated};
+
+fn main() {
+    dedicated::run(Config::from_args_or_exit(Config::default()));
+}
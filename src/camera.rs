@@ -1,16 +1,33 @@
 use bevy::prelude::*;
 
-use crate::{cleanup::Cleanup, player::LocalPlayer, AppState};
+use crate::{
+    chat::ChatInput,
+    cleanup::Cleanup,
+    network::{NetId, NetworkId},
+    player::{LocalPlayer, Player},
+    roster::PlayerRoster,
+    AppState,
+};
 
 pub struct CameraPlugin;
 
 impl Plugin for CameraPlugin {
     fn build(&self, app: &mut App) {
-        app.add_system_set(SystemSet::on_enter(AppState::Game).with_system(spawn_camera))
-            .add_system_set(SystemSet::on_update(AppState::Game).with_system(follow_player));
+        app.init_resource::<WatchedPlayer>()
+            .add_system_set(SystemSet::on_enter(AppState::Game).with_system(spawn_camera))
+            .add_system_set(
+                SystemSet::on_update(AppState::Game)
+                    .with_system(watch_players)
+                    .with_system(follow_player),
+            );
     }
 }
 
+/// The player the camera follows while there's no local player, like when
+/// spectating.
+#[derive(Default)]
+pub struct WatchedPlayer(pub Option<NetId>);
+
 #[derive(Component)]
 struct CameraPivot;
 
@@ -29,12 +46,61 @@ fn spawn_camera(mut commands: Commands) {
         });
 }
 
+/// Without a player of its own, the camera watches someone else's. 'Tab'
+/// moves on to the next one.
+fn watch_players(
+    keyboard: Res<Input<KeyCode>>,
+    chat: Res<ChatInput>,
+    roster: Res<PlayerRoster>,
+    mut watched: ResMut<WatchedPlayer>,
+    local_player_q: Query<Entity, With<LocalPlayer>>,
+    player_q: Query<&NetworkId, With<Player>>,
+) {
+    if local_player_q.iter().next().is_some() {
+        watched.0 = None;
+        return;
+    }
+
+    let mut ids = player_q.iter().map(|id| id.value()).collect::<Vec<_>>();
+    ids.sort_unstable();
+
+    let current = watched.0.filter(|id| ids.contains(id));
+    let next = if keyboard.just_pressed(KeyCode::Tab) && !chat.is_typing() {
+        match current {
+            Some(current) => ids
+                .iter()
+                .copied()
+                .find(|id| *id > current)
+                .or_else(|| ids.first().copied()),
+            None => ids.first().copied(),
+        }
+    } else {
+        current.or_else(|| ids.first().copied())
+    };
+
+    if next != watched.0 {
+        if let Some(id) = next {
+            println!("Watching {}.", roster.name(id));
+        }
+        watched.0 = next;
+    }
+}
+
 fn follow_player(
-    player_q: Query<&Transform, With<LocalPlayer>>,
-    mut pivot_q: Query<&mut Transform, (With<CameraPivot>, Without<LocalPlayer>)>,
+    local_player_q: Query<&Transform, With<LocalPlayer>>,
+    player_q: Query<(&NetworkId, &Transform), With<Player>>,
+    watched: Res<WatchedPlayer>,
+    mut pivot_q: Query<&mut Transform, (With<CameraPivot>, Without<Player>, Without<LocalPlayer>)>,
     time: Res<Time>,
 ) {
-    for player in player_q.iter() {
+    let target = local_player_q.iter().next().or_else(|| {
+        player_q
+            .iter()
+            .find(|(id, _)| Some(id.value()) == watched.0)
+            .map(|(_, transform)| transform)
+    });
+
+    if let Some(player) = target {
         for mut pivot in pivot_q.iter_mut() {
             let distance = player.translation.distance(pivot.translation);
             let t = f32::powf(0.25, distance * time.delta_seconds());
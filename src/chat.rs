@@ -0,0 +1,444 @@
+use std::collections::VecDeque;
+
+use bevy::{prelude::*, utils::HashMap};
+use serde::{Deserialize, Serialize};
+use transport::DeliveryMethod;
+
+use crate::{
+    network::*,
+    roster::{PlayerRoster, RosterEvent},
+    run_criteria::{
+        client_run_criteria, game_client_run_criteria, server_run_criteria, RoleSystem,
+    },
+    spectator::SpectatorCommand,
+    AppState,
+};
+
+pub struct ChatPlugin;
+
+impl Plugin for ChatPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ChatSettings>()
+            .init_resource::<ChatLog>()
+            .init_resource::<ChatInput>()
+            .init_resource::<ChatRateLimits>()
+            .add_event::<SystemMessage>()
+            .add_client_message::<SendChat>(DeliveryMethod::ReliableOrdered)
+            .add_server_message::<ChatMessage>(DeliveryMethod::ReliableOrdered)
+            .add_system_set(SystemSet::on_enter(AppState::Menu).with_system(clear_chat))
+            .add_system_set(
+                SystemSet::new()
+                    .with_run_criteria(server_run_criteria)
+                    .with_system(receive_chat.after(NetworkSystem::Receive))
+                    .with_system(announce_roster_changes)
+                    .with_system(send_system_messages)
+                    .with_system(remove_disconnected_rate_limits),
+            )
+            .add_system_set(
+                SystemSet::new()
+                    .with_run_criteria(client_run_criteria)
+                    .with_system(receive_chat_messages.after(NetworkSystem::Receive)),
+            )
+            .add_system_set(
+                SystemSet::new()
+                    .with_run_criteria(game_client_run_criteria)
+                    .label(RoleSystem::Client)
+                    .with_system(type_chat),
+            );
+    }
+}
+
+/// A chat line from a client, for the server to check and relay.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SendChat {
+    pub text: String,
+}
+
+/// A chat line relayed to every client.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub sender: ChatSender,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChatSender {
+    /// The server itself, like "player joined".
+    System,
+    Player(NetId),
+}
+
+/// Server-side, sends a chat line from [`ChatSender::System`] to everyone.
+#[derive(Debug, Clone)]
+pub struct SystemMessage(pub String);
+
+/// What a filter does with a chat line.
+pub enum ChatFilterAction {
+    Pass,
+    /// Send this text instead.
+    Replace(String),
+    /// Don't send it. The sender is told why.
+    Reject(String),
+}
+
+/// Runs on the server for every chat line, with the sender and the text so
+/// far, after the length cap.
+pub type ChatFilter = Box<dyn Fn(NetId, &str) -> ChatFilterAction + Send + Sync>;
+
+pub struct ChatSettings {
+    /// Longest chat line, in characters. Longer lines are cut off.
+    pub max_length: usize,
+    /// Chat lines a client can send in a row before being limited.
+    pub burst: f32,
+    /// Chat lines a client can send per second once limited.
+    pub per_second: f32,
+    /// Lines kept in the [`ChatLog`].
+    pub history: usize,
+    /// Applied in order. Add profanity filters and the like here.
+    pub filters: Vec<ChatFilter>,
+}
+
+impl Default for ChatSettings {
+    fn default() -> Self {
+        Self {
+            max_length: 120,
+            burst: 5.0,
+            per_second: 1.0,
+            history: 100,
+            filters: Vec::new(),
+        }
+    }
+}
+
+impl ChatSettings {
+    pub fn add_filter(
+        &mut self,
+        filter: impl Fn(NetId, &str) -> ChatFilterAction + Send + Sync + 'static,
+    ) {
+        self.filters.push(Box::new(filter));
+    }
+
+    /// Control characters removed, trimmed and cut to `max_length`.
+    fn clean(&self, text: &str) -> String {
+        text.chars()
+            .filter(|c| !c.is_control())
+            .collect::<String>()
+            .trim()
+            .chars()
+            .take(self.max_length)
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ChatLine {
+    pub sender: ChatSender,
+    /// The sender's name when the line arrived.
+    pub name: String,
+    pub text: String,
+}
+
+/// Recent chat, oldest first, for the game to show.
+#[derive(Default)]
+pub struct ChatLog {
+    lines: VecDeque<ChatLine>,
+}
+
+impl ChatLog {
+    pub fn iter(&self) -> impl Iterator<Item = &ChatLine> + '_ {
+        self.lines.iter()
+    }
+
+    fn push(&mut self, line: ChatLine, history: usize) {
+        match line.sender {
+            ChatSender::System => println!("[Chat] {}", line.text),
+            ChatSender::Player(_) => println!("[Chat] {}: {}", line.name, line.text),
+        }
+
+        self.lines.push_back(line);
+        while self.lines.len() > history {
+            self.lines.pop_front();
+        }
+    }
+
+    /// Adds a relayed message, naming the sender from the roster.
+    fn receive(&mut self, message: &ChatMessage, roster: &PlayerRoster, history: usize) {
+        let name = match message.sender {
+            ChatSender::System => String::new(),
+            ChatSender::Player(id) => roster.name(id),
+        };
+        let line = ChatLine {
+            sender: message.sender,
+            name,
+            text: message.text.clone(),
+        };
+        self.push(line, history);
+    }
+}
+
+fn clear_chat(mut log: ResMut<ChatLog>, mut input: ResMut<ChatInput>) {
+    log.lines.clear();
+    *input = ChatInput::default();
+}
+
+//
+// Server
+//
+
+/// Per-client token buckets.
+#[derive(Default)]
+struct ChatRateLimits(HashMap<NetId, RateLimit>);
+
+struct RateLimit {
+    tokens: f32,
+    last_update: f64,
+    /// Whether the client has been told it's sending too quickly since it
+    /// last got a message through.
+    warned: bool,
+}
+
+enum Throttle {
+    Allow,
+    /// Drop the message, and tell the client why.
+    Warn,
+    /// Drop the message, the client already knows.
+    Drop,
+}
+
+impl ChatRateLimits {
+    fn try_take(&mut self, client_id: NetId, now: f64, settings: &ChatSettings) -> Throttle {
+        let limit = self.0.entry(client_id).or_insert(RateLimit {
+            tokens: settings.burst,
+            last_update: now,
+            warned: false,
+        });
+
+        let elapsed = (now - limit.last_update) as f32;
+        limit.tokens = (limit.tokens + elapsed * settings.per_second).min(settings.burst);
+        limit.last_update = now;
+
+        if limit.tokens < 1.0 {
+            return if std::mem::replace(&mut limit.warned, true) {
+                Throttle::Drop
+            } else {
+                Throttle::Warn
+            };
+        }
+        limit.tokens -= 1.0;
+        limit.warned = false;
+        Throttle::Allow
+    }
+}
+
+fn receive_chat(
+    mut chat_evr: EventReader<FromClient<SendChat>>,
+    mut spectator_evw: EventWriter<SpectatorCommand>,
+    mut server: ResMut<Server>,
+    mut limits: ResMut<ChatRateLimits>,
+    mut log: ResMut<ChatLog>,
+    settings: Res<ChatSettings>,
+    roster: Res<PlayerRoster>,
+    role: Res<State<NetworkRole>>,
+    time: Res<Time>,
+) {
+    let now = time.seconds_since_startup();
+
+    for FromClient { id, message } in chat_evr.iter() {
+        match limits.try_take(*id, now, &settings) {
+            Throttle::Allow => {}
+            Throttle::Warn => {
+                tell(&mut server, *id, "You're sending messages too quickly.");
+                continue;
+            }
+            Throttle::Drop => continue,
+        }
+
+        let mut text = settings.clean(&message.text);
+
+        // Commands act on the sender and aren't relayed.
+        match text.as_str() {
+            "/spectate" => {
+                spectator_evw.send(SpectatorCommand::Demote(*id));
+                continue;
+            }
+            "/play" => {
+                spectator_evw.send(SpectatorCommand::Promote(*id));
+                continue;
+            }
+            _ => {}
+        }
+
+        let mut rejected = None;
+        for filter in settings.filters.iter() {
+            match filter(*id, &text) {
+                ChatFilterAction::Pass => {}
+                ChatFilterAction::Replace(replacement) => text = settings.clean(&replacement),
+                ChatFilterAction::Reject(reason) => {
+                    rejected = Some(reason);
+                    break;
+                }
+            }
+        }
+
+        if let Some(reason) = rejected {
+            tell(&mut server, *id, &reason);
+            continue;
+        }
+        if text.is_empty() {
+            continue;
+        }
+
+        let message = ChatMessage {
+            sender: ChatSender::Player(*id),
+            text,
+        };
+        server.send_message(&message);
+
+        // A listen server logs the message when its own client receives it.
+        if !role.current().has_local_player() {
+            log.receive(&message, &roster, settings.history);
+        }
+    }
+}
+
+/// A system message to one client only.
+fn tell(server: &mut Server, client_id: NetId, text: &str) {
+    server.send_message_to(
+        client_id,
+        &ChatMessage {
+            sender: ChatSender::System,
+            text: text.into(),
+        },
+    );
+}
+
+fn announce_roster_changes(
+    mut names: Local<HashMap<NetId, String>>,
+    mut roster_evr: EventReader<RosterEvent>,
+    mut system_message_evw: EventWriter<SystemMessage>,
+    roster: Res<PlayerRoster>,
+) {
+    for event in roster_evr.iter() {
+        match event {
+            RosterEvent::Joined(id) => {
+                let name = roster.name(*id);
+                system_message_evw.send(SystemMessage(format!("{} joined.", name)));
+                names.insert(*id, name);
+            }
+            RosterEvent::Changed(id) => {
+                let name = roster.name(*id);
+                if let Some(old_name) = names.insert(*id, name.clone()) {
+                    if old_name != name {
+                        system_message_evw
+                            .send(SystemMessage(format!("{} is now {}.", old_name, name)));
+                    }
+                }
+            }
+            RosterEvent::Left(id) => {
+                // The entry is already gone. Players this server never saw
+                // join, like after a host migration, are announced by
+                // whatever removed them.
+                if let Some(name) = names.remove(id) {
+                    system_message_evw.send(SystemMessage(format!("{} left.", name)));
+                }
+            }
+        }
+    }
+}
+
+fn send_system_messages(
+    mut system_message_evr: EventReader<SystemMessage>,
+    mut server: ResMut<Server>,
+    mut log: ResMut<ChatLog>,
+    settings: Res<ChatSettings>,
+    roster: Res<PlayerRoster>,
+    role: Res<State<NetworkRole>>,
+) {
+    for SystemMessage(text) in system_message_evr.iter() {
+        let message = ChatMessage {
+            sender: ChatSender::System,
+            text: text.clone(),
+        };
+        server.send_message(&message);
+
+        if !role.current().has_local_player() {
+            log.receive(&message, &roster, settings.history);
+        }
+    }
+}
+
+fn remove_disconnected_rate_limits(
+    mut server_evr: EventReader<ServerEvent>,
+    mut limits: ResMut<ChatRateLimits>,
+) {
+    for event in server_evr.iter() {
+        if let ServerEvent::PlayerDisconnected(id) = event {
+            limits.0.remove(id);
+        }
+    }
+}
+
+//
+// Client
+//
+
+/// The chat line being typed, if any.
+#[derive(Default)]
+pub struct ChatInput {
+    typing: bool,
+    text: String,
+}
+
+impl ChatInput {
+    /// Whether keys are going to the chat, and shouldn't do anything else.
+    pub fn is_typing(&self) -> bool {
+        self.typing
+    }
+}
+
+fn type_chat(
+    keyboard: Res<Input<KeyCode>>,
+    mut char_evr: EventReader<ReceivedCharacter>,
+    mut input: ResMut<ChatInput>,
+    mut client: ResMut<Client>,
+    settings: Res<ChatSettings>,
+) {
+    if !input.typing {
+        // Skip the 't' that opened the chat.
+        char_evr.iter().for_each(drop);
+        if keyboard.just_pressed(KeyCode::T) {
+            input.typing = true;
+            println!("Chat: type a message, '/spectate' or '/play' and press 'Enter'.");
+        }
+        return;
+    }
+
+    for event in char_evr.iter() {
+        if !event.char.is_control() && input.text.chars().count() < settings.max_length {
+            input.text.push(event.char);
+        }
+    }
+
+    if keyboard.just_pressed(KeyCode::Back) {
+        input.text.pop();
+    }
+
+    if keyboard.just_pressed(KeyCode::Return) {
+        let text = std::mem::take(&mut input.text);
+        if !text.trim().is_empty() {
+            client.send_message(&SendChat { text });
+        }
+        input.typing = false;
+    }
+}
+
+fn receive_chat_messages(
+    mut chat_evr: EventReader<FromServer<ChatMessage>>,
+    mut log: ResMut<ChatLog>,
+    settings: Res<ChatSettings>,
+    roster: Res<PlayerRoster>,
+) {
+    for FromServer(message) in chat_evr.iter() {
+        log.receive(message, &roster, settings.history);
+    }
+}
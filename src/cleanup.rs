@@ -6,7 +6,8 @@ pub struct CleanupPlugin;
 
 impl Plugin for CleanupPlugin {
     fn build(&self, app: &mut App) {
-        app.add_system_set(SystemSet::on_enter(AppState::Menu).with_system(on_enter_game));
+        app.add_system_set(SystemSet::on_enter(AppState::Menu).with_system(on_enter_game))
+            .add_system_set(SystemSet::on_enter(AppState::Migrating).with_system(on_enter_game));
     }
 }
 
@@ -0,0 +1,430 @@
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::Path,
+};
+
+use ron::extensions::Extensions;
+use serde::Deserialize;
+use transport::{RoomCode, Transport};
+
+use crate::network::{TICK_RATE, TICK_SECONDS};
+
+const USAGE: &str = "\
+Options:
+    --config <path>         Read options from a RON file, before applying the flags below
+    --host                  Host a game
+    --join [addr|room]      Join a game, at the server address or relay room code
+    --dedicated             Run a dedicated server without a window
+    --bind <ip>             Address to host on, 127.0.0.1 by default or 0.0.0.0 for a dedicated server
+    --port <port>           Port to host on
+    --server <addr>         Server address to join
+    --transport <name>      laminar or relay
+    --relay <addr>          Relay server address
+    --input-rate <hz>       Input packets sent per second
+    --snapshot-rate <hz>    Snapshots sent per second when hosting
+    --name <name>           Player name
+    --spectate              Watch the game without a player
+    --help                  Print this message";
+
+/// How to start, without going through the menu.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum LaunchRole {
+    Host,
+    Join,
+    Dedicated,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum TransportKind {
+    Laminar,
+    Relay,
+}
+
+/// Startup options. Loaded from an optional RON config file, then overridden
+/// by command-line flags.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// `None` to pick in the menu.
+    pub role: Option<LaunchRole>,
+    /// `None` for the role's default, see [`bind`](Self::bind).
+    pub bind_addr: Option<IpAddr>,
+    pub port: u16,
+    pub server_addr: SocketAddr,
+    /// The relay room to join through, when using the relay.
+    pub room: Option<String>,
+    pub transport: TransportKind,
+    pub relay_addr: SocketAddr,
+    pub input_rate: f32,
+    pub snapshot_rate: f32,
+    pub player_name: String,
+    /// Join as a spectator, without a player of your own.
+    pub spectate: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            role: None,
+            bind_addr: None,
+            port: 12345,
+            server_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 12345),
+            room: None,
+            transport: TransportKind::Laminar,
+            relay_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 12346),
+            input_rate: 20.0,
+            snapshot_rate: 20.0,
+            player_name: "Player".into(),
+            spectate: false,
+        }
+    }
+}
+
+/// The options a config file sets. The rest keep their defaults.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ConfigFile {
+    role: Option<LaunchRole>,
+    bind_addr: Option<IpAddr>,
+    port: Option<u16>,
+    server_addr: Option<SocketAddr>,
+    room: Option<String>,
+    transport: Option<TransportKind>,
+    relay_addr: Option<SocketAddr>,
+    input_rate: Option<f32>,
+    snapshot_rate: Option<f32>,
+    player_name: Option<String>,
+    spectate: Option<bool>,
+}
+
+impl ConfigFile {
+    fn apply(self, config: &mut Config) {
+        fn set<T>(option: &mut T, value: Option<T>) {
+            if let Some(value) = value {
+                *option = value;
+            }
+        }
+
+        set(&mut config.role, self.role.map(Some));
+        set(&mut config.bind_addr, self.bind_addr.map(Some));
+        set(&mut config.port, self.port);
+        set(&mut config.server_addr, self.server_addr);
+        set(&mut config.room, self.room.map(Some));
+        set(&mut config.transport, self.transport);
+        set(&mut config.relay_addr, self.relay_addr);
+        set(&mut config.input_rate, self.input_rate);
+        set(&mut config.snapshot_rate, self.snapshot_rate);
+        set(&mut config.player_name, self.player_name);
+        set(&mut config.spectate, self.spectate);
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(std::io::Error),
+    Ron(ron::Error),
+    MissingValue(String),
+    InvalidValue { flag: String, value: String },
+    UnknownFlag(String),
+    Help,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(err) => write!(f, "couldn't read config file: {}", err),
+            ConfigError::Ron(err) => write!(f, "invalid config file: {}", err),
+            ConfigError::MissingValue(flag) => write!(f, "{} needs a value", flag),
+            ConfigError::InvalidValue { flag, value } => {
+                write!(f, "invalid value for {}: {}", flag, value)
+            }
+            ConfigError::UnknownFlag(flag) => write!(f, "unknown flag {}", flag),
+            ConfigError::Help => write!(f, "{}", USAGE),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Reads the process arguments over `defaults`, or prints the problem
+    /// and exits.
+    pub fn from_args_or_exit(defaults: Config) -> Config {
+        match Config::parse(defaults, std::env::args().skip(1)) {
+            Ok(config) => config,
+            Err(ConfigError::Help) => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            Err(err) => {
+                eprintln!("{}\n\n{}", err, USAGE);
+                std::process::exit(2);
+            }
+        }
+    }
+
+    pub fn parse(
+        defaults: Config,
+        args: impl IntoIterator<Item = String>,
+    ) -> Result<Config, ConfigError> {
+        let args = args.into_iter().collect::<Vec<_>>();
+
+        // The file goes under the flags, wherever it is given.
+        let mut config = match args.iter().position(|arg| arg == "--config") {
+            Some(i) => {
+                let path = args
+                    .get(i + 1)
+                    .ok_or_else(|| ConfigError::MissingValue("--config".into()))?;
+                defaults.load(path)?
+            }
+            None => defaults,
+        };
+
+        let mut args = args.into_iter().peekable();
+        while let Some(flag) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| ConfigError::MissingValue(flag.clone()))
+            };
+
+            match flag.as_str() {
+                "--config" => {
+                    value()?;
+                }
+                "--host" => config.role = Some(LaunchRole::Host),
+                "--dedicated" => config.role = Some(LaunchRole::Dedicated),
+                "--join" => {
+                    config.role = Some(LaunchRole::Join);
+                    if let Some(target) = args.next_if(|arg| !arg.starts_with("--")) {
+                        if target.parse::<RoomCode>().is_ok() {
+                            config.transport = TransportKind::Relay;
+                            config.room = Some(target);
+                        } else {
+                            config.server_addr = parse_value("--join", &target)?;
+                        }
+                    }
+                }
+                "--bind" => config.bind_addr = Some(parse_value(&flag, &value()?)?),
+                "--port" => config.port = parse_value(&flag, &value()?)?,
+                "--server" => config.server_addr = parse_value(&flag, &value()?)?,
+                "--transport" => {
+                    let name = value()?;
+                    config.transport = match name.as_str() {
+                        "laminar" => TransportKind::Laminar,
+                        "relay" => TransportKind::Relay,
+                        _ => return Err(invalid(&flag, &name)),
+                    };
+                }
+                "--relay" => config.relay_addr = parse_value(&flag, &value()?)?,
+                "--input-rate" => config.input_rate = parse_rate(&flag, &value()?)?,
+                "--snapshot-rate" => config.snapshot_rate = parse_rate(&flag, &value()?)?,
+                "--name" => config.player_name = value()?,
+                "--spectate" => config.spectate = true,
+                "--help" | "-h" => return Err(ConfigError::Help),
+                _ => return Err(ConfigError::UnknownFlag(flag)),
+            }
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Reads a config file over these options.
+    pub fn load(mut self, path: impl AsRef<Path>) -> Result<Config, ConfigError> {
+        let text = std::fs::read_to_string(path).map_err(ConfigError::Io)?;
+        let file: ConfigFile = ron::Options::default()
+            .with_default_extension(Extensions::IMPLICIT_SOME)
+            .from_str(&text)
+            .map_err(ConfigError::Ron)?;
+        file.apply(&mut self);
+        Ok(self)
+    }
+
+    /// Catches what the flags' own checks can't see, like values from a file.
+    fn validate(&self) -> Result<(), ConfigError> {
+        check_rate("input_rate", self.input_rate)?;
+        check_rate("snapshot_rate", self.snapshot_rate)?;
+        Ok(())
+    }
+
+    /// The address to host on. Unless given, a dedicated server listens on
+    /// every interface and a listen server only on localhost.
+    pub fn bind(&self) -> SocketAddr {
+        let ip = self.bind_addr.unwrap_or(match self.role {
+            Some(LaunchRole::Dedicated) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            _ => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        });
+        SocketAddr::new(ip, self.port)
+    }
+
+    /// The address a client in the hosting process connects to.
+    pub fn local_server(&self) -> SocketAddr {
+        let bind = self.bind();
+        if bind.ip().is_unspecified() {
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), self.port)
+        } else {
+            bind
+        }
+    }
+
+    pub fn transport(&self) -> Transport {
+        match self.transport {
+            TransportKind::Laminar => Transport::Laminar,
+            TransportKind::Relay => Transport::Relay(self.relay_addr),
+        }
+    }
+
+    pub fn input_interval(&self) -> f32 {
+        1.0 / self.input_rate
+    }
+
+    /// Ticks between snapshots. Snapshots go out on tick boundaries, so the
+    /// rate is rounded to fit.
+    pub fn snapshot_interval(&self) -> u32 {
+        ((TICK_RATE as f32 / self.snapshot_rate).round() as u32).max(1)
+    }
+
+    /// Seconds between snapshots.
+    pub fn snapshot_seconds(&self) -> f32 {
+        self.snapshot_interval() as f32 * TICK_SECONDS
+    }
+}
+
+fn parse_value<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, ConfigError> {
+    value.parse().map_err(|_| invalid(flag, value))
+}
+
+fn parse_rate(flag: &str, value: &str) -> Result<f32, ConfigError> {
+    let rate = parse_value(flag, value)?;
+    check_rate(flag, rate)?;
+    Ok(rate)
+}
+
+fn check_rate(name: &str, rate: f32) -> Result<(), ConfigError> {
+    if rate > 0.0 && rate.is_finite() {
+        Ok(())
+    } else {
+        Err(invalid(name, &rate.to_string()))
+    }
+}
+
+fn invalid(flag: &str, value: &str) -> ConfigError {
+    ConfigError::InvalidValue {
+        flag: flag.into(),
+        value: value.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Config, ConfigError> {
+        Config::parse(Config::default(), args.iter().map(|arg| arg.to_string()))
+    }
+
+    /// Writes a config file that's removed again when dropped.
+    struct TempConfig(std::path::PathBuf);
+
+    impl TempConfig {
+        fn new(name: &str, text: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("{}-{}.ron", name, std::process::id()));
+            std::fs::write(&path, text).unwrap();
+            Self(path)
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for TempConfig {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn flags_override_defaults() {
+        let config = parse(&["--host", "--port", "4000", "--name", "Ferris"]).unwrap();
+        assert_eq!(config.role, Some(LaunchRole::Host));
+        assert_eq!(config.port, 4000);
+        assert_eq!(config.player_name, "Ferris");
+        assert_eq!(config.input_rate, Config::default().input_rate);
+    }
+
+    #[test]
+    fn flags_override_file_wherever_it_is_given() {
+        let file = TempConfig::new("flags_override_file", "(port: 5000, player_name: \"File\")");
+        let config = parse(&["--port", "4000", "--config", file.path()]).unwrap();
+        assert_eq!(config.port, 4000);
+        assert_eq!(config.player_name, "File");
+    }
+
+    #[test]
+    fn file_goes_over_passed_in_defaults() {
+        let file = TempConfig::new("file_over_defaults", "(port: 5000, role: Dedicated)");
+        let defaults = Config {
+            bind_addr: Some(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            ..Default::default()
+        };
+        let args = ["--config", file.path()].map(String::from);
+        let config = Config::parse(defaults, args).unwrap();
+        assert_eq!(config.bind_addr, Some(IpAddr::V4(Ipv4Addr::UNSPECIFIED)));
+        assert_eq!(config.port, 5000);
+        assert_eq!(config.role, Some(LaunchRole::Dedicated));
+    }
+
+    #[test]
+    fn bind_defaults_to_the_role() {
+        let host = parse(&["--host", "--port", "4000"]).unwrap();
+        assert_eq!(host.bind(), "127.0.0.1:4000".parse().unwrap());
+
+        let dedicated = parse(&["--dedicated", "--port", "4000"]).unwrap();
+        assert_eq!(dedicated.bind(), "0.0.0.0:4000".parse().unwrap());
+        assert_eq!(dedicated.local_server(), "127.0.0.1:4000".parse().unwrap());
+
+        let bound = parse(&["--dedicated", "--bind", "10.0.0.2"]).unwrap();
+        assert_eq!(bound.bind().ip(), "10.0.0.2".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn rejects_bad_rates() {
+        assert!(parse(&["--input-rate", "0"]).is_err());
+        assert!(parse(&["--snapshot-rate", "-1"]).is_err());
+        assert!(parse(&["--snapshot-rate", "inf"]).is_err());
+
+        let file = TempConfig::new("rejects_bad_rates", "(snapshot_rate: 0.0)");
+        assert!(matches!(
+            parse(&["--config", file.path()]),
+            Err(ConfigError::InvalidValue { .. })
+        ));
+    }
+
+    #[test]
+    fn join_room_code() {
+        let config = parse(&["--join", "1234"]).unwrap();
+        assert_eq!(config.role, Some(LaunchRole::Join));
+        assert_eq!(config.transport, TransportKind::Relay);
+        assert_eq!(config.room.as_deref(), Some("1234"));
+    }
+
+    #[test]
+    fn join_address() {
+        let config = parse(&["--join", "10.0.0.2:4000"]).unwrap();
+        assert_eq!(config.role, Some(LaunchRole::Join));
+        assert_eq!(config.transport, TransportKind::Laminar);
+        assert_eq!(config.room, None);
+        assert_eq!(config.server_addr, "10.0.0.2:4000".parse().unwrap());
+    }
+
+    #[test]
+    fn join_without_target() {
+        let config = parse(&["--join", "--name", "Ferris"]).unwrap();
+        assert_eq!(config.role, Some(LaunchRole::Join));
+        assert_eq!(config.server_addr, Config::default().server_addr);
+        assert_eq!(config.player_name, "Ferris");
+
+        assert!(parse(&["--join", "not-an-address"]).is_err());
+    }
+}
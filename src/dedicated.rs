@@ -0,0 +1,53 @@
+use std::time::Duration;
+
+use bevy::{app::ScheduleRunnerSettings, prelude::*};
+
+use crate::{
+    config::{Config, LaunchRole},
+    network::*,
+    AppState, SimulationPlugins,
+};
+
+/// Runs a headless server with no local player, until the process is killed.
+pub fn run(mut config: Config) {
+    config.role = Some(LaunchRole::Dedicated);
+    App::new()
+        .insert_resource(ScheduleRunnerSettings::run_loop(Duration::from_secs_f64(
+            1.0 / TICK_RATE,
+        )))
+        .insert_resource(config)
+        .add_state(AppState::Game)
+        .add_state(NetworkRole::DedicatedServer)
+        .add_plugins(MinimalPlugins)
+        .add_plugins(SimulationPlugins)
+        .add_startup_system(start_server)
+        .add_system(announce_room_code)
+        .run();
+}
+
+fn start_server(config: Res<Config>, messages: Res<NetworkMessages>, mut commands: Commands) {
+    let transport = config.transport();
+    let server = match transport {
+        Transport::Laminar => {
+            println!("[S] Listening on {}", config.bind());
+            Server::new(transport, Some(config.bind()), &messages)
+        }
+        Transport::Relay(relay_addr) => {
+            println!("[S] Hosting through the relay at {}", relay_addr);
+            Server::new(transport, None, &messages)
+        }
+    };
+    commands.insert_resource(server);
+}
+
+/// Relay rooms are handed out after the server starts, and clients can't join
+/// without the code.
+fn announce_room_code(mut announced: Local<bool>, server: Option<Res<Server>>) {
+    if *announced {
+        return;
+    }
+    if let Some(room) = server.and_then(|server| server.room_code()) {
+        println!("[S] Room code: {}", room);
+        *announced = true;
+    }
+}
@@ -1,12 +1,18 @@
 use bevy::prelude::*;
 
 use physics::prelude::*;
+use serde::{Deserialize, Serialize};
 use transport::DeliveryMethod;
 
 use crate::{
+    chat::ChatInput,
     cleanup::Cleanup,
+    config::Config,
+    migration::HostMigration,
     network::*,
-    run_criteria::game_server_run_criteria,
+    relevancy::RelevantEntities,
+    roster::{PlayerRoster, RosterSystem},
+    run_criteria::{game_client_run_criteria, game_server_run_criteria, RoleSystem},
     spawn::{Despawn, Spawn, SpawnName},
     AppState,
 };
@@ -15,52 +21,90 @@ pub struct GamePlugin;
 
 impl Plugin for GamePlugin {
     fn build(&self, app: &mut App) {
-        app.add_system_set(
-            SystemSet::on_enter(AppState::Game)
-                .with_system(on_enter_game)
-                .with_system(setup_light)
-                .with_system(setup_level),
-        )
-        .add_system_set(
-            SystemSet::on_update(AppState::Game)
-                .with_system(on_disconnect_event)
-                .with_system(on_client_connection_event)
-                .with_system(on_client_state_event)
-                .with_system(on_client_spawn_obstacle),
-        )
-        .add_system_set(
-            SystemSet::new()
-                .with_run_criteria(game_server_run_criteria)
-                .with_system(on_server_connection_event)
-                .with_system(on_server_ready_event)
-                .with_system(on_server_spawn_obstacle),
-        );
+        app.init_resource::<Config>()
+            .add_client_message::<Ready>(DeliveryMethod::ReliableOrdered)
+            .add_server_rpc::<SpawnObstacle>()
+            .add_system_set(
+                SystemSet::on_enter(AppState::Game)
+                    .with_system(on_enter_game)
+                    .with_system(setup_light)
+                    .with_system(setup_level),
+            )
+            .add_system_set(
+                SystemSet::new()
+                    .with_run_criteria(game_client_run_criteria)
+                    .label(RoleSystem::Client)
+                    .with_system(on_disconnect_event)
+                    .with_system(request_obstacle),
+            )
+            .add_system_set(
+                SystemSet::new()
+                    .with_run_criteria(game_server_run_criteria)
+                    .label(RoleSystem::Server)
+                    .with_system(on_server_connection_event)
+                    .with_system(on_server_ready_event.after(RosterSystem::Receive))
+                    .with_system(on_server_spawn_obstacle),
+            );
     }
 }
 
-fn on_enter_game(mut client: ResMut<Client>) {
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Ready;
+
+/// Asks the server for an obstacle at a random position.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SpawnObstacle;
+
+impl Rpc for SpawnObstacle {
+    type Response = ();
+}
+
+fn on_enter_game(client: Option<ResMut<Client>>, server: Option<Res<Server>>, config: Res<Config>) {
     println!("\n---------- Game ----------");
-    println!("Press 'Q' to quit.");
-    if client.is_host() {
-        println!("Press 'S' to spawn obstacle.");
+    if let Some(room) = server.as_ref().and_then(|server| server.room_code()) {
+        println!("Room code: {}", room);
     }
-    println!();
 
-    client.send(ClientPacket::Ready, DeliveryMethod::ReliableOrdered);
+    if let Some(mut client) = client {
+        println!("Press 'Q' to quit.");
+        // Only the host gets to change the level.
+        if server.is_some() {
+            println!("Press 'S' to spawn obstacle.");
+        }
+        println!("Press 'T' to chat.");
+        if config.spectate {
+            println!("Spectating. Press 'Tab' to watch the next player.");
+        }
+        client.send_message(&Ready);
+    }
+    println!();
 }
 
 fn on_disconnect_event(
     keyboard: Res<Input<KeyCode>>,
+    chat: Res<ChatInput>,
     mut client_evr: EventReader<ClientEvent>,
     mut app_state: ResMut<State<AppState>>,
+    migration: Res<HostMigration>,
+    role: Res<State<NetworkRole>>,
+    client: Res<Client>,
 ) {
-    if keyboard.just_pressed(KeyCode::Q) {
+    if keyboard.just_pressed(KeyCode::Q) && !chat.is_typing() {
         app_state.set(AppState::Menu).unwrap();
     } else {
         for event in client_evr.iter() {
             match event {
                 ClientEvent::Disconnected => {
-                    app_state.set(AppState::Menu).unwrap();
+                    // Lost the host, carry on with the successor if possible.
+                    let next = if *role.current() == NetworkRole::Client
+                        && migration.can_migrate(client.get_id())
+                    {
+                        AppState::Migrating
+                    } else {
+                        AppState::Menu
+                    };
+                    app_state.set(next).unwrap();
+                    return;
                 }
                 _ => {}
             }
@@ -68,135 +112,110 @@ fn on_disconnect_event(
     }
 }
 
-fn on_client_state_event(mut client_evr: EventReader<ClientEvent>, mut commands: Commands) {
-    for event in client_evr.iter() {
+fn on_server_connection_event(
+    mut server_evr: EventReader<ServerEvent>,
+    mut server: ResMut<Server>,
+    mut commands: Commands,
+    entity_map: Res<NetworkEntityMap>,
+) {
+    for event in server_evr.iter() {
         match event {
-            ClientEvent::State(spawns) => {
-                for spawn in spawns.iter() {
-                    commands.spawn().insert(*spawn);
+            ServerEvent::PlayerConnected(id) => {
+                server.send_message_to_all_except(*id, &PlayerConnected(*id));
+            }
+            ServerEvent::PlayerDisconnected(id) => {
+                server.send_message_to_all_except(*id, &PlayerDisconnected(*id));
+                if let Some(entity) = entity_map.entity(*id) {
+                    commands.entity(entity).insert(Despawn);
                 }
             }
-            _ => {}
         }
     }
 }
 
-fn on_client_connection_event(
-    mut client_evr: EventReader<ClientEvent>,
+/// Spawns the player once its client is in the game, unless it's spectating.
+/// Remote clients then get it, and everything else relevant to them, from
+/// `relevancy`.
+fn on_server_ready_event(
+    mut ready_evr: EventReader<FromClient<Ready>>,
+    mut relevant: ResMut<RelevantEntities>,
+    mut roster: ResMut<PlayerRoster>,
     mut commands: Commands,
-    network_id_q: Query<(Entity, &NetworkId)>,
+    client: Option<Res<Client>>,
+    entity_map: Res<NetworkEntityMap>,
+    spawn_q: Query<&Spawn>,
 ) {
-    for event in client_evr.iter() {
-        match event {
-            ClientEvent::PlayerConnected(id) => {
-                commands.spawn().insert(Spawn {
-                    id: *id,
-                    name: SpawnName::Player,
-                    position: Vec3::ZERO,
-                });
-            }
-            ClientEvent::PlayerDisconnected(id) => {
-                for (entity, net_id) in network_id_q.iter() {
-                    if net_id.value() == *id {
-                        commands.entity(entity).insert(Despawn);
-                        return;
-                    }
-                }
-            }
-            _ => {}
+    for FromClient { id, .. } in ready_evr.iter() {
+        roster.modify(*id, |entry| entry.ready = true);
+
+        // Players following a host migration already have one.
+        let spawned =
+            entity_map.entity(*id).is_some() || spawn_q.iter().any(|spawn| spawn.id == *id);
+        let spectator = roster.get(*id).map_or(false, |entry| entry.spectator);
+        if !spawned && !spectator {
+            commands.spawn().insert(Spawn {
+                id: *id,
+                name: SpawnName::Player,
+                position: Vec3::ZERO,
+                rotation: Quat::IDENTITY,
+            });
+        }
+
+        // The local client shares the server's world.
+        if client.as_ref().map(|client| client.get_id()) != Some(*id) {
+            relevant.track(*id);
         }
     }
 }
 
-fn on_server_connection_event(
-    mut server_evr: EventReader<ServerEvent>,
-    mut server: ResMut<Server>,
+fn request_obstacle(
+    keyboard: Res<Input<KeyCode>>,
+    chat: Res<ChatInput>,
+    role: Res<State<NetworkRole>>,
+    mut rpcs: Rpcs,
 ) {
-    for event in server_evr.iter() {
-        match event {
-            ServerEvent::PlayerConnected(id) => {
-                server.send_to_all_except(
-                    *id,
-                    ServerPacket::PlayerConnected(*id),
-                    DeliveryMethod::ReliableOrdered,
-                );
-            }
-            ServerEvent::PlayerDisconnected(id) => {
-                server.send_to_all_except(
-                    *id,
-                    ServerPacket::PlayerDisconnected(*id),
-                    DeliveryMethod::ReliableOrdered,
-                );
-            }
-            _ => {}
-        }
+    if keyboard.just_pressed(KeyCode::S) && !chat.is_typing() && role.current().is_server() {
+        rpcs.call_server(SpawnObstacle);
     }
 }
 
-fn on_server_ready_event(
-    mut server_evr: EventReader<ServerEvent>,
+/// Spawns an obstacle for the host. Calls from remote clients are ignored.
+fn on_server_spawn_obstacle(
+    mut call_evr: EventReader<RpcCall<SpawnObstacle>>,
     mut server: ResMut<Server>,
-    actor_q: Query<(&NetworkId, &SpawnName, &Transform)>,
+    mut commands: Commands,
+    client: Option<Res<Client>>,
 ) {
-    for event in server_evr.iter() {
-        match event {
-            ServerEvent::PlayerReady(id) => {
-                let mut actors = Vec::new();
-                actors.push(Spawn {
-                    id: *id,
-                    name: SpawnName::Player,
-                    position: Vec3::ZERO,
-                });
-                for (net_id, name, transform) in actor_q.iter() {
-                    if net_id.value() == *id {
-                        continue;
-                    }
-                    actors.push(Spawn {
-                        id: net_id.value(),
-                        name: *name,
-                        position: transform.translation,
-                    })
-                }
-                server.send(
-                    *id,
-                    ServerPacket::State(actors.into_boxed_slice()),
-                    DeliveryMethod::ReliableOrdered,
-                );
-            }
-            _ => {}
+    let host = client.map(|client| client.get_id());
+    for call in call_evr.iter() {
+        if call.from.is_some() && call.from != host {
+            println!("[S] {:?} may not spawn obstacles", call.from);
+            continue;
         }
-    }
-}
 
-fn on_server_spawn_obstacle(keyboard: Res<Input<KeyCode>>, mut server: ResMut<Server>) {
-    if keyboard.just_pressed(KeyCode::S) {
-        let id = server.generate_id();
+        let id = match server.generate_id() {
+            Ok(id) => id,
+            Err(err) => {
+                println!("[S] Can't spawn obstacle: {}", err);
+                continue;
+            }
+        };
         let x = fastrand::i32(-10..=10);
         let z = fastrand::i32(-10..=10);
-        let pos = Vec3::new(x as f32, 0.0, z as f32);
-        server.send_to_all(
-            ServerPacket::SpawnObstacle(id, pos),
-            DeliveryMethod::ReliableOrdered,
-        );
+        commands.spawn().insert(Spawn {
+            id,
+            name: SpawnName::Obstacle,
+            position: Vec3::new(x as f32, 0.0, z as f32),
+            rotation: Quat::IDENTITY,
+        });
     }
 }
 
-fn on_client_spawn_obstacle(mut client_evr: EventReader<ClientEvent>, mut commands: Commands) {
-    for event in client_evr.iter() {
-        match event {
-            ClientEvent::SpawnObstacle(id, pos) => {
-                commands.spawn().insert(Spawn {
-                    id: *id,
-                    name: SpawnName::Obstacle,
-                    position: *pos,
-                });
-            }
-            _ => {}
-        }
+fn setup_light(role: Res<State<NetworkRole>>, mut commands: Commands) {
+    if !role.current().has_local_player() {
+        return;
     }
-}
 
-fn setup_light(mut commands: Commands) {
     commands
         .spawn_bundle(DirectionalLightBundle {
             directional_light: DirectionalLight {
@@ -212,26 +231,30 @@ fn setup_light(mut commands: Commands) {
 
 fn setup_level(
     mut commands: Commands,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<StandardMaterial>>,
-    client: Res<Client>,
+    meshes: Option<ResMut<Assets<Mesh>>>,
+    materials: Option<ResMut<Assets<StandardMaterial>>>,
+    role: Res<State<NetworkRole>>,
 ) {
     let size = Vec3::new(20.0, 1.0, 20.0);
     let ground = commands
-        .spawn_bundle(PbrBundle {
-            mesh: meshes.add(Mesh::from(bevy::prelude::shape::Cube::default())),
-            material: materials.add(Color::DARK_GRAY.into()),
-            transform: Transform {
-                translation: -Vec3::Y * 0.5,
-                scale: size,
-                ..Default::default()
-            },
-            ..Default::default()
-        })
+        .spawn()
+        .insert(Transform::from_translation(-Vec3::Y * 0.5))
+        .insert(GlobalTransform::identity())
         .insert(Cleanup)
         .id();
 
-    if client.is_host() {
+    if let (Some(mut meshes), Some(mut materials)) = (meshes, materials) {
+        commands.entity(ground).with_children(|child| {
+            child.spawn_bundle(PbrBundle {
+                mesh: meshes.add(Mesh::from(bevy::prelude::shape::Cube::default())),
+                material: materials.add(Color::DARK_GRAY.into()),
+                transform: Transform::from_scale(size),
+                ..Default::default()
+            });
+        });
+    }
+
+    if role.current().is_server() {
         commands.entity(ground).insert_bundle(ColliderBundle {
             shape: ColliderShape::cuboid(size.x * 0.5, size.y * 0.5, size.z * 0.5).into(),
             position: (-Vec3::Y * size.y * 0.5).into(),
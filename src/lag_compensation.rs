@@ -0,0 +1,263 @@
+use std::{collections::VecDeque, marker::PhantomData};
+
+use bevy::{ecs::system::SystemParam, prelude::*, utils::HashMap};
+use physics::prelude::{
+    parry::query::{self, RayCast},
+    *,
+};
+
+use crate::{
+    config::Config,
+    network::*,
+    run_criteria::{game_server_run_criteria, RoleSystem},
+    snapshot::interpolation_delay,
+    tick::{NetworkTick, TickAppExt, TickStage},
+    AppState,
+};
+
+pub struct LagCompensationPlugin;
+
+impl Plugin for LagCompensationPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<LagCompensationSettings>()
+            .init_resource::<ColliderHistory>()
+            .add_system_set(SystemSet::on_enter(AppState::Game).with_system(clear_history))
+            .add_tick_system_set(
+                TickStage::Send,
+                SystemSet::new()
+                    .with_run_criteria(game_server_run_criteria)
+                    .label(RoleSystem::Server)
+                    .with_system(record_history),
+            );
+    }
+}
+
+pub struct LagCompensationSettings {
+    /// Ticks of history kept.
+    pub history: usize,
+    /// The furthest back a client can be rewound to, in seconds. Clients
+    /// claiming more latency than this get this much.
+    pub max_rewind: f64,
+}
+
+impl Default for LagCompensationSettings {
+    fn default() -> Self {
+        Self {
+            history: TICK_RATE as usize,
+            max_rewind: 0.5,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct HistoricalCollider {
+    entity: Entity,
+    position: Isometry<Real>,
+    shape: ColliderShape,
+}
+
+/// Where every networked collider was at the end of each recent tick.
+#[derive(Default)]
+pub struct ColliderHistory {
+    ticks: VecDeque<(u32, HashMap<NetId, HistoricalCollider>)>,
+}
+
+impl ColliderHistory {
+    fn get(&self, tick: u32) -> Option<&HashMap<NetId, HistoricalCollider>> {
+        let (oldest, _) = self.ticks.front()?;
+        let index = tick.wrapping_sub(*oldest) as usize;
+        self.ticks
+            .get(index)
+            .filter(|(recorded, _)| *recorded == tick)
+            .map(|(_, colliders)| colliders)
+    }
+
+    /// The colliders as they were at `tick`, blending between the recorded
+    /// ticks either side of it. Ticks older than the history are clamped to
+    /// the oldest one kept.
+    fn at(&self, tick: f64) -> Option<RewoundWorld> {
+        let (oldest, _) = self.ticks.front()?;
+        let (newest, _) = self.ticks.back()?;
+        let tick = tick.clamp(*oldest as f64, *newest as f64);
+
+        let from_tick = tick.floor() as u32;
+        let from = self.get(from_tick)?;
+        let to = self.get(from_tick.wrapping_add(1)).unwrap_or(from);
+        let t = tick.fract() as Real;
+
+        let colliders = from
+            .iter()
+            .map(|(id, from)| {
+                let position = match to.get(id) {
+                    Some(to) => from.position.lerp_slerp(&to.position, t),
+                    None => from.position,
+                };
+                let collider = HistoricalCollider {
+                    position,
+                    ..from.clone()
+                };
+                (*id, collider)
+            })
+            .collect();
+
+        Some(RewoundWorld { tick, colliders })
+    }
+}
+
+/// Networked colliders as a client saw them, for hit detection against what
+/// the client was actually aiming at.
+pub struct RewoundWorld {
+    tick: f64,
+    colliders: HashMap<NetId, HistoricalCollider>,
+}
+
+/// The closest collider hit by a ray.
+#[derive(Debug, Clone, Copy)]
+pub struct RewoundHit {
+    pub id: NetId,
+    pub entity: Entity,
+    /// The distance along the ray, in multiples of its direction.
+    pub toi: f32,
+}
+
+impl RewoundWorld {
+    /// The tick this world is from. Usually between two simulated ticks.
+    pub fn tick(&self) -> f64 {
+        self.tick
+    }
+
+    pub fn position(&self, id: NetId) -> Option<(Vec3, Quat)> {
+        self.colliders.get(&id).map(|collider| {
+            (
+                collider.position.translation.vector.into(),
+                collider.position.rotation.into(),
+            )
+        })
+    }
+
+    pub fn cast_ray(
+        &self,
+        origin: Vec3,
+        direction: Vec3,
+        max_toi: f32,
+        mut filter: impl FnMut(NetId) -> bool,
+    ) -> Option<RewoundHit> {
+        let ray = Ray::new(Vector::from(origin).into(), direction.into());
+        self.colliders
+            .iter()
+            .filter(|(id, _)| filter(**id))
+            .filter_map(|(id, collider)| {
+                let toi = collider
+                    .shape
+                    .cast_ray(&collider.position, &ray, max_toi, true)?;
+                Some(RewoundHit {
+                    id: *id,
+                    entity: collider.entity,
+                    toi,
+                })
+            })
+            .min_by(|a, b| a.toi.total_cmp(&b.toi))
+    }
+
+    /// Every collider overlapping `shape` placed at `position` and `rotation`.
+    pub fn intersect_shape(
+        &self,
+        position: Vec3,
+        rotation: Quat,
+        shape: &ColliderShape,
+        mut filter: impl FnMut(NetId) -> bool,
+    ) -> Vec<(NetId, Entity)> {
+        let shape_position = Isometry::from_parts(Vector::from(position).into(), rotation.into());
+        self.colliders
+            .iter()
+            .filter(|(id, _)| filter(**id))
+            .filter(|(_, collider)| {
+                query::intersection_test(
+                    &shape_position,
+                    &**shape,
+                    &collider.position,
+                    &*collider.shape,
+                )
+                .unwrap_or(false)
+            })
+            .map(|(id, collider)| (*id, collider.entity))
+            .collect()
+    }
+}
+
+/// Rewinds the networked colliders to what a client was seeing.
+#[derive(SystemParam)]
+pub struct LagCompensation<'w, 's> {
+    history: Res<'w, ColliderHistory>,
+    settings: Res<'w, LagCompensationSettings>,
+    config: Res<'w, Config>,
+    tick: Res<'w, NetworkTick>,
+    round_trips: Res<'w, ClientRoundTrips>,
+    client: Option<Res<'w, Client>>,
+    #[system_param(ignore)]
+    marker: PhantomData<&'s ()>,
+}
+
+impl<'w, 's> LagCompensation<'w, 's> {
+    /// How long ago the state `client_id` is acting on was simulated, in
+    /// seconds. By the time the client's input arrives, the state it saw
+    /// took half a round trip to reach it and was played back after the
+    /// interpolation delay, and the input took the other half to get here.
+    pub fn latency(&self, client_id: NetId) -> f64 {
+        let is_local = self
+            .client
+            .as_ref()
+            .map_or(false, |client| client.get_id() == client_id);
+        if is_local {
+            return 0.0;
+        }
+
+        let rtt = self.round_trips.get(client_id).unwrap_or_default();
+        (rtt + interpolation_delay(&self.config) as f64).min(self.settings.max_rewind)
+    }
+
+    /// The networked colliders as `client_id` saw them when it sent the input
+    /// being handled this tick.
+    pub fn rewind(&self, client_id: NetId) -> Option<RewoundWorld> {
+        self.rewind_by(self.latency(client_id))
+    }
+
+    /// The networked colliders as they were `seconds` ago.
+    pub fn rewind_by(&self, seconds: f64) -> Option<RewoundWorld> {
+        self.history.at(self.tick.0 as f64 - seconds * TICK_RATE)
+    }
+}
+
+fn record_history(
+    tick: Res<NetworkTick>,
+    settings: Res<LagCompensationSettings>,
+    mut history: ResMut<ColliderHistory>,
+    collider_q: Query<(
+        Entity,
+        &NetworkId,
+        &ColliderPositionComponent,
+        &ColliderShapeComponent,
+    )>,
+) {
+    let colliders = collider_q
+        .iter()
+        .map(|(entity, net_id, position, shape)| {
+            let position: &Isometry<Real> = position;
+            let collider = HistoricalCollider {
+                entity,
+                position: *position,
+                shape: ColliderShape::clone(shape),
+            };
+            (net_id.value(), collider)
+        })
+        .collect();
+
+    history.ticks.push_back((tick.0, colliders));
+    while history.ticks.len() > settings.history {
+        history.ticks.pop_front();
+    }
+}
+
+fn clear_history(mut history: ResMut<ColliderHistory>) {
+    history.ticks.clear();
+}
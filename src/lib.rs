@@ -0,0 +1,57 @@
+use bevy::{app::PluginGroupBuilder, prelude::*};
+
+pub mod camera;
+pub mod chat;
+pub mod cleanup;
+pub mod config;
+pub mod dedicated;
+pub mod game;
+pub mod lag_compensation;
+pub mod menu;
+pub mod migration;
+pub mod network;
+pub mod obstacle;
+pub mod player;
+pub mod prediction;
+pub mod relevancy;
+pub mod roster;
+pub mod run_criteria;
+pub mod snapshot;
+pub mod spawn;
+pub mod spectator;
+pub mod tick;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum AppState {
+    Menu,
+    Game,
+    /// Moving the game to a new host after the last one left.
+    Migrating,
+}
+
+/// Networking and gameplay, without anything that needs a window. Shared by
+/// the game and the dedicated server, so both register the same messages.
+pub struct SimulationPlugins;
+
+impl PluginGroup for SimulationPlugins {
+    fn build(&mut self, group: &mut PluginGroupBuilder) {
+        group
+            .add(transport::TransportPlugin)
+            .add(physics::PhysicsPlugin)
+            .add(network::NetworkPlugin)
+            .add(tick::TickPlugin)
+            .add(game::GamePlugin)
+            .add(roster::RosterPlugin)
+            .add(chat::ChatPlugin)
+            .add(migration::MigrationPlugin)
+            .add(spawn::SpawnPlugin)
+            .add(spectator::SpectatorPlugin)
+            .add(player::PlayerPlugin)
+            .add(prediction::PredictionPlugin)
+            .add(obstacle::ObstaclePlugin)
+            .add(cleanup::CleanupPlugin)
+            .add(snapshot::SnapshotPlugin)
+            .add(relevancy::RelevancyPlugin)
+            .add(lag_compensation::LagCompensationPlugin);
+    }
+}
@@ -1,23 +1,18 @@
 use bevy::prelude::*;
 
-mod camera;
-mod cleanup;
-mod game;
-mod menu;
-mod network;
-mod obstacle;
-mod player;
-mod run_criteria;
-mod snapshot;
-mod spawn;
-
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
-pub enum AppState {
-    Menu,
-    Game,
-}
+use bevy_snapshot_interpolation::{
+    camera,
+    config::{Config, LaunchRole},
+    dedicated, menu, AppState, SimulationPlugins,
+};
 
 fn main() {
+    let config = Config::from_args_or_exit(Config::default());
+    if config.role == Some(LaunchRole::Dedicated) {
+        dedicated::run(config);
+        return;
+    }
+
     App::new()
         .insert_resource(WindowDescriptor {
             title: "Bevy Snapshot Interpolation".into(),
@@ -27,19 +22,12 @@ fn main() {
             ..Default::default()
         })
         .insert_resource(Msaa { samples: 4 })
+        .insert_resource(config)
         .add_state(AppState::Menu)
         .add_plugins(DefaultPlugins)
-        .add_plugin(transport::TransportPlugin)
-        .add_plugin(physics::PhysicsPlugin)
-        .add_plugin(network::NetworkPlugin)
+        .add_plugins(SimulationPlugins)
         .add_plugin(menu::MenuPlugin)
-        .add_plugin(game::GamePlugin)
-        .add_plugin(spawn::SpawnPlugin)
-        .add_plugin(player::PlayerPlugin)
-        .add_plugin(obstacle::ObstaclePlugin)
         .add_plugin(camera::CameraPlugin)
-        .add_plugin(cleanup::CleanupPlugin)
-        .add_plugin(snapshot::SnapshotPlugin)
         .add_system(bevy::input::system::exit_on_esc_system)
         .run();
 }
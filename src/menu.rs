@@ -1,8 +1,11 @@
-use std::net::SocketAddr;
-
 use bevy::prelude::*;
+use transport::ConnectTarget;
 
-use crate::{network::*, AppState};
+use crate::{
+    config::{Config, LaunchRole},
+    network::*,
+    AppState,
+};
 
 pub struct MenuPlugin;
 
@@ -11,37 +14,179 @@ impl Plugin for MenuPlugin {
         app.add_system_set(SystemSet::on_enter(AppState::Menu).with_system(on_enter_menu))
             .add_system_set(
                 SystemSet::on_update(AppState::Menu)
+                    .with_system(launch_from_config)
                     .with_system(on_update_menu)
+                    .with_system(on_room_code_input)
+                    .with_system(on_relay_room_hosted)
                     .with_system(on_connecting),
             );
     }
 }
 
-fn on_enter_menu(mut commands: Commands) {
+/// Inserted while a relay host waits for the relay to hand out a room code.
+struct AwaitingRoomCode;
+
+fn on_enter_menu(mut role: ResMut<State<NetworkRole>>, mut commands: Commands) {
     println!("\n---------- Menu ----------");
-    println!("Press 'H' to host, or 'J' to join.\n");
-    remove_server_and_client(&mut commands);
+    println!("Press 'H' to host, or 'J' to join.");
+    println!("Press 'R' to host through the relay, or type a room code and press 'Enter' to join through it.");
+    println!("Press 'V' to switch between playing and spectating.\n");
+    remove_server_and_client(&mut role, &mut commands);
 }
 
-fn on_update_menu(keyboard_input: Res<Input<KeyCode>>, mut commands: Commands) {
-    let server_addr: SocketAddr = "127.0.0.1:12345".parse().unwrap();
+/// Hosts or joins straight away when asked to on the command line. Only the
+/// first time the menu is shown, so leaving a game lands back in the menu.
+fn launch_from_config(
+    mut launched: Local<bool>,
+    config: Res<Config>,
+    messages: Res<NetworkMessages>,
+    mut role: ResMut<State<NetworkRole>>,
+    mut commands: Commands,
+) {
+    if *launched {
+        return;
+    }
+    *launched = true;
 
+    match config.role {
+        Some(LaunchRole::Host) => host(
+            config.transport(),
+            &config,
+            &messages,
+            &mut role,
+            &mut commands,
+        ),
+        Some(LaunchRole::Join) => match (config.transport(), &config.room) {
+            (Transport::Relay(_), Some(room)) => match room.parse::<RoomCode>() {
+                Ok(room) => {
+                    println!("Joining room {}...", room);
+                    join(
+                        config.transport(),
+                        room,
+                        &messages,
+                        &mut role,
+                        &mut commands,
+                    );
+                }
+                Err(_) => println!("Invalid room code {}.", room),
+            },
+            (transport, _) => {
+                println!("Joining {}...", config.server_addr);
+                join(
+                    transport,
+                    config.server_addr,
+                    &messages,
+                    &mut role,
+                    &mut commands,
+                );
+            }
+        },
+        Some(LaunchRole::Dedicated) | None => {}
+    }
+}
+
+fn on_update_menu(
+    keyboard_input: Res<Input<KeyCode>>,
+    mut config: ResMut<Config>,
+    messages: Res<NetworkMessages>,
+    mut role: ResMut<State<NetworkRole>>,
+    mut commands: Commands,
+) {
     if keyboard_input.just_pressed(KeyCode::H) {
-        let server = Server::new(Transport::Laminar, Some(server_addr));
-        let mut client = Client::new(Transport::Laminar, None);
-        client.connect(server_addr);
-        commands.insert_resource(server);
-        commands.insert_resource(client);
+        host(
+            Transport::Laminar,
+            &config,
+            &messages,
+            &mut role,
+            &mut commands,
+        );
     } else if keyboard_input.just_pressed(KeyCode::J) {
-        let mut client = Client::new(Transport::Laminar, None);
-        client.connect(server_addr);
-        commands.insert_resource(client);
+        join(
+            Transport::Laminar,
+            config.server_addr,
+            &messages,
+            &mut role,
+            &mut commands,
+        );
+    } else if keyboard_input.just_pressed(KeyCode::R) {
+        host(
+            Transport::Relay(config.relay_addr),
+            &config,
+            &messages,
+            &mut role,
+            &mut commands,
+        );
+    } else if keyboard_input.just_pressed(KeyCode::V) {
+        config.spectate = !config.spectate;
+        if config.spectate {
+            println!("Joining as a spectator.");
+        } else {
+            println!("Joining as a player.");
+        }
+    }
+}
+
+fn on_room_code_input(
+    mut room_code: Local<String>,
+    mut char_evr: EventReader<ReceivedCharacter>,
+    keyboard_input: Res<Input<KeyCode>>,
+    config: Res<Config>,
+    messages: Res<NetworkMessages>,
+    mut role: ResMut<State<NetworkRole>>,
+    mut commands: Commands,
+) {
+    for event in char_evr.iter() {
+        if event.char.is_ascii_digit() && room_code.len() < RoomCode::LEN {
+            room_code.push(event.char);
+        }
+    }
+
+    if keyboard_input.just_pressed(KeyCode::Back) {
+        room_code.clear();
+    }
+
+    if keyboard_input.just_pressed(KeyCode::Return) {
+        match room_code.parse::<RoomCode>() {
+            Ok(room) => {
+                println!("Joining room {}...", room);
+                join(
+                    Transport::Relay(config.relay_addr),
+                    room,
+                    &messages,
+                    &mut role,
+                    &mut commands,
+                );
+            }
+            Err(_) => {
+                println!("Room codes are {} digits.", RoomCode::LEN);
+            }
+        }
+        room_code.clear();
+    }
+}
+
+fn on_relay_room_hosted(
+    awaiting: Option<Res<AwaitingRoomCode>>,
+    server: Option<Res<Server>>,
+    client: Option<ResMut<Client>>,
+    mut commands: Commands,
+) {
+    if awaiting.is_none() {
+        return;
+    }
+
+    if let (Some(server), Some(mut client)) = (server, client) {
+        if let Some(room) = server.room_code() {
+            client.connect(room);
+            commands.remove_resource::<AwaitingRoomCode>();
+        }
     }
 }
 
 fn on_connecting(
     mut client_evr: EventReader<ClientEvent>,
     mut app_state: ResMut<State<AppState>>,
+    mut role: ResMut<State<NetworkRole>>,
     mut commands: Commands,
 ) {
     for event in client_evr.iter() {
@@ -50,14 +195,55 @@ fn on_connecting(
                 app_state.set(AppState::Game).unwrap();
             }
             ClientEvent::Disconnected => {
-                remove_server_and_client(&mut commands);
+                remove_server_and_client(&mut role, &mut commands);
             }
             _ => {}
         }
     }
 }
 
-fn remove_server_and_client(commands: &mut Commands) {
+fn host(
+    transport: Transport,
+    config: &Config,
+    messages: &NetworkMessages,
+    role: &mut State<NetworkRole>,
+    commands: &mut Commands,
+) {
+    match transport {
+        Transport::Laminar => {
+            let server = Server::new(transport, Some(config.bind()), messages);
+            let mut client = Client::new(transport, None, messages);
+            client.connect(config.local_server());
+            commands.insert_resource(server);
+            commands.insert_resource(client);
+        }
+        Transport::Relay(_) => {
+            let server = Server::new(transport, None, messages);
+            let client = Client::new(transport, None, messages);
+            commands.insert_resource(server);
+            commands.insert_resource(client);
+            commands.insert_resource(AwaitingRoomCode);
+        }
+    }
+    set_network_role(role, NetworkRole::ListenServer);
+}
+
+fn join(
+    transport: Transport,
+    target: impl Into<ConnectTarget>,
+    messages: &NetworkMessages,
+    role: &mut State<NetworkRole>,
+    commands: &mut Commands,
+) {
+    let mut client = Client::new(transport, None, messages);
+    client.connect(target);
+    commands.insert_resource(client);
+    set_network_role(role, NetworkRole::Client);
+}
+
+fn remove_server_and_client(role: &mut State<NetworkRole>, commands: &mut Commands) {
     commands.remove_resource::<Server>();
     commands.remove_resource::<Client>();
+    commands.remove_resource::<AwaitingRoomCode>();
+    set_network_role(role, NetworkRole::Offline);
 }
@@ -0,0 +1,425 @@
+use std::{
+    cmp::Ordering,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+};
+
+use bevy::{prelude::*, utils::HashMap};
+use bitpack::NetSerialize;
+use serde::{Deserialize, Serialize};
+use transport::DeliveryMethod;
+
+use crate::{
+    chat::SystemMessage,
+    config::Config,
+    game::Ready,
+    network::*,
+    roster::PlayerRoster,
+    run_criteria::{game_client_exclusive_run_criteria, game_server_run_criteria, RoleSystem},
+    snapshot::ImportedComponents,
+    spawn::{Despawn, EntityState, EntityStates, SpawnName},
+    AppState,
+};
+
+pub struct MigrationPlugin;
+
+impl Plugin for MigrationPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<HostMigration>()
+            .add_server_message::<SuccessorChanged>(DeliveryMethod::ReliableOrdered)
+            .add_server_message::<MigrationToken>(DeliveryMethod::ReliableOrdered)
+            .add_packed_server_message::<MigrationState>(DeliveryMethod::ReliableOrdered)
+            .add_system_set(SystemSet::on_enter(AppState::Menu).with_system(forget_migration))
+            .add_system_set(SystemSet::on_enter(AppState::Migrating).with_system(start_migration))
+            .add_system_set(
+                SystemSet::on_update(AppState::Migrating).with_system(reconnect_to_new_host),
+            )
+            .add_system_set(
+                // Sees the players and entities the server added this frame.
+                SystemSet::new()
+                    .with_run_criteria(game_server_run_criteria)
+                    .after(RoleSystem::Server)
+                    .with_system(issue_tokens)
+                    .with_system(choose_successor)
+                    .with_system(send_migration_state)
+                    .with_system(expire_reserved_ids),
+            )
+            .add_system_set(
+                SystemSet::new()
+                    .with_run_criteria(game_client_exclusive_run_criteria)
+                    .label(RoleSystem::ClientExclusive)
+                    .with_system(receive_successor.after(NetworkSystem::Receive))
+                    .with_system(receive_token.after(NetworkSystem::Receive))
+                    .with_system(receive_migration_state.after(NetworkSystem::Receive)),
+            );
+    }
+}
+
+/// How often the host sends its state to the successor, in seconds.
+const STATE_INTERVAL: f64 = 1.0;
+
+/// How long the successor keeps ids for players who haven't reconnected, in
+/// seconds.
+const RECONNECT_GRACE: f64 = 15.0;
+
+/// How long a client tries to reach the new host before giving up, in
+/// seconds.
+const RECONNECT_TIMEOUT: f64 = 10.0;
+
+/// The new host may not be listening yet, so connecting is retried this
+/// often, in seconds.
+const RECONNECT_INTERVAL: f64 = 1.0;
+
+/// Who takes over hosting if the host leaves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Successor {
+    pub id: NetId,
+    /// Where everyone else finds the successor's server.
+    pub addr: SocketAddr,
+    /// The host's own player, which leaves with it.
+    pub host: NetId,
+}
+
+/// Sent to every client when the successor changes.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SuccessorChanged(pub Option<Successor>);
+
+/// Sent privately to each client, to reclaim its id on the successor with.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MigrationToken(pub ReconnectToken);
+
+/// The host's authoritative state, sent only to the successor.
+#[derive(Debug, Clone, NetSerialize)]
+pub struct MigrationState {
+    /// Where [`Server::generate_id`] was up to, so new entities don't reuse
+    /// ids.
+    pub next_id: NetId,
+    pub entities: Box<[EntityState]>,
+    /// Every client's token, for the successor to reserve their ids with.
+    pub tokens: Box<[(NetId, ReconnectToken)]>,
+}
+
+/// What this process knows for carrying on without the host. The server keeps
+/// the successor it chose here too.
+#[derive(Default)]
+pub struct HostMigration {
+    successor: Option<Successor>,
+    /// The latest state from the host, if this client is the successor.
+    state: Option<MigrationState>,
+    /// This client's token from the host.
+    token: Option<ReconnectToken>,
+    /// On the server, the token given to each client.
+    tokens: HashMap<NetId, ReconnectToken>,
+    /// The id and token this client is reconnecting with, and where to.
+    reconnect: Option<(NetId, ReconnectToken, SocketAddr)>,
+    last_attempt: f64,
+    give_up_at: f64,
+    /// On the new host, ids kept for players who haven't reconnected yet.
+    reserved: Vec<NetId>,
+    reserved_until: f64,
+}
+
+impl HostMigration {
+    pub fn successor(&self) -> Option<Successor> {
+        self.successor
+    }
+
+    /// Whether client `id` can carry on with the successor if the host
+    /// leaves. The successor itself needs the host's state to take over.
+    pub fn can_migrate(&self, id: NetId) -> bool {
+        if self.token.is_none() {
+            return false;
+        }
+        match self.successor {
+            Some(successor) if successor.id == id => self.state.is_some(),
+            Some(_) => true,
+            None => false,
+        }
+    }
+}
+
+fn forget_migration(mut migration: ResMut<HostMigration>) {
+    *migration = HostMigration::default();
+}
+
+/// Takes over as host, or reconnects to whoever does.
+fn start_migration(
+    mut migration: ResMut<HostMigration>,
+    mut roster: ResMut<PlayerRoster>,
+    mut imported: ResMut<ImportedComponents>,
+    mut clock: ResMut<ServerClock>,
+    mut role: ResMut<State<NetworkRole>>,
+    mut app_state: ResMut<State<AppState>>,
+    client: Option<Res<Client>>,
+    messages: Res<NetworkMessages>,
+    time: Res<Time>,
+    mut commands: Commands,
+) {
+    let id = match client {
+        Some(client) => client.get_id(),
+        None => return,
+    };
+    let (successor, token) = match (migration.successor.take(), migration.token.take()) {
+        (Some(successor), Some(token)) => (successor, token),
+        _ => {
+            app_state.set(AppState::Menu).unwrap();
+            return;
+        }
+    };
+    let now = time.seconds_since_startup();
+
+    roster.remove(successor.host);
+    // Tokens are handed out afresh by whoever hosts next.
+    migration.tokens.clear();
+
+    let target = if successor.id == id {
+        let state = match migration.state.take() {
+            Some(state) => state,
+            None => {
+                app_state.set(AppState::Menu).unwrap();
+                return;
+            }
+        };
+        println!("Host left. Taking over the game...");
+
+        let port = successor.addr.port();
+        let bind = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port);
+        let mut server = Server::new(Transport::Laminar, Some(bind), &messages);
+        server.set_next_id(state.next_id);
+
+        // Our own id too, for the local client to reconnect with.
+        let reserved = state
+            .tokens
+            .iter()
+            .copied()
+            .filter(|(other, _)| *other != successor.host)
+            .collect::<Vec<_>>();
+        server.reserve_ids(&reserved);
+        migration.reserved = reserved.iter().map(|(other, _)| *other).collect();
+        migration.reserved_until = now + RECONNECT_GRACE;
+
+        imported.clear();
+        for entity in state.entities.iter() {
+            if entity.spawn.name == SpawnName::Player && entity.spawn.id == successor.host {
+                continue;
+            }
+            imported.import(&entity.components);
+            commands.spawn().insert(entity.spawn);
+        }
+
+        commands.insert_resource(server);
+        set_network_role(&mut role, NetworkRole::ListenServer);
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port)
+    } else {
+        println!("Host left. Moving to {}...", roster.name(successor.id));
+        // A different server, with a different clock.
+        clock.reset();
+        successor.addr
+    };
+
+    let mut client = Client::new(Transport::Laminar, None, &messages);
+    client.reconnect(target, id, token);
+    commands.insert_resource(client);
+
+    migration.reconnect = Some((id, token, target));
+    migration.last_attempt = now;
+    migration.give_up_at = now + RECONNECT_TIMEOUT;
+}
+
+fn reconnect_to_new_host(
+    mut client_evr: EventReader<ClientEvent>,
+    mut migration: ResMut<HostMigration>,
+    mut app_state: ResMut<State<AppState>>,
+    client: Option<ResMut<Client>>,
+    time: Res<Time>,
+) {
+    // Failed attempts are retried below.
+    if client_evr
+        .iter()
+        .any(|event| matches!(event, ClientEvent::Connected))
+    {
+        migration.reconnect = None;
+        app_state.set(AppState::Game).unwrap();
+        return;
+    }
+
+    let (mut client, (id, token, target)) = match (client, migration.reconnect) {
+        (Some(client), Some(reconnect)) => (client, reconnect),
+        _ => return,
+    };
+
+    let now = time.seconds_since_startup();
+    if now >= migration.give_up_at {
+        println!("Couldn't reach the new host.");
+        app_state.set(AppState::Menu).unwrap();
+    } else if now - migration.last_attempt >= RECONNECT_INTERVAL {
+        migration.last_attempt = now;
+        client.reconnect(target, id, token);
+    }
+}
+
+//
+// Server
+//
+
+/// Gives each client in the game a token, so only it can reclaim its id on
+/// the successor.
+fn issue_tokens(
+    mut ready_evr: EventReader<FromClient<Ready>>,
+    mut server_evr: EventReader<ServerEvent>,
+    mut migration: ResMut<HostMigration>,
+    mut server: ResMut<Server>,
+) {
+    for FromClient { id, .. } in ready_evr.iter() {
+        let token = fastrand::u64(..);
+        migration.tokens.insert(*id, token);
+        server.send_message_to(*id, &MigrationToken(token));
+    }
+    for event in server_evr.iter() {
+        if let ServerEvent::PlayerDisconnected(id) = event {
+            migration.tokens.remove(id);
+        }
+    }
+}
+
+/// Picks the ready player who has been in the game longest, and reachable
+/// directly, to take over if the host leaves.
+fn choose_successor(
+    mut ready_evr: EventReader<FromClient<Ready>>,
+    mut migration: ResMut<HostMigration>,
+    mut server: ResMut<Server>,
+    roster: Res<PlayerRoster>,
+    config: Res<Config>,
+    role: Res<State<NetworkRole>>,
+    client: Option<Res<Client>>,
+) {
+    // Only a listen server's host leaves with players still in the game.
+    let host = match client {
+        Some(client) if *role.current() == NetworkRole::ListenServer => client.get_id(),
+        _ => return,
+    };
+
+    let successor = roster
+        .iter()
+        .filter(|(id, entry)| *id != host && entry.ready)
+        .filter_map(|(id, entry)| Some((id, entry.join_time, server.peer_addr(id)?)))
+        .min_by(|a, b| {
+            a.1.partial_cmp(&b.1)
+                .unwrap_or(Ordering::Equal)
+                .then(a.0.cmp(&b.0))
+        })
+        .map(|(id, _, addr)| Successor {
+            id,
+            addr: SocketAddr::new(addr.ip(), config.port),
+            host,
+        });
+
+    if successor != migration.successor {
+        migration.successor = successor;
+        server.send_message(&SuccessorChanged(successor));
+        ready_evr.iter().for_each(drop);
+    } else {
+        for FromClient { id, .. } in ready_evr.iter() {
+            server.send_message_to(*id, &SuccessorChanged(successor));
+        }
+    }
+}
+
+fn send_migration_state(
+    mut last_sent: Local<f64>,
+    time: Res<Time>,
+    migration: Res<HostMigration>,
+    entity_q: Query<Entity, With<NetworkId>>,
+    states: EntityStates,
+    mut server: ResMut<Server>,
+) {
+    let successor = match migration.successor {
+        Some(successor) => successor,
+        None => return,
+    };
+
+    let now = time.seconds_since_startup();
+    if now - *last_sent < STATE_INTERVAL {
+        return;
+    }
+    *last_sent = now;
+
+    let state = MigrationState {
+        next_id: server.next_id(),
+        entities: entity_q
+            .iter()
+            .filter_map(|entity| states.get(entity))
+            .collect(),
+        tokens: migration
+            .tokens
+            .iter()
+            .map(|(id, token)| (*id, *token))
+            .collect(),
+    };
+    server.send_message_to(successor.id, &state);
+}
+
+/// Drops players who didn't follow the game to this host in time.
+fn expire_reserved_ids(
+    mut server_evr: EventReader<ServerEvent>,
+    mut migration: ResMut<HostMigration>,
+    mut roster: ResMut<PlayerRoster>,
+    mut server: ResMut<Server>,
+    mut system_message_evw: EventWriter<SystemMessage>,
+    entity_map: Res<NetworkEntityMap>,
+    time: Res<Time>,
+    mut commands: Commands,
+) {
+    for event in server_evr.iter() {
+        if let ServerEvent::PlayerConnected(id) = event {
+            migration.reserved.retain(|reserved| reserved != id);
+        }
+    }
+
+    if migration.reserved.is_empty() || time.seconds_since_startup() < migration.reserved_until {
+        return;
+    }
+
+    for id in std::mem::take(&mut migration.reserved) {
+        system_message_evw.send(SystemMessage(format!("{} left.", roster.name(id))));
+        roster.remove(id);
+        if let Some(entity) = entity_map.entity(id) {
+            commands.entity(entity).insert(Despawn);
+        }
+    }
+    server.reserve_ids(&[]);
+}
+
+//
+// Client
+//
+
+fn receive_successor(
+    mut successor_evr: EventReader<FromServer<SuccessorChanged>>,
+    mut migration: ResMut<HostMigration>,
+    client: Res<Client>,
+) {
+    for FromServer(SuccessorChanged(successor)) in successor_evr.iter() {
+        migration.successor = *successor;
+        if successor.map(|successor| successor.id) != Some(client.get_id()) {
+            migration.state = None;
+        }
+    }
+}
+
+fn receive_token(
+    mut token_evr: EventReader<FromServer<MigrationToken>>,
+    mut migration: ResMut<HostMigration>,
+) {
+    for FromServer(MigrationToken(token)) in token_evr.iter() {
+        migration.token = Some(*token);
+    }
+}
+
+fn receive_migration_state(
+    mut state_evr: EventReader<FromServer<MigrationState>>,
+    mut migration: ResMut<HostMigration>,
+) {
+    for FromServer(state) in state_evr.iter() {
+        migration.state = Some(state.clone());
+    }
+}
@@ -1,35 +1,47 @@
-use std::net::SocketAddr;
+use std::{net::SocketAddr, sync::Arc};
 
 use bevy::{ecs::schedule::ShouldRun, prelude::*, utils::HashMap};
-use serde::{Deserialize, Serialize};
+use bytes::Bytes;
 
-use crate::spawn::Spawn;
-
-use super::{ServerPacket, Snapshot};
-use transport::{ClientTransport, ClientTransportEvent, DeliveryMethod, NetId, Transport};
+use super::{
+    FromServer, MessageFormat, MessageRegistry, NetworkMessage, NetworkMessages, NetworkSystem,
+    PlayerConnected, PlayerDisconnected,
+};
+use transport::{
+    ClientTransport, ClientTransportEvent, ConnectTarget, NetId, ReconnectToken, Transport,
+};
 
 pub(super) struct ClientPlugin;
 
 impl Plugin for ClientPlugin {
     fn build(&self, app: &mut App) {
-        app.add_event::<ClientEvent>().add_system_set(
-            SystemSet::new()
-                .with_run_criteria(client_run_criteria)
-                .with_system(update)
-                .with_system(events),
-        );
+        app.add_event::<ClientEvent>()
+            .init_resource::<ClientInbox>()
+            .add_system_set(
+                SystemSet::new()
+                    .with_run_criteria(client_run_criteria)
+                    .with_system(update.label(NetworkSystem::Update))
+                    .with_system(
+                        events
+                            .label(NetworkSystem::Events)
+                            .after(NetworkSystem::Update),
+                    )
+                    .with_system(player_connection_events.after(NetworkSystem::Receive)),
+            );
     }
 }
 
 pub struct Client {
     transport: Box<dyn ClientTransport>,
+    messages: Arc<MessageRegistry>,
     players: HashMap<NetId, RemotePlayer>,
 }
 
 impl Client {
-    pub fn new(transport: Transport, addr: Option<SocketAddr>) -> Self {
+    pub fn new(transport: Transport, addr: Option<SocketAddr>, messages: &NetworkMessages) -> Self {
         Self {
             transport: transport.client(addr),
+            messages: messages.client.clone(),
             players: HashMap::default(),
         }
     }
@@ -38,25 +50,32 @@ impl Client {
         self.transport.get_id()
     }
 
-    pub fn _is_connected(&self) -> bool {
+    pub fn is_connected(&self) -> bool {
         self.transport.is_connected()
     }
 
-    pub fn is_host(&self) -> bool {
-        self.transport.get_id() == 0
+    pub fn connect(&mut self, target: impl Into<ConnectTarget>) {
+        self.transport.connect(target.into());
     }
 
-    pub fn connect(&mut self, addr: SocketAddr) {
-        self.transport.connect(addr);
+    /// Connects asking for the id this client had before, with the token its
+    /// previous server gave out for it.
+    pub fn reconnect(
+        &mut self,
+        target: impl Into<ConnectTarget>,
+        id: NetId,
+        token: ReconnectToken,
+    ) {
+        self.transport.reconnect(target.into(), id, token);
     }
 
-    pub fn send(&mut self, packet: ClientPacket, delivery: DeliveryMethod) {
-        let bytes = bincode::serialize(&packet).unwrap();
+    pub fn send_message<T: NetworkMessage>(&mut self, message: &T) {
+        let (bytes, delivery) = self.messages.encode(message);
         self.transport.send(bytes, delivery);
     }
 }
 
-fn client_run_criteria(client: Option<Res<Client>>) -> ShouldRun {
+pub(super) fn client_run_criteria(client: Option<Res<Client>>) -> ShouldRun {
     if client.is_some() {
         ShouldRun::Yes
     } else {
@@ -64,6 +83,23 @@ fn client_run_criteria(client: Option<Res<Client>>) -> ShouldRun {
     }
 }
 
+#[derive(Default)]
+pub(super) struct ClientInbox {
+    /// Received message payloads, indexed by message id.
+    queues: Vec<Vec<Bytes>>,
+    malformed: u32,
+}
+
+impl ClientInbox {
+    fn malformed(&mut self, reason: impl std::fmt::Display) {
+        self.malformed += 1;
+        println!(
+            "[C] Malformed message from server ({} so far): {}",
+            self.malformed, reason
+        );
+    }
+}
+
 fn update(mut client: ResMut<Client>, mut client_evw: EventWriter<ClientTransportEvent>) {
     client.transport.poll();
     client.transport.receive(&mut client_evw);
@@ -73,10 +109,15 @@ fn events(
     mut client_transport_evr: EventReader<ClientTransportEvent>,
     mut client_evw: EventWriter<ClientEvent>,
     mut client: ResMut<Client>,
+    mut inbox: ResMut<ClientInbox>,
+    messages: Res<NetworkMessages>,
 ) {
+    inbox.queues.resize_with(messages.server.len(), Vec::new);
+
     for event in client_transport_evr.iter() {
         match event {
             ClientTransportEvent::Connected(id) => {
+                inbox.malformed = 0;
                 client.players.insert(*id, RemotePlayer);
                 client_evw.send(ClientEvent::Connected);
                 println!("[C] Connected({:?})", id);
@@ -86,49 +127,57 @@ fn events(
                 println!("[C] Disconnected");
             }
             ClientTransportEvent::Message(bytes) => {
-                let packet: ServerPacket = bincode::deserialize(bytes).unwrap();
-                println!("[C] {:?}", packet);
-                match packet {
-                    ServerPacket::PlayerConnected(id) => {
-                        client.players.insert(id, RemotePlayer);
-                        client_evw.send(ClientEvent::PlayerConnected(id));
-                    }
-                    ServerPacket::PlayerDisconnected(id) => {
-                        client.players.remove(&id);
-                        client_evw.send(ClientEvent::PlayerDisconnected(id));
-                    }
-                    ServerPacket::State(spawns) => {
-                        client_evw.send(ClientEvent::State(spawns));
-                    }
-                    ServerPacket::Snapshot(snapshot) => {
-                        if !client.is_host() {
-                            client_evw.send(ClientEvent::Snapshot(snapshot));
-                        }
-                    }
-                    ServerPacket::SpawnObstacle(id, pos) => {
-                        client_evw.send(ClientEvent::SpawnObstacle(id, pos));
+                match bytes.first().map(|message_id| *message_id as usize) {
+                    Some(message_id) if message_id < inbox.queues.len() => {
+                        inbox.queues[message_id].push(bytes.slice(1..));
                     }
+                    Some(message_id) => inbox.malformed(format!("unknown id {}", message_id)),
+                    None => inbox.malformed("empty"),
                 }
             }
         }
     }
 }
 
-pub struct RemotePlayer;
+pub(super) fn receive_message<T: NetworkMessage, F: MessageFormat<T>>(
+    mut inbox: ResMut<ClientInbox>,
+    mut message_evw: EventWriter<FromServer<T>>,
+    messages: Res<NetworkMessages>,
+) {
+    let message_id = messages.server.id::<T>() as usize;
+    let queue = match inbox.queues.get_mut(message_id) {
+        Some(queue) => std::mem::take(queue),
+        None => return,
+    };
+
+    for bytes in queue {
+        match F::read(&bytes) {
+            Ok(message) => {
+                println!("[C] {:?}", message);
+                message_evw.send(FromServer(message));
+            }
+            Err(err) => inbox.malformed(err),
+        }
+    }
+}
 
-#[derive(Debug, Serialize, Deserialize)]
-pub enum ClientPacket {
-    Ready,
-    Input(Vec2),
+fn player_connection_events(
+    mut connected_evr: EventReader<FromServer<PlayerConnected>>,
+    mut disconnected_evr: EventReader<FromServer<PlayerDisconnected>>,
+    mut client: ResMut<Client>,
+) {
+    for FromServer(PlayerConnected(id)) in connected_evr.iter() {
+        client.players.insert(*id, RemotePlayer);
+    }
+    for FromServer(PlayerDisconnected(id)) in disconnected_evr.iter() {
+        client.players.remove(id);
+    }
 }
 
+pub struct RemotePlayer;
+
 #[derive(Debug)]
 pub enum ClientEvent {
     Connected,
     Disconnected,
-    PlayerConnected(NetId),
-    PlayerDisconnected(NetId),
-    State(Box<[Spawn]>),
-    Snapshot(Snapshot),
-    SpawnObstacle(NetId, Vec3),
 }
@@ -0,0 +1,350 @@
+use std::collections::VecDeque;
+
+use bevy::{prelude::*, utils::HashMap};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    client, server, Client, FromClient, FromServer, NetworkMessageAppExt, NetworkRole,
+    NetworkSystem, Server, ServerEvent,
+};
+use transport::{DeliveryMethod, NetId};
+
+/// Simulation ticks per second on the server.
+pub const TICK_RATE: f64 = 60.0;
+pub const TICK_SECONDS: f32 = (1.0 / TICK_RATE) as f32;
+
+const PING_INTERVAL: f64 = 0.5;
+
+/// Weight of each new round trip sample the server takes for a client.
+const ROUND_TRIP_SMOOTHING: f64 = 0.2;
+
+/// Server pings awaiting their pong. Older ones count as lost.
+const PENDING_PINGS: usize = 8;
+
+/// Offset samples kept. Only the ones with the lowest round trip are trusted.
+const SAMPLES: usize = 16;
+const TRUSTED_SAMPLES: usize = 4;
+
+pub(super) struct ClockPlugin;
+
+impl Plugin for ClockPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ServerClock>()
+            .init_resource::<ClientRoundTrips>()
+            .add_client_message::<Ping>(DeliveryMethod::UnreliableSequenced)
+            .add_server_message::<Pong>(DeliveryMethod::UnreliableSequenced)
+            .add_server_message::<ServerPing>(DeliveryMethod::UnreliableSequenced)
+            .add_client_message::<ServerPong>(DeliveryMethod::UnreliableSequenced)
+            .add_system_set(
+                SystemSet::new()
+                    .with_run_criteria(client::client_run_criteria)
+                    .with_system(send_ping)
+                    .with_system(receive_pong.after(NetworkSystem::Receive))
+                    .with_system(answer_server_ping.after(NetworkSystem::Receive)),
+            )
+            .add_system_set(
+                SystemSet::new()
+                    .with_run_criteria(server::server_run_criteria)
+                    .with_system(receive_ping.after(NetworkSystem::Receive))
+                    .with_system(send_server_ping)
+                    .with_system(receive_server_pong.after(NetworkSystem::Receive))
+                    .with_system(remove_disconnected_round_trips),
+            )
+            .add_system_to_stage(CoreStage::PreUpdate, tick_clock);
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Ping {
+    pub client_time: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Pong {
+    pub client_time: f64,
+    pub server_time: f64,
+}
+
+/// Sent by the server to time the round trip to each client itself, rather
+/// than trusting what clients say.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ServerPing(pub u32);
+
+/// A client's immediate answer to a [`ServerPing`].
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ServerPong(pub u32);
+
+/// The client's estimate of the server's clock.
+///
+/// Every pong gives an offset sample, assuming the reply took half the round
+/// trip. Samples from slow round trips are the least symmetric, so the offset
+/// is the average of the few samples with the lowest round trip.
+#[derive(Default)]
+pub struct ServerClock {
+    local_time: f64,
+    offset: Option<f64>,
+    rtt: f64,
+    samples: VecDeque<(f64, f64)>,
+}
+
+impl ServerClock {
+    /// Whether enough is known to trust [`server_time`](Self::server_time).
+    pub fn is_synced(&self) -> bool {
+        self.offset.is_some()
+    }
+
+    /// The server's time since startup, in seconds.
+    pub fn server_time(&self) -> f64 {
+        self.local_time + self.offset.unwrap_or_default()
+    }
+
+    pub fn server_tick(&self) -> u32 {
+        (self.server_time() * TICK_RATE) as u32
+    }
+
+    /// Round trip time to the server, in seconds.
+    pub fn rtt(&self) -> f64 {
+        self.rtt
+    }
+
+    fn add_sample(&mut self, rtt: f64, offset: f64) {
+        if self.samples.len() == SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((rtt, offset));
+
+        let mut trusted = self.samples.iter().copied().collect::<Vec<_>>();
+        trusted.sort_by(|a, b| a.0.total_cmp(&b.0));
+        trusted.truncate(TRUSTED_SAMPLES);
+
+        let count = trusted.len() as f64;
+        self.rtt = trusted.iter().map(|(rtt, _)| rtt).sum::<f64>() / count;
+        self.offset = Some(trusted.iter().map(|(_, offset)| offset).sum::<f64>() / count);
+    }
+
+    /// Forgets the server's clock, like when moving to another server.
+    pub fn reset(&mut self) {
+        self.offset = None;
+        self.rtt = 0.0;
+        self.samples.clear();
+    }
+}
+
+/// Each client's round trip time to the server, in seconds, as measured by
+/// the server.
+#[derive(Default)]
+pub struct ClientRoundTrips {
+    round_trips: HashMap<NetId, f64>,
+    /// Pings sent and when, newest last.
+    pending: VecDeque<(u32, f64)>,
+    next_ping: u32,
+}
+
+impl ClientRoundTrips {
+    pub fn get(&self, client_id: NetId) -> Option<f64> {
+        self.round_trips.get(&client_id).copied()
+    }
+
+    fn add_sample(&mut self, client_id: NetId, rtt: f64) {
+        let smoothed = match self.round_trips.get(&client_id) {
+            Some(previous) => previous + (rtt - previous) * ROUND_TRIP_SMOOTHING,
+            None => rtt,
+        };
+        self.round_trips.insert(client_id, smoothed);
+    }
+}
+
+fn tick_clock(
+    time: Res<Time>,
+    mut clock: ResMut<ServerClock>,
+    client: Option<Res<Client>>,
+    server: Option<Res<Server>>,
+) {
+    clock.local_time = time.seconds_since_startup();
+
+    if server.is_some() {
+        // Same process, same clock.
+        clock.offset = Some(0.0);
+    } else if client.is_none() && clock.offset.is_some() {
+        clock.reset();
+    }
+}
+
+//
+// Client
+//
+
+fn send_ping(
+    mut last_ping: Local<f64>,
+    time: Res<Time>,
+    role: Res<State<NetworkRole>>,
+    mut client: ResMut<Client>,
+) {
+    let now = time.seconds_since_startup();
+    // A listen server's client shares the server's clock.
+    if role.current().is_server() || !client.is_connected() || now - *last_ping < PING_INTERVAL {
+        return;
+    }
+
+    *last_ping = now;
+    client.send_message(&Ping { client_time: now });
+}
+
+fn receive_pong(
+    mut pong_evr: EventReader<FromServer<Pong>>,
+    time: Res<Time>,
+    mut clock: ResMut<ServerClock>,
+) {
+    let now = time.seconds_since_startup();
+    for FromServer(pong) in pong_evr.iter() {
+        let rtt = now - pong.client_time;
+        if rtt.is_nan() || rtt < 0.0 || !pong.server_time.is_finite() {
+            continue;
+        }
+        clock.add_sample(rtt, pong.server_time + rtt * 0.5 - now);
+    }
+}
+
+fn answer_server_ping(
+    mut ping_evr: EventReader<FromServer<ServerPing>>,
+    mut client: ResMut<Client>,
+) {
+    for FromServer(ServerPing(ping)) in ping_evr.iter() {
+        client.send_message(&ServerPong(*ping));
+    }
+}
+
+//
+// Server
+//
+
+fn receive_ping(
+    mut ping_evr: EventReader<FromClient<Ping>>,
+    time: Res<Time>,
+    mut server: ResMut<Server>,
+) {
+    for FromClient { id, message } in ping_evr.iter() {
+        server.send_message_to(
+            *id,
+            &Pong {
+                client_time: message.client_time,
+                server_time: time.seconds_since_startup(),
+            },
+        );
+    }
+}
+
+fn send_server_ping(
+    mut last_ping: Local<f64>,
+    time: Res<Time>,
+    mut round_trips: ResMut<ClientRoundTrips>,
+    mut server: ResMut<Server>,
+) {
+    let now = time.seconds_since_startup();
+    if now - *last_ping < PING_INTERVAL {
+        return;
+    }
+    *last_ping = now;
+
+    let ping = round_trips.next_ping;
+    round_trips.next_ping = ping.wrapping_add(1);
+    if round_trips.pending.len() == PENDING_PINGS {
+        round_trips.pending.pop_front();
+    }
+    round_trips.pending.push_back((ping, now));
+    server.send_message(&ServerPing(ping));
+}
+
+fn receive_server_pong(
+    mut pong_evr: EventReader<FromClient<ServerPong>>,
+    time: Res<Time>,
+    mut round_trips: ResMut<ClientRoundTrips>,
+) {
+    let now = time.seconds_since_startup();
+    for FromClient { id, message } in pong_evr.iter() {
+        let ServerPong(ping) = message;
+        // Unknown pings are either long lost or made up.
+        let sent_at = match round_trips.pending.iter().find(|(sent, _)| sent == ping) {
+            Some((_, sent_at)) => *sent_at,
+            None => continue,
+        };
+        round_trips.add_sample(*id, now - sent_at);
+    }
+}
+
+fn remove_disconnected_round_trips(
+    mut server_evr: EventReader<ServerEvent>,
+    mut round_trips: ResMut<ClientRoundTrips>,
+) {
+    for event in server_evr.iter() {
+        if let ServerEvent::PlayerDisconnected(id) = event {
+            round_trips.round_trips.remove(id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offset_averages_the_fastest_samples() {
+        let mut clock = ServerClock::default();
+        assert!(!clock.is_synced());
+
+        // Slow round trips are off by more than the fast ones.
+        for (rtt, offset) in [(0.5, 10.0), (0.05, 2.0), (0.3, -4.0), (0.04, 2.2)] {
+            clock.add_sample(rtt, offset);
+        }
+        clock.add_sample(0.06, 1.8);
+        clock.add_sample(0.05, 2.0);
+        clock.add_sample(0.9, 50.0);
+
+        assert!(clock.is_synced());
+        assert!((clock.offset.unwrap() - 2.0).abs() < 1e-9);
+        assert!((clock.rtt() - 0.05).abs() < 1e-9);
+    }
+
+    #[test]
+    fn old_samples_are_dropped() {
+        let mut clock = ServerClock::default();
+        clock.add_sample(0.01, 100.0);
+        for _ in 0..SAMPLES {
+            clock.add_sample(0.1, 5.0);
+        }
+        assert_eq!(clock.samples.len(), SAMPLES);
+        assert!((clock.offset.unwrap() - 5.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn nan_round_trips_sort_last() {
+        let mut clock = ServerClock::default();
+        clock.add_sample(f64::NAN, 100.0);
+        for _ in 0..TRUSTED_SAMPLES {
+            clock.add_sample(0.1, 5.0);
+        }
+        assert!((clock.offset.unwrap() - 5.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn reset_forgets_the_offset() {
+        let mut clock = ServerClock::default();
+        clock.add_sample(0.1, 5.0);
+        clock.reset();
+        assert!(!clock.is_synced());
+        assert_eq!(clock.rtt(), 0.0);
+    }
+
+    #[test]
+    fn client_round_trips_are_smoothed() {
+        let mut round_trips = ClientRoundTrips::default();
+        assert_eq!(round_trips.get(1), None);
+
+        round_trips.add_sample(1, 0.1);
+        assert_eq!(round_trips.get(1), Some(0.1));
+        round_trips.add_sample(1, 0.6);
+        let expected = 0.1 + 0.5 * ROUND_TRIP_SMOOTHING;
+        assert!((round_trips.get(1).unwrap() - expected).abs() < 1e-9);
+        assert_eq!(round_trips.get(2), None);
+    }
+}
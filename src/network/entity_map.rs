@@ -0,0 +1,69 @@
+use bevy::{prelude::*, utils::HashMap};
+
+use super::NetworkId;
+use transport::NetId;
+
+/// Entities by [`NetworkId`] and back. Kept up to date in `PostUpdate`, so
+/// entities spawned or despawned this frame show up from the next one.
+#[derive(Default)]
+pub struct NetworkEntityMap {
+    entities: HashMap<NetId, Entity>,
+    ids: HashMap<Entity, NetId>,
+}
+
+impl NetworkEntityMap {
+    pub fn entity(&self, id: NetId) -> Option<Entity> {
+        self.entities.get(&id).copied()
+    }
+
+    pub fn id(&self, entity: Entity) -> Option<NetId> {
+        self.ids.get(&entity).copied()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (NetId, Entity)> + '_ {
+        self.entities.iter().map(|(id, entity)| (*id, *entity))
+    }
+}
+
+/// Sent when an entity gets a [`NetworkId`] that another entity already has.
+/// The map then points at `new`.
+#[derive(Debug)]
+pub struct NetworkIdConflict {
+    pub id: NetId,
+    pub existing: Entity,
+    pub new: Entity,
+}
+
+pub(super) fn sync_entity_map(
+    mut map: ResMut<NetworkEntityMap>,
+    mut conflict_evw: EventWriter<NetworkIdConflict>,
+    added_q: Query<(Entity, &NetworkId), Added<NetworkId>>,
+    removed: RemovedComponents<NetworkId>,
+) {
+    for entity in removed.iter() {
+        if let Some(id) = map.ids.remove(&entity) {
+            if map.entities.get(&id) == Some(&entity) {
+                map.entities.remove(&id);
+            }
+        }
+    }
+
+    for (entity, net_id) in added_q.iter() {
+        let id = net_id.value();
+        if let Some(existing) = map.entities.insert(id, entity) {
+            if existing != entity {
+                map.ids.remove(&existing);
+                println!(
+                    "Network id {} moved from {:?} to {:?}",
+                    id, existing, entity
+                );
+                conflict_evw.send(NetworkIdConflict {
+                    id,
+                    existing,
+                    new: entity,
+                });
+            }
+        }
+        map.ids.insert(entity, id);
+    }
+}
@@ -0,0 +1,266 @@
+use std::{
+    any::{Any, TypeId},
+    fmt::{self, Debug},
+    sync::Arc,
+};
+
+use bevy::{prelude::*, utils::HashMap};
+use bincode::Options;
+use bitpack::{BitError, NetSerialize};
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::{client, server, NetworkSystem};
+use transport::{DeliveryMethod, NetId};
+
+pub type MessageId = u8;
+
+/// Upper bound on the encoded size of a message. Every length prefix is checked
+/// against what's left of it before anything is allocated.
+const MAX_MESSAGE_SIZE: u64 = 16 * 1024;
+
+fn bincode_options() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_limit(MAX_MESSAGE_SIZE)
+}
+
+pub trait NetworkMessage: Debug + Send + Sync + 'static {}
+
+impl<T> NetworkMessage for T where T: Debug + Send + Sync + 'static {}
+
+/// How a message type is turned into bytes. Picked per type at registration.
+pub trait MessageFormat<T>: Send + Sync + 'static {
+    fn write(message: &T, bytes: &mut Vec<u8>);
+    fn read(bytes: &[u8]) -> Result<T, DecodeError>;
+}
+
+/// serde + bincode. Works for any serde type; the fallback for messages that
+/// haven't been given a bit-packed layout.
+pub struct Bincode;
+
+impl<T: Serialize + DeserializeOwned> MessageFormat<T> for Bincode {
+    fn write(message: &T, bytes: &mut Vec<u8>) {
+        bincode_options().serialize_into(bytes, message).unwrap();
+    }
+
+    fn read(bytes: &[u8]) -> Result<T, DecodeError> {
+        Ok(bincode_options().deserialize(bytes)?)
+    }
+}
+
+/// [`NetSerialize`] bit packing, for messages sent often enough that every bit
+/// counts.
+pub struct BitPacked;
+
+impl<T: NetSerialize> MessageFormat<T> for BitPacked {
+    fn write(message: &T, bytes: &mut Vec<u8>) {
+        let mut writer = bitpack::BitWriter::with_bytes(std::mem::take(bytes));
+        message.net_serialize(&mut writer);
+        *bytes = writer.finish();
+    }
+
+    fn read(bytes: &[u8]) -> Result<T, DecodeError> {
+        if bytes.len() as u64 > MAX_MESSAGE_SIZE {
+            return Err(DecodeError::TooLarge);
+        }
+        Ok(bitpack::from_bytes(bytes)?)
+    }
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    Bincode(bincode::Error),
+    BitPacked(BitError),
+    TooLarge,
+}
+
+impl From<bincode::Error> for DecodeError {
+    fn from(err: bincode::Error) -> Self {
+        DecodeError::Bincode(err)
+    }
+}
+
+impl From<BitError> for DecodeError {
+    fn from(err: BitError) -> Self {
+        DecodeError::BitPacked(err)
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Bincode(err) => err.fmt(f),
+            DecodeError::BitPacked(err) => err.fmt(f),
+            DecodeError::TooLarge => write!(f, "larger than {} bytes", MAX_MESSAGE_SIZE),
+        }
+    }
+}
+
+/// A message received from the server.
+pub struct FromServer<T>(pub T);
+
+/// A message received from the client with the given id.
+pub struct FromClient<T> {
+    pub id: NetId,
+    pub message: T,
+}
+
+#[derive(Clone, Copy)]
+struct MessageKind {
+    id: MessageId,
+    delivery: DeliveryMethod,
+    write: fn(&dyn Any, &mut Vec<u8>),
+}
+
+fn write_message<T: NetworkMessage, F: MessageFormat<T>>(message: &dyn Any, bytes: &mut Vec<u8>) {
+    F::write(message.downcast_ref::<T>().unwrap(), bytes);
+}
+
+#[derive(Default, Clone)]
+pub struct MessageRegistry {
+    kinds: HashMap<TypeId, MessageKind>,
+}
+
+impl MessageRegistry {
+    fn register<T: NetworkMessage, F: MessageFormat<T>>(&mut self, delivery: DeliveryMethod) {
+        let id = self.kinds.len();
+        assert!(id <= MessageId::MAX as usize, "too many message types");
+        let previous = self.kinds.insert(
+            TypeId::of::<T>(),
+            MessageKind {
+                id: id as MessageId,
+                delivery,
+                write: write_message::<T, F>,
+            },
+        );
+        assert!(
+            previous.is_none(),
+            "message type {} registered twice",
+            std::any::type_name::<T>()
+        );
+    }
+
+    fn kind<T: NetworkMessage>(&self) -> MessageKind {
+        match self.kinds.get(&TypeId::of::<T>()) {
+            Some(kind) => *kind,
+            None => panic!(
+                "message type {} is not registered",
+                std::any::type_name::<T>()
+            ),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.kinds.len()
+    }
+
+    pub fn id<T: NetworkMessage>(&self) -> MessageId {
+        self.kind::<T>().id
+    }
+
+    pub fn encode<T: NetworkMessage>(&self, message: &T) -> (Vec<u8>, DeliveryMethod) {
+        let kind = self.kind::<T>();
+        let mut bytes = vec![kind.id];
+        (kind.write)(message, &mut bytes);
+        (bytes, kind.delivery)
+    }
+}
+
+/// Message types sent by the server and by clients. Both ends register the same
+/// types in the same order, so the ids assigned at registration line up.
+#[derive(Default, Clone)]
+pub struct NetworkMessages {
+    pub server: Arc<MessageRegistry>,
+    pub client: Arc<MessageRegistry>,
+}
+
+pub trait NetworkMessageAppExt {
+    /// Registers a server-to-client message sent with [`Bincode`].
+    fn add_server_message<T>(&mut self, delivery: DeliveryMethod) -> &mut Self
+    where
+        T: NetworkMessage + Serialize + DeserializeOwned;
+    /// Registers a client-to-server message sent with [`Bincode`].
+    fn add_client_message<T>(&mut self, delivery: DeliveryMethod) -> &mut Self
+    where
+        T: NetworkMessage + Serialize + DeserializeOwned;
+    /// Registers a server-to-client message sent [`BitPacked`].
+    fn add_packed_server_message<T>(&mut self, delivery: DeliveryMethod) -> &mut Self
+    where
+        T: NetworkMessage + NetSerialize;
+    /// Registers a client-to-server message sent [`BitPacked`].
+    fn add_packed_client_message<T>(&mut self, delivery: DeliveryMethod) -> &mut Self
+    where
+        T: NetworkMessage + NetSerialize;
+}
+
+impl NetworkMessageAppExt for App {
+    fn add_server_message<T>(&mut self, delivery: DeliveryMethod) -> &mut Self
+    where
+        T: NetworkMessage + Serialize + DeserializeOwned,
+    {
+        add_server_message::<T, Bincode>(self, delivery)
+    }
+
+    fn add_client_message<T>(&mut self, delivery: DeliveryMethod) -> &mut Self
+    where
+        T: NetworkMessage + Serialize + DeserializeOwned,
+    {
+        add_client_message::<T, Bincode>(self, delivery)
+    }
+
+    fn add_packed_server_message<T>(&mut self, delivery: DeliveryMethod) -> &mut Self
+    where
+        T: NetworkMessage + NetSerialize,
+    {
+        add_server_message::<T, BitPacked>(self, delivery)
+    }
+
+    fn add_packed_client_message<T>(&mut self, delivery: DeliveryMethod) -> &mut Self
+    where
+        T: NetworkMessage + NetSerialize,
+    {
+        add_client_message::<T, BitPacked>(self, delivery)
+    }
+}
+
+fn add_server_message<T, F>(app: &mut App, delivery: DeliveryMethod) -> &mut App
+where
+    T: NetworkMessage,
+    F: MessageFormat<T>,
+{
+    let mut messages = app
+        .world
+        .get_resource_or_insert_with(NetworkMessages::default);
+    Arc::make_mut(&mut messages.server).register::<T, F>(delivery);
+
+    app.add_event::<FromServer<T>>().add_system_set(
+        SystemSet::new()
+            .with_run_criteria(client::client_run_criteria)
+            .with_system(
+                client::receive_message::<T, F>
+                    .label(NetworkSystem::Receive)
+                    .after(NetworkSystem::Events),
+            ),
+    )
+}
+
+fn add_client_message<T, F>(app: &mut App, delivery: DeliveryMethod) -> &mut App
+where
+    T: NetworkMessage,
+    F: MessageFormat<T>,
+{
+    let mut messages = app
+        .world
+        .get_resource_or_insert_with(NetworkMessages::default);
+    Arc::make_mut(&mut messages.client).register::<T, F>(delivery);
+
+    app.add_event::<FromClient<T>>().add_system_set(
+        SystemSet::new()
+            .with_run_criteria(server::server_run_criteria)
+            .with_system(
+                server::receive_message::<T, F>
+                    .label(NetworkSystem::Receive)
+                    .after(NetworkSystem::Events),
+            ),
+    )
+}
@@ -1,21 +1,88 @@
 use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
 
-pub use transport::{DeliveryMethod, NetId, Transport};
+pub use transport::{DeliveryMethod, NetId, ReconnectToken, RoomCode, Transport};
 
 mod client;
+mod clock;
+mod entity_map;
+mod message;
+mod rpc;
 mod server;
 
 pub use client::*;
+pub use clock::*;
+pub use entity_map::*;
+pub use message::*;
+pub use rpc::*;
 pub use server::*;
 
 pub struct NetworkPlugin;
 
 impl Plugin for NetworkPlugin {
     fn build(&self, app: &mut App) {
-        app.add_plugin(ServerPlugin).add_plugin(ClientPlugin);
+        // Processes that start in a role, like a dedicated server, add the
+        // state themselves.
+        if app.world.get_resource::<State<NetworkRole>>().is_none() {
+            app.add_state(NetworkRole::Offline);
+        }
+
+        app.init_resource::<NetworkMessages>()
+            .init_resource::<NetworkEntityMap>()
+            .init_resource::<PendingRpcs>()
+            .init_resource::<RpcSettings>()
+            .add_event::<NetworkIdConflict>()
+            .add_system_to_stage(CoreStage::PostUpdate, sync_entity_map)
+            .add_plugin(ServerPlugin)
+            .add_plugin(ClientPlugin)
+            .add_plugin(ClockPlugin)
+            .add_server_message::<PlayerConnected>(DeliveryMethod::ReliableOrdered)
+            .add_server_message::<PlayerDisconnected>(DeliveryMethod::ReliableOrdered);
+    }
+}
+
+/// What this process is in the networked game. Kept as a [`State`], so
+/// systems can run on entering or leaving a role.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NetworkRole {
+    Offline,
+    Client,
+    /// A server with a local player.
+    ListenServer,
+    /// A server on its own, without a window or local player.
+    DedicatedServer,
+}
+
+impl NetworkRole {
+    /// Whether this process simulates the game, rather than following a
+    /// server.
+    pub fn is_server(&self) -> bool {
+        matches!(
+            self,
+            NetworkRole::ListenServer | NetworkRole::DedicatedServer
+        )
+    }
+
+    /// Whether this process has a player of its own.
+    pub fn has_local_player(&self) -> bool {
+        matches!(self, NetworkRole::Client | NetworkRole::ListenServer)
     }
 }
 
+/// Switches to `role`, unless already in it.
+pub fn set_network_role(state: &mut State<NetworkRole>, role: NetworkRole) {
+    if *state.current() != role {
+        state.overwrite_set(role).unwrap();
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemLabel)]
+pub enum NetworkSystem {
+    Update,
+    Events,
+    Receive,
+}
+
 #[derive(Component)]
 pub struct NetworkId(NetId);
 
@@ -27,3 +94,9 @@ impl NetworkId {
         self.0
     }
 }
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PlayerConnected(pub NetId);
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PlayerDisconnected(pub NetId);
@@ -0,0 +1,446 @@
+use std::{any::TypeId, marker::PhantomData};
+
+use bevy::{
+    ecs::system::SystemParam,
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use super::{
+    client, server, Client, FromClient, FromServer, NetworkEntityMap, NetworkMessage,
+    NetworkMessageAppExt, NetworkSystem, Server,
+};
+use transport::{DeliveryMethod, NetId};
+
+pub type RequestId = u32;
+
+/// A call made on the other end of the connection.
+///
+/// Calls whose `Response` is `()` are fire-and-forget: nothing is sent back and
+/// they never time out. Otherwise the handler is expected to
+/// [`respond`](Rpcs::respond), and the caller gets an [`RpcReply`] or, after
+/// [`RpcSettings::timeout`], an [`RpcTimeout`].
+pub trait Rpc: NetworkMessage + Serialize + DeserializeOwned {
+    type Response: NetworkMessage + Serialize + DeserializeOwned;
+
+    const DELIVERY: DeliveryMethod = DeliveryMethod::ReliableOrdered;
+
+    /// The networked entity the call is about, handed to the handler as an
+    /// [`Entity`].
+    fn target(&self) -> Option<NetId> {
+        None
+    }
+}
+
+pub struct RpcSettings {
+    /// Seconds to wait for responses.
+    pub timeout: f64,
+}
+
+impl Default for RpcSettings {
+    fn default() -> Self {
+        Self { timeout: 5.0 }
+    }
+}
+
+/// A call to handle. `from` is the calling client, or `None` for the server.
+pub struct RpcCall<T> {
+    pub id: RequestId,
+    pub from: Option<NetId>,
+    pub entity: Option<Entity>,
+    pub call: T,
+}
+
+/// A response to one of our calls. `from` is the responding client, or `None`
+/// for the server.
+pub struct RpcReply<T: Rpc> {
+    pub id: RequestId,
+    pub from: Option<NetId>,
+    pub response: T::Response,
+}
+
+/// Sent when a call still had responses outstanding after the timeout.
+pub struct RpcTimeout<T> {
+    pub id: RequestId,
+    marker: PhantomData<T>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RpcRequest<T> {
+    id: RequestId,
+    call: T,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(bound(
+    serialize = "T::Response: Serialize",
+    deserialize = "T::Response: DeserializeOwned"
+))]
+struct RpcResponse<T: Rpc> {
+    id: RequestId,
+    response: T::Response,
+}
+
+struct PendingRpc {
+    kind: TypeId,
+    sent_at: f64,
+    /// Who still has to respond: clients by id, the server as `None`.
+    responders: HashSet<Option<NetId>>,
+}
+
+#[derive(Default)]
+pub struct PendingRpcs {
+    next_id: RequestId,
+    pending: HashMap<RequestId, PendingRpc>,
+}
+
+impl PendingRpcs {
+    fn start<T: Rpc>(
+        &mut self,
+        now: f64,
+        responders: impl IntoIterator<Item = Option<NetId>>,
+    ) -> RequestId {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        let responders = responders.into_iter().collect::<HashSet<_>>();
+        if expects_response::<T>() && !responders.is_empty() {
+            self.pending.insert(
+                id,
+                PendingRpc {
+                    kind: TypeId::of::<T>(),
+                    sent_at: now,
+                    responders,
+                },
+            );
+        }
+        id
+    }
+
+    /// Whether a response to `id` was still expected from `from`. Anyone else
+    /// answering, or answering twice, is ignored.
+    fn finish<T: Rpc>(&mut self, id: RequestId, from: Option<NetId>) -> bool {
+        let pending = match self.pending.get_mut(&id) {
+            Some(pending) if pending.kind == TypeId::of::<T>() => pending,
+            _ => return false,
+        };
+        if !pending.responders.remove(&from) {
+            return false;
+        }
+        if pending.responders.is_empty() {
+            self.pending.remove(&id);
+        }
+        true
+    }
+
+    /// Forgets calls of type `T` made more than `timeout` seconds before
+    /// `now`, returning their ids.
+    fn expire<T: Rpc>(&mut self, now: f64, timeout: f64) -> Vec<RequestId> {
+        let kind = TypeId::of::<T>();
+        let mut expired = Vec::new();
+        self.pending.retain(|id, rpc| {
+            if rpc.kind == kind && now - rpc.sent_at > timeout {
+                expired.push(*id);
+                return false;
+            }
+            true
+        });
+        expired
+    }
+}
+
+fn expects_response<T: Rpc>() -> bool {
+    TypeId::of::<T::Response>() != TypeId::of::<()>()
+}
+
+/// Makes and answers calls, from whichever of client and server is running.
+#[derive(SystemParam)]
+pub struct Rpcs<'w, 's> {
+    client: Option<ResMut<'w, Client>>,
+    server: Option<ResMut<'w, Server>>,
+    pending: ResMut<'w, PendingRpcs>,
+    time: Res<'w, Time>,
+    #[system_param(ignore)]
+    marker: PhantomData<&'s ()>,
+}
+
+impl<'w, 's> Rpcs<'w, 's> {
+    pub fn call_server<T: Rpc>(&mut self, call: T) -> RequestId {
+        let id = self
+            .pending
+            .start::<T>(self.time.seconds_since_startup(), [None]);
+        if let Some(client) = self.client.as_mut() {
+            client.send_message(&RpcRequest { id, call });
+        }
+        id
+    }
+
+    pub fn call_client<T: Rpc>(&mut self, client_id: NetId, call: T) -> RequestId {
+        let id = self
+            .pending
+            .start::<T>(self.time.seconds_since_startup(), [Some(client_id)]);
+        if let Some(server) = self.server.as_mut() {
+            server.send_message_to(client_id, &RpcRequest { id, call });
+        }
+        id
+    }
+
+    /// Calls every connected client, expecting a response from each.
+    pub fn broadcast<T: Rpc>(&mut self, call: T) -> RequestId {
+        let clients = self
+            .server
+            .as_ref()
+            .map(|server| server.player_ids().map(Some).collect::<Vec<_>>())
+            .unwrap_or_default();
+        let id = self
+            .pending
+            .start::<T>(self.time.seconds_since_startup(), clients);
+        if let Some(server) = self.server.as_mut() {
+            server.send_message(&RpcRequest { id, call });
+        }
+        id
+    }
+
+    pub fn respond<T: Rpc>(&mut self, call: &RpcCall<T>, response: T::Response) {
+        if !expects_response::<T>() {
+            return;
+        }
+
+        let response = RpcResponse::<T> {
+            id: call.id,
+            response,
+        };
+        match call.from {
+            Some(client_id) => {
+                if let Some(server) = self.server.as_mut() {
+                    server.send_message_to(client_id, &response);
+                }
+            }
+            None => {
+                if let Some(client) = self.client.as_mut() {
+                    client.send_message(&response);
+                }
+            }
+        }
+    }
+}
+
+pub trait RpcAppExt {
+    /// Registers `T` as a call from clients, handled on the server.
+    fn add_server_rpc<T: Rpc>(&mut self) -> &mut Self;
+    /// Registers `T` as a call from the server, handled on clients.
+    fn add_client_rpc<T: Rpc>(&mut self) -> &mut Self;
+}
+
+impl RpcAppExt for App {
+    fn add_server_rpc<T: Rpc>(&mut self) -> &mut Self {
+        add_rpc_events::<T>(self)
+            .add_client_message::<RpcRequest<T>>(T::DELIVERY)
+            .add_server_message::<RpcResponse<T>>(T::DELIVERY)
+            .add_system_set(
+                SystemSet::new()
+                    .with_run_criteria(server::server_run_criteria)
+                    .with_system(receive_client_calls::<T>.after(NetworkSystem::Receive)),
+            )
+            .add_system_set(
+                SystemSet::new()
+                    .with_run_criteria(client::client_run_criteria)
+                    .with_system(receive_server_responses::<T>.after(NetworkSystem::Receive)),
+            )
+    }
+
+    fn add_client_rpc<T: Rpc>(&mut self) -> &mut Self {
+        add_rpc_events::<T>(self)
+            .add_server_message::<RpcRequest<T>>(T::DELIVERY)
+            .add_client_message::<RpcResponse<T>>(T::DELIVERY)
+            .add_system_set(
+                SystemSet::new()
+                    .with_run_criteria(client::client_run_criteria)
+                    .with_system(receive_server_calls::<T>.after(NetworkSystem::Receive)),
+            )
+            .add_system_set(
+                SystemSet::new()
+                    .with_run_criteria(server::server_run_criteria)
+                    .with_system(receive_client_responses::<T>.after(NetworkSystem::Receive)),
+            )
+    }
+}
+
+/// The events are shared by both directions, so only add them once.
+fn add_rpc_events<T: Rpc>(app: &mut App) -> &mut App {
+    if app.world.contains_resource::<Events<RpcCall<T>>>() {
+        return app;
+    }
+
+    app.add_event::<RpcCall<T>>()
+        .add_event::<RpcReply<T>>()
+        .add_event::<RpcTimeout<T>>()
+        .add_system(expire_calls::<T>)
+}
+
+fn receive_client_calls<T: Rpc>(
+    mut request_evr: ResMut<Events<FromClient<RpcRequest<T>>>>,
+    mut call_evw: EventWriter<RpcCall<T>>,
+    entity_map: Res<NetworkEntityMap>,
+) {
+    for FromClient { id, message } in request_evr.drain() {
+        call_evw.send(RpcCall {
+            id: message.id,
+            from: Some(id),
+            entity: message.call.target().and_then(|id| entity_map.entity(id)),
+            call: message.call,
+        });
+    }
+}
+
+fn receive_server_calls<T: Rpc>(
+    mut request_evr: ResMut<Events<FromServer<RpcRequest<T>>>>,
+    mut call_evw: EventWriter<RpcCall<T>>,
+    entity_map: Res<NetworkEntityMap>,
+) {
+    for FromServer(message) in request_evr.drain() {
+        call_evw.send(RpcCall {
+            id: message.id,
+            from: None,
+            entity: message.call.target().and_then(|id| entity_map.entity(id)),
+            call: message.call,
+        });
+    }
+}
+
+fn receive_client_responses<T: Rpc>(
+    mut response_evr: ResMut<Events<FromClient<RpcResponse<T>>>>,
+    mut reply_evw: EventWriter<RpcReply<T>>,
+    mut pending: ResMut<PendingRpcs>,
+) {
+    for FromClient { id, message } in response_evr.drain() {
+        if pending.finish::<T>(message.id, Some(id)) {
+            reply_evw.send(RpcReply {
+                id: message.id,
+                from: Some(id),
+                response: message.response,
+            });
+        }
+    }
+}
+
+fn receive_server_responses<T: Rpc>(
+    mut response_evr: ResMut<Events<FromServer<RpcResponse<T>>>>,
+    mut reply_evw: EventWriter<RpcReply<T>>,
+    mut pending: ResMut<PendingRpcs>,
+) {
+    for FromServer(message) in response_evr.drain() {
+        if pending.finish::<T>(message.id, None) {
+            reply_evw.send(RpcReply {
+                id: message.id,
+                from: None,
+                response: message.response,
+            });
+        }
+    }
+}
+
+fn expire_calls<T: Rpc>(
+    time: Res<Time>,
+    settings: Res<RpcSettings>,
+    mut pending: ResMut<PendingRpcs>,
+    mut timeout_evw: EventWriter<RpcTimeout<T>>,
+) {
+    let now = time.seconds_since_startup();
+    for id in pending.expire::<T>(now, settings.timeout) {
+        println!("RPC {} ({}) timed out", id, std::any::type_name::<T>());
+        timeout_evw.send(RpcTimeout {
+            id,
+            marker: PhantomData,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Ask;
+
+    impl Rpc for Ask {
+        type Response = u32;
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Other;
+
+    impl Rpc for Other {
+        type Response = u32;
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Notify;
+
+    impl Rpc for Notify {
+        type Response = ();
+    }
+
+    #[test]
+    fn responses_are_matched_to_the_callee() {
+        let mut pending = PendingRpcs::default();
+        let id = pending.start::<Ask>(0.0, [Some(1)]);
+
+        assert!(!pending.finish::<Ask>(id, Some(2)));
+        assert!(!pending.finish::<Ask>(id, None));
+        assert!(!pending.finish::<Other>(id, Some(1)));
+        assert!(pending.finish::<Ask>(id, Some(1)));
+        assert!(!pending.finish::<Ask>(id, Some(1)));
+        assert!(pending.pending.is_empty());
+    }
+
+    #[test]
+    fn broadcasts_wait_for_every_client_once() {
+        let mut pending = PendingRpcs::default();
+        let id = pending.start::<Ask>(0.0, [Some(1), Some(2)]);
+
+        assert!(pending.finish::<Ask>(id, Some(1)));
+        // Answering again doesn't stand in for the other client.
+        assert!(!pending.finish::<Ask>(id, Some(1)));
+        assert!(pending.pending.contains_key(&id));
+        assert!(pending.finish::<Ask>(id, Some(2)));
+        assert!(pending.pending.is_empty());
+    }
+
+    #[test]
+    fn server_responses() {
+        let mut pending = PendingRpcs::default();
+        let id = pending.start::<Ask>(0.0, [None]);
+        assert!(!pending.finish::<Ask>(id, Some(0)));
+        assert!(pending.finish::<Ask>(id, None));
+    }
+
+    #[test]
+    fn fire_and_forget_calls_are_not_pending() {
+        let mut pending = PendingRpcs::default();
+        let id = pending.start::<Notify>(0.0, [None]);
+        assert!(pending.pending.is_empty());
+        assert!(!pending.finish::<Notify>(id, None));
+
+        // Nor are calls nobody can answer.
+        pending.start::<Ask>(0.0, Vec::new());
+        assert!(pending.pending.is_empty());
+    }
+
+    #[test]
+    fn calls_expire_after_the_timeout() {
+        let mut pending = PendingRpcs::default();
+        let early = pending.start::<Ask>(0.0, [Some(1)]);
+        let late = pending.start::<Ask>(3.0, [Some(1)]);
+        let other = pending.start::<Other>(0.0, [Some(1)]);
+
+        assert!(pending.expire::<Ask>(5.0, 5.0).is_empty());
+        assert_eq!(pending.expire::<Ask>(6.0, 5.0), vec![early]);
+        assert!(!pending.finish::<Ask>(early, Some(1)));
+
+        // Only calls of the given type.
+        assert!(pending.pending.contains_key(&other));
+        assert!(pending.finish::<Ask>(late, Some(1)));
+        assert_eq!(pending.expire::<Other>(6.0, 5.0), vec![other]);
+    }
+}
@@ -1,71 +1,114 @@
-use std::net::SocketAddr;
+use std::{net::SocketAddr, sync::Arc};
 
 use bevy::{ecs::schedule::ShouldRun, prelude::*, utils::HashMap};
-use bincode;
-use serde::{Deserialize, Serialize};
+use bytes::Bytes;
 
-use crate::spawn::Spawn;
-
-use super::ClientPacket;
-use transport::{DeliveryMethod, NetId, ServerTransport, ServerTransportEvent, Transport};
+use super::{
+    FromClient, MessageFormat, MessageRegistry, NetworkMessage, NetworkMessages, NetworkSystem,
+};
+use transport::{
+    NetId, ReconnectToken, RoomCode, ServerTransport, ServerTransportEvent, Transport,
+};
 
 pub(super) struct ServerPlugin;
 
 impl Plugin for ServerPlugin {
     fn build(&self, app: &mut App) {
-        app.add_event::<ServerEvent>().add_system_set(
-            SystemSet::new()
-                .with_run_criteria(server_run_criteria)
-                .with_system(update)
-                .with_system(events),
-        );
+        app.add_event::<ServerEvent>()
+            .init_resource::<ServerInbox>()
+            .init_resource::<MalformedMessagePolicy>()
+            .add_system_set(
+                SystemSet::new()
+                    .with_run_criteria(server_run_criteria)
+                    .with_system(update.label(NetworkSystem::Update))
+                    .with_system(
+                        events
+                            .label(NetworkSystem::Events)
+                            .after(NetworkSystem::Update),
+                    )
+                    .with_system(malformed_messages.after(NetworkSystem::Receive)),
+            );
     }
 }
 
 pub struct Server {
     transport: Box<dyn ServerTransport>,
+    messages: Arc<MessageRegistry>,
     players: HashMap<NetId, ServerPlayer>,
     id_counter: NetId,
 }
 
 impl Server {
-    pub fn new(transport: Transport, addr: Option<SocketAddr>) -> Self {
+    pub fn new(transport: Transport, addr: Option<SocketAddr>, messages: &NetworkMessages) -> Self {
         Self {
             transport: transport.server(addr),
+            messages: messages.server.clone(),
             players: HashMap::default(),
             id_counter: u8::MAX,
         }
     }
 
-    pub fn generate_id(&mut self) -> NetId {
+    /// An id for an entity the server spawns. These count down from the top,
+    /// clear of client ids counting up from zero, until the two would meet.
+    pub fn generate_id(&mut self) -> Result<NetId, IdsExhausted> {
         let id = self.id_counter;
-        self.id_counter -= 1;
-        id
+        if id == 0 || self.players.keys().any(|client_id| *client_id >= id) {
+            return Err(IdsExhausted);
+        }
+        self.id_counter = id - 1;
+        Ok(id)
     }
 
-    pub fn send(&mut self, client_id: u8, packet: ServerPacket, delivery: DeliveryMethod) {
-        let bytes = bincode::serialize(&packet).unwrap();
-        self.transport.send(client_id, bytes, delivery);
+    /// The next id [`generate_id`](Self::generate_id) hands out.
+    pub fn next_id(&self) -> NetId {
+        self.id_counter
+    }
+
+    /// Carries on generating ids from where another server left off.
+    pub fn set_next_id(&mut self, id: NetId) {
+        self.id_counter = id;
+    }
+
+    pub fn player_ids(&self) -> impl Iterator<Item = NetId> + '_ {
+        self.players.keys().copied()
+    }
+
+    pub fn disconnect(&mut self, client_id: NetId) {
+        self.transport.disconnect(client_id);
+    }
+
+    pub fn room_code(&self) -> Option<RoomCode> {
+        self.transport.room_code()
+    }
+
+    /// Keeps ids for the clients that had them, for whoever reconnects with
+    /// the matching token. Replaces any reserved before.
+    pub fn reserve_ids(&mut self, ids: &[(NetId, ReconnectToken)]) {
+        self.transport.reserve(ids);
     }
 
-    pub fn send_to_all(&mut self, packet: ServerPacket, delivery: DeliveryMethod) {
-        let bytes = bincode::serialize(&packet).unwrap();
+    pub fn peer_addr(&self, client_id: NetId) -> Option<SocketAddr> {
+        self.transport.peer_addr(client_id)
+    }
+
+    pub fn send_message<T: NetworkMessage>(&mut self, message: &T) {
+        let (bytes, delivery) = self.messages.encode(message);
         self.transport.send_to_all(bytes, delivery);
     }
 
-    pub fn send_to_all_except(
-        &mut self,
-        client_id: NetId,
-        packet: ServerPacket,
-        delivery: DeliveryMethod,
-    ) {
-        let bytes = bincode::serialize(&packet).unwrap();
+    pub fn send_message_to<T: NetworkMessage>(&mut self, client_id: NetId, message: &T) {
+        let (bytes, delivery) = self.messages.encode(message);
+        self.transport.send(client_id, bytes, delivery);
+    }
+
+    pub fn send_message_to_all_except<T: NetworkMessage>(&mut self, client_id: NetId, message: &T) {
+        let (bytes, delivery) = self.messages.encode(message);
         self.transport
             .send_to_all_except(client_id, bytes, delivery);
     }
 }
 
-fn server_run_criteria(server: Option<Res<Server>>) -> ShouldRun {
+pub(super) fn server_run_criteria(server: Option<Res<Server>>) -> ShouldRun {
     if server.is_some() {
         ShouldRun::Yes
     } else {
@@ -73,6 +116,27 @@ fn server_run_criteria(server: Option<Res<Server>>) -> ShouldRun {
     }
 }
 
+/// How many undecodable messages a client may send before it's disconnected.
+/// `None` only logs them.
+pub struct MalformedMessagePolicy {
+    pub disconnect_after: Option<u32>,
+}
+
+impl Default for MalformedMessagePolicy {
+    fn default() -> Self {
+        Self {
+            disconnect_after: Some(10),
+        }
+    }
+}
+
+#[derive(Default)]
+pub(super) struct ServerInbox {
+    /// Received message payloads and their senders, indexed by message id.
+    queues: Vec<Vec<(NetId, Bytes)>>,
+    malformed: Vec<(NetId, String)>,
+}
+
 fn update(mut server: ResMut<Server>, mut server_evw: EventWriter<ServerTransportEvent>) {
     server.transport.poll();
     server.transport.receive(&mut server_evw);
@@ -82,12 +146,16 @@ fn events(
     mut server_transport_evr: EventReader<ServerTransportEvent>,
     mut server_evw: EventWriter<ServerEvent>,
     mut server: ResMut<Server>,
+    mut inbox: ResMut<ServerInbox>,
+    messages: Res<NetworkMessages>,
 ) {
+    inbox.queues.resize_with(messages.client.len(), Vec::new);
+
     for event in server_transport_evr.iter() {
         match event {
             ServerTransportEvent::Connected(id) => {
                 println!("[S] Connected({:?})", id);
-                server.players.insert(*id, ServerPlayer);
+                server.players.insert(*id, ServerPlayer::default());
                 server_evw.send(ServerEvent::PlayerConnected(*id));
             }
             ServerTransportEvent::Disconnected(id) => {
@@ -96,48 +164,87 @@ fn events(
                 server_evw.send(ServerEvent::PlayerDisconnected(*id));
             }
             ServerTransportEvent::Message(id, bytes) => {
-                let packet: ClientPacket = bincode::deserialize(bytes).unwrap();
-                println!("[S] {:?}", packet);
-                match packet {
-                    ClientPacket::Ready => {
-                        server_evw.send(ServerEvent::PlayerReady(*id));
-                    }
-                    ClientPacket::Input(input) => {
-                        server_evw.send(ServerEvent::PlayerInput(*id, input));
+                match bytes.first().map(|message_id| *message_id as usize) {
+                    Some(message_id) if message_id < inbox.queues.len() => {
+                        inbox.queues[message_id].push((*id, bytes.slice(1..)));
                     }
+                    Some(message_id) => inbox
+                        .malformed
+                        .push((*id, format!("unknown id {}", message_id))),
+                    None => inbox.malformed.push((*id, "empty".into())),
                 }
             }
         }
     }
 }
 
-pub struct ServerPlayer;
+pub(super) fn receive_message<T: NetworkMessage, F: MessageFormat<T>>(
+    mut inbox: ResMut<ServerInbox>,
+    mut message_evw: EventWriter<FromClient<T>>,
+    messages: Res<NetworkMessages>,
+) {
+    let message_id = messages.client.id::<T>() as usize;
+    let queue = match inbox.queues.get_mut(message_id) {
+        Some(queue) => std::mem::take(queue),
+        None => return,
+    };
+
+    for (id, bytes) in queue {
+        match F::read(&bytes) {
+            Ok(message) => {
+                println!("[S] {:?}", message);
+                message_evw.send(FromClient { id, message });
+            }
+            Err(err) => inbox.malformed.push((id, err.to_string())),
+        }
+    }
+}
+
+fn malformed_messages(
+    mut inbox: ResMut<ServerInbox>,
+    mut server: ResMut<Server>,
+    policy: Res<MalformedMessagePolicy>,
+) {
+    for (id, reason) in inbox.malformed.drain(..) {
+        let count = match server.players.get_mut(&id) {
+            Some(player) => {
+                player.malformed_messages += 1;
+                player.malformed_messages
+            }
+            None => continue,
+        };
 
-#[derive(Debug, Serialize, Deserialize)]
-pub enum ServerPacket {
-    PlayerConnected(NetId),
-    PlayerDisconnected(NetId),
-    State(Box<[Spawn]>),
-    Snapshot(Snapshot),
-    SpawnObstacle(NetId, Vec3),
+        println!(
+            "[S] Malformed message from {} ({} so far): {}",
+            id, count, reason
+        );
+
+        if matches!(policy.disconnect_after, Some(max) if count >= max) {
+            println!("[S] Disconnecting {}", id);
+            server.disconnect(id);
+        }
+    }
 }
 
-#[derive(Debug)]
-pub enum ServerEvent {
-    PlayerConnected(NetId),
-    PlayerDisconnected(NetId),
-    PlayerReady(NetId),
-    PlayerInput(NetId, Vec2),
+/// Every id [`Server::generate_id`] could hand out is taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdsExhausted;
+
+impl std::fmt::Display for IdsExhausted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("out of entity ids")
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Snapshot {
-    pub sequence: u32,
-    pub transforms: Box<[(NetId, NetworkTransform)]>,
+impl std::error::Error for IdsExhausted {}
+
+#[derive(Default)]
+pub struct ServerPlayer {
+    malformed_messages: u32,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct NetworkTransform {
-    pub position: Vec3,
-    pub rotation: Quat,
+#[derive(Debug)]
+pub enum ServerEvent {
+    PlayerConnected(NetId),
+    PlayerDisconnected(NetId),
 }
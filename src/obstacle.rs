@@ -1,17 +1,29 @@
 use bevy::prelude::*;
+use bitpack::NetSerialize;
 use physics::prelude::*;
 
-use crate::run_criteria::game_server_run_criteria;
+use crate::{
+    network::TICK_SECONDS,
+    run_criteria::{game_server_run_criteria, RoleSystem},
+    snapshot::ReplicateAppExt,
+    tick::{TickAppExt, TickStage},
+};
 
 pub struct ObstaclePlugin;
 
 impl Plugin for ObstaclePlugin {
     fn build(&self, app: &mut App) {
-        app.add_system_set(
-            SystemSet::new()
-                .with_run_criteria(game_server_run_criteria)
-                .with_system(move_obstacle),
-        );
+        // The ends of the path change every few seconds, the timer every tick.
+        app.replicate::<LerpStart>()
+            .replicate::<LerpTarget>()
+            .replicate_state::<LerpTimer>()
+            .add_tick_system_set(
+                TickStage::Simulate,
+                SystemSet::new()
+                    .with_run_criteria(game_server_run_criteria)
+                    .label(RoleSystem::Server)
+                    .with_system(move_obstacle),
+            );
     }
 }
 
@@ -42,20 +54,19 @@ impl ObstacleBundle {
 #[derive(Component)]
 struct Obstacle;
 
-#[derive(Component)]
+#[derive(Clone, Component, NetSerialize)]
 struct LerpStart(Vec3);
 
-#[derive(Component)]
+#[derive(Clone, Component, NetSerialize)]
 struct LerpTarget(Vec3);
 
-#[derive(Component)]
+#[derive(Clone, Component, NetSerialize)]
 struct LerpTimer {
     duration: f32,
     timer: f32,
 }
 
 fn move_obstacle(
-    time: Res<Time>,
     mut q: Query<
         (
             &mut RigidBodyPositionComponent,
@@ -67,7 +78,7 @@ fn move_obstacle(
     >,
 ) {
     for (mut rb_pos, mut lerp_start, mut lerp_target, mut lerp_timer) in q.iter_mut() {
-        lerp_timer.timer += time.delta_seconds();
+        lerp_timer.timer += TICK_SECONDS;
 
         if lerp_timer.timer > lerp_timer.duration {
             lerp_timer.timer -= lerp_timer.duration;
@@ -1,76 +1,236 @@
-use std::f32::consts::PI;
+use std::{collections::VecDeque, f32::consts::PI};
 
 use bevy::prelude::*;
 
 use bevy_extensions::*;
+use bitpack::NetSerialize;
 use physics::prelude::*;
 use transport::DeliveryMethod;
 
 use crate::{
-    network::{Client, ClientPacket, NetworkId, ServerEvent},
-    run_criteria::game_server_run_criteria,
-    AppState,
+    config::Config,
+    network::{
+        Client, FromClient, NetId, NetworkEntityMap, NetworkId, NetworkMessageAppExt, Server,
+        TICK_SECONDS,
+    },
+    run_criteria::{game_client_run_criteria, game_server_run_criteria, RoleSystem},
+    snapshot::{SnapshotAcks, POSITION_PRECISION, WORLD_MAX, WORLD_MIN},
+    tick::{TickAppExt, TickStage},
 };
 
 pub struct PlayerPlugin;
 
 impl Plugin for PlayerPlugin {
     fn build(&self, app: &mut App) {
-        app.add_system_set(SystemSet::on_update(AppState::Game).with_system(send_input))
+        app.init_resource::<LocalInput>()
+            .add_event::<InputBufferEvent>()
+            .add_packed_client_message::<PlayerInput>(DeliveryMethod::UnreliableSequenced)
+            .add_packed_server_message::<InputAck>(DeliveryMethod::UnreliableSequenced)
+            .add_system_set(
+                SystemSet::new()
+                    .with_run_criteria(game_client_run_criteria)
+                    .label(RoleSystem::Client)
+                    .with_system(read_input.label(PlayerSystem::ReadInput))
+                    .with_system(
+                        send_input
+                            .label(PlayerSystem::SendInput)
+                            .after(PlayerSystem::ReadInput),
+                    ),
+            )
             .add_system_set(
                 SystemSet::new()
                     .with_run_criteria(game_server_run_criteria)
+                    .label(RoleSystem::Server)
                     .with_system(server_player_setup)
-                    .with_system(server_input_event)
-                    .with_system(server_move_players)
-                    .with_system(server_rotate_players),
+                    .with_system(server_input_event),
+            )
+            .add_tick_system_set(
+                TickStage::Simulate,
+                SystemSet::new()
+                    .with_run_criteria(game_server_run_criteria)
+                    .label(RoleSystem::Server)
+                    .with_system(server_consume_input.label(PlayerSystem::ConsumeInput))
+                    .with_system(server_move_players.after(PlayerSystem::ConsumeInput))
+                    .with_system(server_rotate_players.after(PlayerSystem::ConsumeInput)),
             )
             .add_system_to_stage(CoreStage::PostUpdate, sync_position);
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemLabel)]
+pub enum PlayerSystem {
+    ReadInput,
+    SendInput,
+    ConsumeInput,
+}
+
 #[derive(Component)]
 pub struct Player;
 
 #[derive(Component)]
 pub struct LocalPlayer;
 
+/// The input for one client tick.
+#[derive(Debug, Clone, Copy, NetSerialize)]
+pub struct TickInput {
+    #[net(float(min = -1.0, max = 1.0, precision = 0.01))]
+    pub direction: Vec2,
+}
+
+/// The last few ticks of movement input, oldest first and ending at `tick`, so
+/// that a lost packet is covered by the next. The snapshot acks are
+/// piggybacked on it.
+#[derive(Debug, NetSerialize)]
+pub struct PlayerInput {
+    pub tick: u32,
+    pub inputs: Box<[TickInput]>,
+    pub acks: SnapshotAcks,
+}
+
+/// The state of a client's player once the server has simulated its input for
+/// `tick`.
+#[derive(Debug, NetSerialize)]
+pub struct InputAck {
+    pub tick: u32,
+    #[net(float(min = WORLD_MIN, max = WORLD_MAX, precision = POSITION_PRECISION))]
+    pub position: Vec3,
+    #[net(float(min = -16.0, max = 16.0, precision = 0.001))]
+    pub velocity: Vec3,
+    pub rotation: Quat,
+}
+
+/// Problems keeping a player's input buffer fed.
+#[derive(Debug)]
+pub enum InputBufferEvent {
+    /// There was no input for a tick, so the last one was repeated.
+    Starved(NetId),
+    /// Input arrived faster than it was used, and the oldest was dropped.
+    Overflowed { client_id: NetId, dropped: usize },
+}
+
+const MAX_SPEED: f32 = 10.0;
+const MAX_ACCELERATION: f32 = 100.0;
+const TURN_SPEED: f32 = 10.0;
+
+/// Accelerates `velocity` towards `input`. Shared by the server and client
+/// prediction so both move players the same way.
+pub fn move_player(velocity: &mut Vec3, input: Vec3, delta_seconds: f32) {
+    let max_delta = MAX_ACCELERATION * delta_seconds;
+    let target = input * MAX_SPEED;
+
+    velocity.x.move_towards(target.x, max_delta);
+    velocity.z.move_towards(target.z, max_delta);
+}
+
+/// Turns `transform` towards `input`, see [`move_player`].
+pub fn rotate_player(transform: &mut Transform, input: Vec3, delta_seconds: f32) {
+    if input == Vec3::ZERO {
+        return;
+    }
+
+    let dir = f32::atan2(-input.x, -input.z);
+    let forward = f32::atan2(-transform.forward().x, -transform.forward().z);
+    let mut angle = dir - forward;
+
+    if angle < -PI {
+        angle += PI * 2.0;
+    } else if angle > PI {
+        angle -= PI * 2.0;
+    }
+
+    transform.rotate(Quat::from_rotation_y(angle * TURN_SPEED * delta_seconds));
+}
+
 //
 // Client
 //
 
-const CLIENT_SEND_RATE: f32 = 1.0 / 20.0;
+/// Ticks of input resent in every packet.
+const INPUT_REDUNDANCY: usize = 12;
+
+/// Input sampled at the tick rate.
+#[derive(Default)]
+pub struct LocalInput {
+    /// The direction being held this frame.
+    pub direction: Vec2,
+    tick: u32,
+    timer: f32,
+    recent: VecDeque<(u32, Vec2)>,
+    new: usize,
+}
+
+impl LocalInput {
+    /// The ticks that passed this frame, with their input.
+    pub fn new_ticks(&self) -> impl Iterator<Item = (u32, Vec3)> + '_ {
+        self.recent
+            .iter()
+            .skip(self.recent.len() - self.new)
+            .map(|(tick, direction)| (*tick, Vec3::new(direction.x, 0.0, direction.y)))
+    }
+}
+
+fn read_input(time: Res<Time>, keyboard: Res<Input<KeyCode>>, mut input: ResMut<LocalInput>) {
+    let mut direction = Vec2::ZERO;
+
+    if keyboard.pressed(KeyCode::Left) {
+        direction.x -= 1.0;
+    }
+    if keyboard.pressed(KeyCode::Right) {
+        direction.x += 1.0;
+    }
+    if keyboard.pressed(KeyCode::Up) {
+        direction.y -= 1.0;
+    }
+    if keyboard.pressed(KeyCode::Down) {
+        direction.y += 1.0;
+    }
+
+    let input = &mut *input;
+    input.direction = direction.normalize_or_zero();
+    input.timer += time.delta_seconds();
+    input.new = 0;
+
+    while input.timer >= TICK_SECONDS {
+        input.timer -= TICK_SECONDS;
+        input.tick = input.tick.wrapping_add(1);
+
+        if input.recent.len() == INPUT_REDUNDANCY {
+            input.recent.pop_front();
+        }
+        input.recent.push_back((input.tick, input.direction));
+        input.new = (input.new + 1).min(INPUT_REDUNDANCY);
+    }
+}
 
 fn send_input(
     mut send_rate_timer: Local<f32>,
     time: Res<Time>,
-    keyboard: Res<Input<KeyCode>>,
+    config: Res<Config>,
+    acks: Res<SnapshotAcks>,
+    input: Res<LocalInput>,
     mut client: ResMut<Client>,
 ) {
     *send_rate_timer += time.delta_seconds();
 
-    if *send_rate_timer > CLIENT_SEND_RATE {
-        *send_rate_timer -= CLIENT_SEND_RATE;
-
-        let mut input = Vec2::ZERO;
+    let send_interval = config.input_interval();
+    if *send_rate_timer > send_interval {
+        *send_rate_timer -= send_interval;
 
-        if keyboard.pressed(KeyCode::Left) {
-            input.x -= 1.0;
-        }
-        if keyboard.pressed(KeyCode::Right) {
-            input.x += 1.0;
-        }
-        if keyboard.pressed(KeyCode::Up) {
-            input.y -= 1.0;
-        }
-        if keyboard.pressed(KeyCode::Down) {
-            input.y += 1.0;
-        }
-
-        client.send(
-            ClientPacket::Input(input.normalize_or_zero()),
-            DeliveryMethod::UnreliableSequenced,
-        );
+        let tick = match input.recent.back() {
+            Some((tick, _)) => *tick,
+            None => return,
+        };
+        client.send_message(&PlayerInput {
+            tick,
+            inputs: input
+                .recent
+                .iter()
+                .map(|(_, direction)| TickInput {
+                    direction: *direction,
+                })
+                .collect(),
+            acks: *acks,
+        });
     }
 }
 
@@ -78,72 +238,156 @@ fn send_input(
 // Server
 //
 
+/// Ticks of input a player may have queued up before the oldest are dropped.
+const INPUT_BUFFER_CAPACITY: usize = 12;
+
+#[derive(Default, Component)]
+struct CurrentInput {
+    direction: Vec3,
+    tick: Option<u32>,
+}
+
+/// Input received from a player's client, waiting for its tick.
 #[derive(Default, Component)]
-struct CurrentInput(Vec3);
+struct InputBuffer {
+    inputs: VecDeque<(u32, Vec3)>,
+    last_received: Option<u32>,
+    starved: bool,
+    /// Whether the buffer overflowed and hasn't had room since.
+    overflowed: bool,
+}
 
 fn server_player_setup(player_added_q: Query<Entity, Added<Player>>, mut commands: Commands) {
     for entity in player_added_q.iter() {
-        commands.entity(entity).insert(CurrentInput::default());
+        commands
+            .entity(entity)
+            .insert(CurrentInput::default())
+            .insert(InputBuffer::default());
     }
 }
 
 fn server_input_event(
-    mut server_evr: EventReader<ServerEvent>,
-    mut input_q: Query<(&mut CurrentInput, &NetworkId), With<Player>>,
+    mut input_evr: EventReader<FromClient<PlayerInput>>,
+    mut buffer_evw: EventWriter<InputBufferEvent>,
+    mut buffer_q: Query<&mut InputBuffer, With<Player>>,
+    entity_map: Res<NetworkEntityMap>,
 ) {
-    for event in server_evr.iter() {
-        match event {
-            ServerEvent::PlayerInput(player_id, input) => {
-                for (mut current_input, id) in input_q.iter_mut() {
-                    if *player_id == id.value() {
-                        current_input.0 = Vec3::new(input.x, 0.0, input.y);
-                        return;
+    for FromClient { id, message } in input_evr.iter() {
+        let player = entity_map.entity(*id);
+        let mut buffer = match player.and_then(|entity| buffer_q.get_mut(entity).ok()) {
+            Some(buffer) => buffer,
+            None => continue,
+        };
+
+        let first_tick = message
+            .tick
+            .wrapping_sub(message.inputs.len().saturating_sub(1) as u32);
+        for (i, input) in message.inputs.iter().enumerate() {
+            let tick = first_tick.wrapping_add(i as u32);
+            // Ticks wrap, so compare by distance rather than magnitude.
+            let is_new = buffer
+                .last_received
+                .map_or(true, |last| (tick.wrapping_sub(last) as i32) > 0);
+            if !is_new {
+                continue;
+            }
+
+            let direction = Vec3::new(input.direction.x, 0.0, input.direction.y);
+            buffer.inputs.push_back((tick, direction));
+            buffer.last_received = Some(tick);
+        }
+
+        let dropped = buffer.inputs.len().saturating_sub(INPUT_BUFFER_CAPACITY);
+        if dropped > 0 {
+            buffer.inputs.drain(..dropped);
+            if !buffer.overflowed {
+                println!("[S] Input buffer of {} overflowed", id);
+            }
+            buffer.overflowed = true;
+            buffer_evw.send(InputBufferEvent::Overflowed {
+                client_id: *id,
+                dropped,
+            });
+        } else if buffer.inputs.len() < INPUT_BUFFER_CAPACITY {
+            buffer.overflowed = false;
+        }
+    }
+}
+
+/// Moves every player on to their next tick of input, and acks the one just
+/// simulated.
+fn server_consume_input(
+    mut buffer_evw: EventWriter<InputBufferEvent>,
+    mut player_q: Query<
+        (
+            &NetworkId,
+            &mut CurrentInput,
+            &mut InputBuffer,
+            &Transform,
+            &RigidBodyPositionComponent,
+            &RigidBodyVelocityComponent,
+        ),
+        With<Player>,
+    >,
+    client: Option<Res<Client>>,
+    mut server: ResMut<Server>,
+) {
+    let host = client.map(|client| client.get_id());
+
+    for (net_id, mut current_input, mut buffer, transform, position, velocity) in
+        player_q.iter_mut()
+    {
+        let id = net_id.value();
+
+        if let Some(tick) = current_input.tick {
+            if host != Some(id) {
+                server.send_message_to(
+                    id,
+                    &InputAck {
+                        tick,
+                        position: position.position.translation.vector.into(),
+                        velocity: velocity.linvel.into(),
+                        rotation: transform.rotation,
+                    },
+                );
+            }
+        }
+
+        match buffer.inputs.pop_front() {
+            Some((tick, direction)) => {
+                current_input.direction = direction;
+                current_input.tick = Some(tick);
+                buffer.starved = false;
+            }
+            None => {
+                // Keep going the same way, the client will be corrected if
+                // it did something else.
+                current_input.tick = None;
+                if buffer.last_received.is_some() {
+                    if !buffer.starved {
+                        println!("[S] Input buffer of {} starved", id);
                     }
+                    buffer.starved = true;
+                    buffer_evw.send(InputBufferEvent::Starved(id));
                 }
             }
-            _ => {}
-        };
+        }
     }
 }
 
 fn server_move_players(
     mut player_q: Query<(&mut RigidBodyVelocityComponent, &CurrentInput), With<Player>>,
-    time: Res<Time>,
 ) {
     for (mut velocity, input) in player_q.iter_mut() {
-        let max_speed = 10.0;
-        let max_acc = 100.0;
-        let max_delta = max_acc * time.delta_seconds();
-        let target = input.0 * max_speed;
-
         let mut vel: Vec3 = velocity.linvel.into();
-        vel.x.move_towards(target.x, max_delta);
-        vel.z.move_towards(target.z, max_delta);
-
+        move_player(&mut vel, input.direction, TICK_SECONDS);
         velocity.linvel = vel.into();
     }
 }
 
-fn server_rotate_players(
-    mut player_q: Query<(&mut Transform, &CurrentInput), With<Player>>,
-    time: Res<Time>,
-) {
+fn server_rotate_players(mut player_q: Query<(&mut Transform, &CurrentInput), With<Player>>) {
     for (mut transform, input) in player_q.iter_mut() {
-        if input.0 == Vec3::ZERO {
-            continue;
-        }
-
-        let dir = f32::atan2(-input.0.x, -input.0.z);
-        let forward = f32::atan2(-transform.forward().x, -transform.forward().z);
-        let mut angle = dir - forward;
-
-        if angle < -PI {
-            angle += PI * 2.0;
-        } else if angle > PI {
-            angle -= PI * 2.0;
-        }
-
-        transform.rotate(Quat::from_rotation_y(angle * 10.0 * time.delta_seconds()));
+        rotate_player(&mut transform, input.direction, TICK_SECONDS);
     }
 }
 
@@ -0,0 +1,146 @@
+use std::collections::VecDeque;
+
+use bevy::prelude::*;
+
+use crate::{
+    network::{FromServer, TICK_SECONDS},
+    player::{move_player, rotate_player, InputAck, LocalInput, LocalPlayer, PlayerSystem},
+    run_criteria::{game_client_exclusive_run_criteria, RoleSystem},
+};
+
+pub struct PredictionPlugin;
+
+impl Plugin for PredictionPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_set(
+            SystemSet::new()
+                .with_run_criteria(game_client_exclusive_run_criteria)
+                .label(RoleSystem::ClientExclusive)
+                .with_system(prediction_setup)
+                .with_system(reconcile.label(PredictionSystem::Reconcile))
+                .with_system(
+                    predict
+                        .label(PredictionSystem::Predict)
+                        .after(PlayerSystem::ReadInput)
+                        .after(PredictionSystem::Reconcile),
+                ),
+        );
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemLabel)]
+pub enum PredictionSystem {
+    Reconcile,
+    Predict,
+}
+
+/// How far the prediction may drift from the server before it is corrected.
+const POSITION_TOLERANCE: f32 = 0.05;
+const VELOCITY_TOLERANCE: f32 = 0.1;
+
+/// Ticks kept for replaying. Anything older than this is never going to be
+/// acked.
+const MAX_HISTORY: usize = 256;
+
+/// The local player's movement, simulated ahead of the server.
+#[derive(Default, Component)]
+pub struct Prediction {
+    velocity: Vec3,
+    history: VecDeque<PredictedTick>,
+}
+
+/// One tick of predicted movement and where it ended up.
+struct PredictedTick {
+    tick: u32,
+    direction: Vec3,
+    position: Vec3,
+    velocity: Vec3,
+}
+
+fn prediction_setup(local_player_q: Query<Entity, Added<LocalPlayer>>, mut commands: Commands) {
+    for entity in local_player_q.iter() {
+        commands.entity(entity).insert(Prediction::default());
+    }
+}
+
+fn predict(
+    input: Res<LocalInput>,
+    mut local_player_q: Query<(&mut Transform, &mut Prediction), With<LocalPlayer>>,
+) {
+    for (mut transform, mut prediction) in local_player_q.iter_mut() {
+        for (tick, direction) in input.new_ticks() {
+            let mut velocity = prediction.velocity;
+            step(&mut transform, &mut velocity, direction);
+            prediction.velocity = velocity;
+
+            if prediction.history.len() == MAX_HISTORY {
+                prediction.history.pop_front();
+            }
+            prediction.history.push_back(PredictedTick {
+                tick,
+                direction,
+                position: transform.translation,
+                velocity,
+            });
+        }
+    }
+}
+
+/// Checks acked input against what was predicted for it. On a mismatch, starts
+/// over from the server's state and replays the input it hasn't processed yet.
+fn reconcile(
+    mut ack_evr: EventReader<FromServer<InputAck>>,
+    mut local_player_q: Query<(&mut Transform, &mut Prediction), With<LocalPlayer>>,
+) {
+    let ack = match ack_evr.iter().last() {
+        Some(FromServer(ack)) => ack,
+        None => return,
+    };
+
+    for (mut transform, mut prediction) in local_player_q.iter_mut() {
+        let predicted = prediction
+            .history
+            .iter()
+            .rev()
+            .find(|predicted| predicted.tick == ack.tick);
+        let mismatch = match predicted {
+            Some(predicted) => {
+                predicted.position.distance(ack.position) > POSITION_TOLERANCE
+                    || predicted.velocity.distance(ack.velocity) > VELOCITY_TOLERANCE
+            }
+            None => true,
+        };
+
+        // Ticks wrap, so compare by distance rather than magnitude.
+        prediction
+            .history
+            .retain(|predicted| (predicted.tick.wrapping_sub(ack.tick) as i32) > 0);
+
+        if !mismatch {
+            continue;
+        }
+
+        let mut replayed = Transform {
+            translation: ack.position,
+            rotation: ack.rotation,
+            ..*transform
+        };
+        let mut velocity = ack.velocity;
+        for predicted in prediction.history.iter_mut() {
+            step(&mut replayed, &mut velocity, predicted.direction);
+            predicted.position = replayed.translation;
+            predicted.velocity = velocity;
+        }
+
+        transform.translation = replayed.translation;
+        transform.rotation = replayed.rotation;
+        prediction.velocity = velocity;
+    }
+}
+
+/// The server's movement for one tick, without the physics.
+fn step(transform: &mut Transform, velocity: &mut Vec3, direction: Vec3) {
+    move_player(velocity, direction, TICK_SECONDS);
+    rotate_player(transform, direction, TICK_SECONDS);
+    transform.translation += *velocity * TICK_SECONDS;
+}
@@ -0,0 +1,247 @@
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+use bitpack::NetSerialize;
+use transport::DeliveryMethod;
+
+use crate::{
+    network::*,
+    player::Player,
+    run_criteria::{game_client_exclusive_run_criteria, game_server_run_criteria, RoleSystem},
+    snapshot::{ReceivedComponents, SnapshotSystem},
+    spawn::{Despawn, EntityState, EntityStates, Spawn},
+    tick::{TickAppExt, TickStage},
+    AppState,
+};
+
+pub struct RelevancyPlugin;
+
+impl Plugin for RelevancyPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Relevancy>()
+            .init_resource::<RelevantEntities>()
+            .add_packed_server_message::<RelevancyUpdate>(DeliveryMethod::ReliableOrdered)
+            .add_system_set(
+                SystemSet::on_enter(AppState::Game).with_system(clear_relevant_entities),
+            )
+            // Entities entering relevancy carry their replicated components,
+            // so this runs once the server has collected them for the tick.
+            .add_tick_system_set(
+                TickStage::Send,
+                SystemSet::new()
+                    .with_run_criteria(game_server_run_criteria)
+                    .after(RoleSystem::Server)
+                    .with_system(
+                        update_relevancy
+                            .label(RelevancySystem)
+                            .before(SnapshotSystem::Send),
+                    ),
+            )
+            .add_system_set(
+                SystemSet::new()
+                    .with_run_criteria(game_server_run_criteria)
+                    .label(RoleSystem::Server)
+                    .with_system(remove_disconnected),
+            )
+            .add_system_set(
+                SystemSet::new()
+                    .with_run_criteria(game_client_exclusive_run_criteria)
+                    .label(RoleSystem::ClientExclusive)
+                    .with_system(on_relevancy_update),
+            );
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemLabel)]
+pub struct RelevancySystem;
+
+/// Decides which networked entities each client is told about. A client always
+/// sees its own player, and sees everything while it has no player to measure
+/// from.
+pub enum Relevancy {
+    All,
+    /// Entities within `radius` of the client's player.
+    Distance {
+        radius: f32,
+    },
+    /// Entities in the client's grid cell on the XZ plane, or at most `range`
+    /// cells away from it in either direction.
+    Grid {
+        cell_size: f32,
+        range: i32,
+    },
+    Custom(Box<dyn Fn(&RelevancyQuery) -> bool + Send + Sync>),
+}
+
+impl Default for Relevancy {
+    fn default() -> Self {
+        Relevancy::All
+    }
+}
+
+pub struct RelevancyQuery {
+    pub client_id: NetId,
+    /// Position of the client's player, if it has one.
+    pub viewer: Option<Vec3>,
+    pub entity_id: NetId,
+    pub position: Vec3,
+}
+
+impl Relevancy {
+    pub fn is_relevant(&self, query: &RelevancyQuery) -> bool {
+        if query.entity_id == query.client_id {
+            return true;
+        }
+
+        match self {
+            Relevancy::All => true,
+            Relevancy::Distance { radius } => match query.viewer {
+                Some(viewer) => viewer.distance_squared(query.position) <= radius * radius,
+                None => true,
+            },
+            Relevancy::Grid { cell_size, range } => match query.viewer {
+                Some(viewer) => {
+                    let cell = |position: Vec3| (position / *cell_size).floor();
+                    let offset = (cell(query.position) - cell(viewer)).abs();
+                    offset.x <= *range as f32 && offset.z <= *range as f32
+                }
+                None => true,
+            },
+            Relevancy::Custom(predicate) => predicate(query),
+        }
+    }
+}
+
+/// Entities each ready client currently knows about. Clients that aren't in
+/// here yet get no entities and no snapshots.
+#[derive(Default)]
+pub struct RelevantEntities(HashMap<NetId, HashSet<NetId>>);
+
+impl RelevantEntities {
+    pub fn get(&self, client_id: NetId) -> Option<&HashSet<NetId>> {
+        self.0.get(&client_id)
+    }
+
+    /// Starts sending entities to `client_id`, beginning with none.
+    pub fn track(&mut self, client_id: NetId) {
+        self.0.entry(client_id).or_default();
+    }
+}
+
+/// Entities that came into or went out of relevancy for the receiving client.
+/// Entered entities come with their full current state, so a client seeing one
+/// for the first time sees it just as everyone else does.
+#[derive(Debug, NetSerialize)]
+pub struct RelevancyUpdate {
+    pub entered: Box<[EntityState]>,
+    pub left: Box<[NetId]>,
+}
+
+//
+// Server
+//
+
+fn clear_relevant_entities(mut relevant: ResMut<RelevantEntities>) {
+    relevant.0.clear();
+}
+
+fn update_relevancy(
+    relevancy: Res<Relevancy>,
+    mut relevant: ResMut<RelevantEntities>,
+    mut server: ResMut<Server>,
+    entity_q: Query<(Entity, &NetworkId, &Transform)>,
+    player_q: Query<&Transform, With<Player>>,
+    states: EntityStates,
+    entity_map: Res<NetworkEntityMap>,
+) {
+    let RelevantEntities(relevant) = &mut *relevant;
+
+    for (client_id, known) in relevant.iter_mut() {
+        let viewer = entity_map
+            .entity(*client_id)
+            .and_then(|entity| player_q.get(entity).ok())
+            .map(|transform| transform.translation);
+
+        let mut entered = Vec::new();
+        let mut now_relevant = HashSet::default();
+        for (entity, net_id, transform) in entity_q.iter() {
+            let query = RelevancyQuery {
+                client_id: *client_id,
+                viewer,
+                entity_id: net_id.value(),
+                position: transform.translation,
+            };
+            if !relevancy.is_relevant(&query) {
+                continue;
+            }
+
+            now_relevant.insert(net_id.value());
+            if !known.contains(&net_id.value()) {
+                entered.extend(states.get(entity));
+            }
+        }
+
+        let left = known
+            .iter()
+            .filter(|id| !now_relevant.contains(*id))
+            .copied()
+            .collect::<Vec<_>>();
+
+        if entered.is_empty() && left.is_empty() {
+            continue;
+        }
+
+        *known = now_relevant;
+        server.send_message_to(
+            *client_id,
+            &RelevancyUpdate {
+                entered: entered.into_boxed_slice(),
+                left: left.into_boxed_slice(),
+            },
+        );
+    }
+}
+
+fn remove_disconnected(
+    mut server_evr: EventReader<ServerEvent>,
+    mut relevant: ResMut<RelevantEntities>,
+) {
+    for event in server_evr.iter() {
+        if let ServerEvent::PlayerDisconnected(id) = event {
+            relevant.0.remove(id);
+        }
+    }
+}
+
+//
+// Client
+//
+
+fn on_relevancy_update(
+    mut update_evr: EventReader<FromServer<RelevancyUpdate>>,
+    mut commands: Commands,
+    mut received: ResMut<ReceivedComponents>,
+    spawn_q: Query<(Entity, &Spawn)>,
+    entity_map: Res<NetworkEntityMap>,
+) {
+    for FromServer(update) in update_evr.iter() {
+        for id in update.left.iter() {
+            // The entity may still be waiting on `spawn_event`.
+            for (entity, spawn) in spawn_q.iter() {
+                if spawn.id == *id {
+                    commands.entity(entity).despawn();
+                }
+            }
+            if let Some(entity) = entity_map.entity(*id) {
+                commands.entity(entity).insert(Despawn);
+            }
+        }
+
+        for state in update.entered.iter() {
+            commands.spawn().insert(state.spawn);
+            // Picked up once the entity is spawned.
+            received.receive(&state.components);
+        }
+    }
+}
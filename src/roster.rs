@@ -0,0 +1,358 @@
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+use serde::{Deserialize, Serialize};
+use transport::DeliveryMethod;
+
+use crate::{
+    config::Config,
+    network::*,
+    run_criteria::{client_exclusive_run_criteria, client_run_criteria, server_run_criteria},
+    AppState,
+};
+
+pub struct RosterPlugin;
+
+impl Plugin for RosterPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<PlayerRoster>()
+            .add_event::<RosterEvent>()
+            .add_client_message::<Hello>(DeliveryMethod::ReliableOrdered)
+            .add_server_message::<RosterUpdate>(DeliveryMethod::ReliableOrdered)
+            .add_system_set(SystemSet::on_enter(AppState::Menu).with_system(clear_roster))
+            .add_system_set(
+                SystemSet::new()
+                    .with_run_criteria(client_run_criteria)
+                    .with_system(send_hello),
+            )
+            .add_system_set(
+                SystemSet::new()
+                    .with_run_criteria(server_run_criteria)
+                    .with_system(
+                        receive_hello
+                            .label(RosterSystem::Receive)
+                            .after(NetworkSystem::Receive),
+                    )
+                    .with_system(remove_disconnected_players)
+                    .with_system(update_pings),
+            )
+            .add_system_set(
+                SystemSet::new()
+                    .with_run_criteria(client_exclusive_run_criteria)
+                    .with_system(
+                        receive_roster_updates
+                            .label(RosterSystem::Receive)
+                            .after(NetworkSystem::Receive),
+                    ),
+            )
+            .add_system_to_stage(CoreStage::PostUpdate, flush_roster_changes);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemLabel)]
+pub enum RosterSystem {
+    /// Entries added from handshakes, or updated from the server.
+    Receive,
+}
+
+const MAX_NAME_LEN: usize = 24;
+
+/// How often the server refreshes everyone's ping, in seconds.
+const PING_UPDATE_INTERVAL: f64 = 1.0;
+
+const PALETTE: [Color; 8] = [
+    Color::rgb(0.9, 0.3, 0.3),
+    Color::rgb(0.3, 0.6, 0.9),
+    Color::rgb(0.4, 0.8, 0.4),
+    Color::rgb(0.95, 0.8, 0.3),
+    Color::rgb(0.7, 0.4, 0.9),
+    Color::rgb(0.95, 0.55, 0.2),
+    Color::rgb(0.3, 0.85, 0.8),
+    Color::rgb(0.9, 0.5, 0.75),
+];
+
+/// Sent by a client as soon as it connects, to join the roster.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Hello {
+    pub name: String,
+    /// Whether to watch instead of getting a player.
+    pub spectator: bool,
+}
+
+/// An entry added or changed, or removed when `entry` is `None`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RosterUpdate {
+    pub id: NetId,
+    pub entry: Option<RosterEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RosterEntry {
+    pub name: String,
+    pub color: Color,
+    /// Server time the player joined at, in seconds.
+    pub join_time: f64,
+    /// Round trip to the server, in milliseconds.
+    pub ping: u16,
+    /// Whether the player has entered the game.
+    pub ready: bool,
+    /// Watching the game without a player entity.
+    pub spectator: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RosterEvent {
+    Joined(NetId),
+    Changed(NetId),
+    Left(NetId),
+}
+
+/// Everyone in the game. The server owns it, clients get a copy.
+#[derive(Default)]
+pub struct PlayerRoster {
+    players: HashMap<NetId, RosterEntry>,
+    /// Connections the server has had a `Hello` from.
+    greeted: HashSet<NetId>,
+    /// Changes since the last flush, to send out and raise events for.
+    changes: Vec<RosterEvent>,
+}
+
+impl PlayerRoster {
+    pub fn get(&self, id: NetId) -> Option<&RosterEntry> {
+        self.players.get(&id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (NetId, &RosterEntry)> + '_ {
+        self.players.iter().map(|(id, entry)| (*id, entry))
+    }
+
+    pub fn len(&self) -> usize {
+        self.players.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.players.is_empty()
+    }
+
+    /// The name to show for `id`, even if it isn't in the roster.
+    pub fn name(&self, id: NetId) -> String {
+        match self.players.get(&id) {
+            Some(entry) => entry.name.clone(),
+            None => format!("Player {}", id),
+        }
+    }
+
+    /// Changes an entry. On the server the change is sent to every client.
+    pub fn modify(&mut self, id: NetId, f: impl FnOnce(&mut RosterEntry)) {
+        if let Some(entry) = self.players.get_mut(&id) {
+            let before = entry.clone();
+            f(entry);
+            if *entry != before {
+                self.push_change(RosterEvent::Changed(id));
+            }
+        }
+    }
+
+    pub(crate) fn insert(&mut self, id: NetId, entry: RosterEntry) {
+        let change = match self.players.insert(id, entry) {
+            Some(_) => RosterEvent::Changed(id),
+            None => RosterEvent::Joined(id),
+        };
+        self.push_change(change);
+    }
+
+    /// Removes an entry. On the server everyone is told the player left.
+    pub fn remove(&mut self, id: NetId) {
+        self.greeted.remove(&id);
+        if self.players.remove(&id).is_some() {
+            self.push_change(RosterEvent::Left(id));
+        }
+    }
+
+    fn push_change(&mut self, change: RosterEvent) {
+        // A pending join or change already sends the latest entry.
+        if let RosterEvent::Changed(id) = change {
+            let pending = self.changes.iter().any(|pending| match pending {
+                RosterEvent::Joined(other) | RosterEvent::Changed(other) => *other == id,
+                RosterEvent::Left(_) => false,
+            });
+            if pending {
+                return;
+            }
+        }
+        self.changes.push(change);
+    }
+
+    fn unused_color(&self, id: NetId) -> Color {
+        PALETTE
+            .iter()
+            .copied()
+            .find(|color| self.players.values().all(|entry| entry.color != *color))
+            .unwrap_or(PALETTE[id as usize % PALETTE.len()])
+    }
+
+    fn clear(&mut self) {
+        self.players.clear();
+        self.greeted.clear();
+        self.changes.clear();
+    }
+}
+
+fn clear_roster(mut roster: ResMut<PlayerRoster>) {
+    roster.clear();
+}
+
+/// Sends out and raises events for everything changed this frame.
+fn flush_roster_changes(
+    mut roster: ResMut<PlayerRoster>,
+    mut roster_evw: EventWriter<RosterEvent>,
+    role: Res<State<NetworkRole>>,
+    mut server: Option<ResMut<Server>>,
+) {
+    let changes = std::mem::take(&mut roster.changes);
+    for change in changes {
+        roster_evw.send(change);
+
+        let server = match server.as_mut() {
+            Some(server) if role.current().is_server() => server,
+            _ => continue,
+        };
+        match change {
+            RosterEvent::Joined(id) | RosterEvent::Changed(id) => {
+                if let Some(entry) = roster.get(id) {
+                    server.send_message(&RosterUpdate {
+                        id,
+                        entry: Some(entry.clone()),
+                    });
+                }
+            }
+            RosterEvent::Left(id) => {
+                server.send_message(&RosterUpdate { id, entry: None });
+            }
+        }
+    }
+}
+
+//
+// Server
+//
+
+fn receive_hello(
+    mut hello_evr: EventReader<FromClient<Hello>>,
+    mut roster: ResMut<PlayerRoster>,
+    mut server: ResMut<Server>,
+    clock: Res<ServerClock>,
+) {
+    for FromClient { id, message } in hello_evr.iter() {
+        // Only the first one counts, a client can't rename or respawn itself
+        // by saying hello again.
+        if !roster.greeted.insert(*id) {
+            continue;
+        }
+
+        // Everyone already here. Everyone else hears about the new player
+        // when the roster is flushed.
+        for (other_id, entry) in roster.iter() {
+            if other_id != *id {
+                server.send_message_to(
+                    *id,
+                    &RosterUpdate {
+                        id: other_id,
+                        entry: Some(entry.clone()),
+                    },
+                );
+            }
+        }
+
+        let name = message.name.trim();
+        let name = if name.is_empty() {
+            format!("Player {}", id)
+        } else {
+            name.chars().take(MAX_NAME_LEN).collect()
+        };
+        // Players coming back, like after a host migration, keep their color,
+        // when they joined and whether they were promoted or demoted since.
+        let (color, join_time, spectator) = match roster.get(*id) {
+            Some(entry) => (entry.color, entry.join_time, entry.spectator),
+            None => (
+                roster.unused_color(*id),
+                clock.server_time(),
+                message.spectator,
+            ),
+        };
+        let entry = RosterEntry {
+            name,
+            color,
+            join_time,
+            ping: 0,
+            ready: false,
+            spectator,
+        };
+        println!("[S] {} joined as {}", id, entry.name);
+        roster.insert(*id, entry);
+    }
+}
+
+fn remove_disconnected_players(
+    mut server_evr: EventReader<ServerEvent>,
+    mut roster: ResMut<PlayerRoster>,
+) {
+    for event in server_evr.iter() {
+        if let ServerEvent::PlayerDisconnected(id) = event {
+            roster.remove(*id);
+        }
+    }
+}
+
+fn update_pings(
+    mut last_update: Local<f64>,
+    time: Res<Time>,
+    round_trips: Res<ClientRoundTrips>,
+    mut roster: ResMut<PlayerRoster>,
+) {
+    let now = time.seconds_since_startup();
+    if now - *last_update < PING_UPDATE_INTERVAL {
+        return;
+    }
+    *last_update = now;
+
+    let ids = roster.iter().map(|(id, _)| id).collect::<Vec<_>>();
+    for id in ids {
+        // Nothing measured yet.
+        let rtt = round_trips.get(id).unwrap_or_default();
+        let ping = (rtt * 1000.0).round().min(u16::MAX as f64) as u16;
+        roster.modify(id, |entry| entry.ping = ping);
+    }
+}
+
+//
+// Client
+//
+
+fn send_hello(
+    mut client_evr: EventReader<ClientEvent>,
+    config: Res<Config>,
+    mut client: ResMut<Client>,
+) {
+    for event in client_evr.iter() {
+        if let ClientEvent::Connected = event {
+            client.send_message(&Hello {
+                name: config.player_name.clone(),
+                spectator: config.spectate,
+            });
+        }
+    }
+}
+
+fn receive_roster_updates(
+    mut update_evr: EventReader<FromServer<RosterUpdate>>,
+    mut roster: ResMut<PlayerRoster>,
+) {
+    for FromServer(update) in update_evr.iter() {
+        match &update.entry {
+            Some(entry) => roster.insert(update.id, entry.clone()),
+            None => roster.remove(update.id),
+        }
+    }
+}
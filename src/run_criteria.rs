@@ -1,25 +1,86 @@
 use bevy::{ecs::schedule::ShouldRun, prelude::*};
 
-use crate::{network::Server, AppState};
+use crate::{
+    network::{Client, NetworkRole, Server},
+    AppState,
+};
 
-pub fn game_server_run_criteria(
+/// Labels for system sets gated by the criteria below, for ordering against
+/// everything a role runs. Sets that report on the role's state, like
+/// snapshots and migration, run after the label rather than under it.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemLabel)]
+pub enum RoleSystem {
+    Server,
+    Client,
+    ClientExclusive,
+}
+
+/// A server, listen or dedicated, whose transport is up.
+pub fn server_run_criteria(
+    role: Res<State<NetworkRole>>,
     server: Option<Res<Server>>,
-    game_state: Res<State<AppState>>,
 ) -> ShouldRun {
-    if server.is_some() && *game_state.current() == AppState::Game {
+    if role.current().is_server() && server.is_some() {
         return ShouldRun::Yes;
     }
 
     ShouldRun::No
 }
 
-pub fn game_client_exclusive_run_criteria(
+/// A process with a local player and a connection, whether or not it is
+/// also the server.
+pub fn client_run_criteria(
+    role: Res<State<NetworkRole>>,
+    client: Option<Res<Client>>,
+) -> ShouldRun {
+    if role.current().has_local_player() && client.is_some() {
+        return ShouldRun::Yes;
+    }
+
+    ShouldRun::No
+}
+
+/// A client following a remote server.
+pub fn client_exclusive_run_criteria(
+    role: Res<State<NetworkRole>>,
+    client: Option<Res<Client>>,
+) -> ShouldRun {
+    if *role.current() == NetworkRole::Client && client.is_some() {
+        return ShouldRun::Yes;
+    }
+
+    ShouldRun::No
+}
+
+pub fn game_server_run_criteria(
+    role: Res<State<NetworkRole>>,
     server: Option<Res<Server>>,
     app_state: Res<State<AppState>>,
 ) -> ShouldRun {
-    if server.is_some() || *app_state.current() != AppState::Game {
-        return ShouldRun::No;
+    in_game(server_run_criteria(role, server), app_state)
+}
+
+pub fn game_client_exclusive_run_criteria(
+    role: Res<State<NetworkRole>>,
+    client: Option<Res<Client>>,
+    app_state: Res<State<AppState>>,
+) -> ShouldRun {
+    in_game(client_exclusive_run_criteria(role, client), app_state)
+}
+
+/// In the game with a local player, whether or not it is also the server.
+pub fn game_client_run_criteria(
+    role: Res<State<NetworkRole>>,
+    client: Option<Res<Client>>,
+    app_state: Res<State<AppState>>,
+) -> ShouldRun {
+    in_game(client_run_criteria(role, client), app_state)
+}
+
+fn in_game(should_run: ShouldRun, app_state: Res<State<AppState>>) -> ShouldRun {
+    if should_run == ShouldRun::Yes && *app_state.current() == AppState::Game {
+        return ShouldRun::Yes;
     }
 
-    ShouldRun::Yes
+    ShouldRun::No
 }
@@ -0,0 +1,156 @@
+use bitpack::NetSerialize;
+
+/// Which snapshot sequences were received: the latest one, plus one bit for
+/// each of the 32 before it (bit `i` is `latest - 1 - i`). Sequences wrap, so
+/// they're compared by distance rather than magnitude.
+#[derive(Debug, Default, Clone, Copy, PartialEq, NetSerialize)]
+pub struct SnapshotAcks {
+    latest: Option<u32>,
+    bits: u32,
+}
+
+impl SnapshotAcks {
+    pub fn latest(&self) -> Option<u32> {
+        self.latest
+    }
+
+    pub fn contains(&self, sequence: u32) -> bool {
+        let latest = match self.latest {
+            Some(latest) => latest,
+            None => return false,
+        };
+        match latest.wrapping_sub(sequence) {
+            0 => true,
+            behind @ 1..=u32::BITS => self.bits & (1 << (behind - 1)) != 0,
+            _ => false,
+        }
+    }
+
+    pub fn record(&mut self, sequence: u32) {
+        let latest = match self.latest {
+            Some(latest) => latest,
+            None => {
+                self.latest = Some(sequence);
+                self.bits = 0;
+                return;
+            }
+        };
+
+        let ahead = sequence.wrapping_sub(latest) as i32;
+        if ahead > 0 {
+            let shift = ahead as u32;
+            self.bits = self.bits.checked_shl(shift).unwrap_or(0)
+                | 1u32.checked_shl(shift - 1).unwrap_or(0);
+            self.latest = Some(sequence);
+        } else if ahead < 0 {
+            let bit = ahead.unsigned_abs() - 1;
+            if bit < u32::BITS {
+                self.bits |= 1 << bit;
+            }
+        }
+    }
+
+    /// Merges acks from another report, e.g. a newer one from the same client.
+    pub fn merge(&mut self, other: &SnapshotAcks) {
+        if let Some(latest) = other.latest {
+            self.record(latest);
+            for bit in 0..u32::BITS {
+                if other.bits & (1 << bit) != 0 {
+                    self.record(latest.wrapping_sub(bit + 1));
+                }
+            }
+        }
+    }
+
+    /// Fraction of the 32 sequences before the latest that never arrived.
+    pub fn loss(&self) -> f32 {
+        match self.latest {
+            Some(latest) => {
+                let window = latest.min(u32::BITS);
+                if window == 0 {
+                    return 0.0;
+                }
+                let mask = u32::MAX >> (u32::BITS - window);
+                1.0 - (self.bits & mask).count_ones() as f32 / window as f32
+            }
+            None => 0.0,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn acks(sequences: &[u32]) -> SnapshotAcks {
+        let mut acks = SnapshotAcks::default();
+        for sequence in sequences {
+            acks.record(*sequence);
+        }
+        acks
+    }
+
+    #[test]
+    fn records_the_window_behind_the_latest() {
+        let acks = acks(&[10, 8, 9, 5]);
+        assert_eq!(acks.latest(), Some(10));
+        for sequence in [5, 8, 9, 10] {
+            assert!(acks.contains(sequence), "{} missing", sequence);
+        }
+        for sequence in [4, 6, 7, 11] {
+            assert!(!acks.contains(sequence), "{} not received", sequence);
+        }
+    }
+
+    #[test]
+    fn duplicates_change_nothing() {
+        let once = acks(&[3, 5, 7]);
+        let twice = acks(&[3, 5, 7, 7, 5, 3]);
+        assert_eq!(once, twice);
+    }
+
+    #[test]
+    fn sequences_out_of_the_window_are_forgotten() {
+        let acks = acks(&[100, 67, 68]);
+        assert!(!acks.contains(67), "33 behind the latest");
+        assert!(acks.contains(68), "32 behind the latest");
+
+        let jumped = {
+            let mut acks = acks;
+            acks.record(200);
+            acks
+        };
+        assert_eq!(jumped.latest(), Some(200));
+        assert!(!jumped.contains(100));
+        assert!(!jumped.contains(68));
+    }
+
+    #[test]
+    fn sequences_wrap_around() {
+        let acks = acks(&[u32::MAX - 1, 1, u32::MAX, 0]);
+        assert_eq!(acks.latest(), Some(1));
+        for sequence in [u32::MAX - 1, u32::MAX, 0, 1] {
+            assert!(acks.contains(sequence), "{} missing", sequence);
+        }
+        assert!(!acks.contains(2));
+        assert!(!acks.contains(u32::MAX - 2));
+    }
+
+    #[test]
+    fn merging_keeps_both_reports() {
+        let mut merged = acks(&[1, 2, 4]);
+        merged.merge(&acks(&[3, 6]));
+        assert_eq!(merged, acks(&[1, 2, 3, 4, 6]));
+    }
+
+    #[test]
+    fn loss_counts_missing_sequences() {
+        assert_eq!(SnapshotAcks::default().loss(), 0.0);
+        assert_eq!(acks(&[0, 1, 2, 3, 4]).loss(), 0.0);
+        assert_eq!(acks(&[0, 2, 4]).loss(), 0.5);
+    }
+}
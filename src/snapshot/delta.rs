@@ -0,0 +1,291 @@
+use std::collections::VecDeque;
+
+use bevy::utils::HashMap;
+use bitpack::{BitError, BitReader, BitWriter, NetSerialize};
+
+use super::{ComponentValue, QuantizedTransform};
+use crate::network::NetId;
+
+/// Full transform state of every networked entity at one snapshot.
+pub type SnapshotState = HashMap<NetId, QuantizedTransform>;
+
+/// Snapshots older than this can't be used as a baseline anymore.
+const HISTORY_LEN: usize = 32;
+
+/// Recent snapshot states by sequence, kept on the server per client (what was
+/// sent) and on the client (what was received).
+#[derive(Default)]
+pub struct SnapshotHistory(VecDeque<(u32, SnapshotState)>);
+
+impl SnapshotHistory {
+    pub fn get(&self, sequence: u32) -> Option<&SnapshotState> {
+        self.0
+            .iter()
+            .find(|(s, _)| *s == sequence)
+            .map(|(_, state)| state)
+    }
+
+    pub fn push(&mut self, sequence: u32, state: SnapshotState) {
+        if self.0.len() == HISTORY_LEN {
+            self.0.pop_front();
+        }
+        self.0.push_back((sequence, state));
+    }
+
+    pub fn clear(&mut self) {
+        self.0.clear();
+    }
+}
+
+/// A snapshot encoded against a baseline the client already has. Entities that
+/// haven't changed since the baseline are left out. Without a baseline every
+/// entity is sent in full.
+#[derive(Debug, Clone, NetSerialize)]
+pub struct DeltaSnapshot {
+    pub sequence: u32,
+    pub baseline: Option<u32>,
+    pub changed: Box<[(NetId, TransformDelta)]>,
+    pub removed: Box<[NetId]>,
+    /// Replicated components, sent whole until acknowledged.
+    pub components: Box<[ComponentValue]>,
+}
+
+impl DeltaSnapshot {
+    pub fn encode(
+        sequence: u32,
+        baseline: Option<(u32, &SnapshotState)>,
+        state: &SnapshotState,
+        components: Vec<ComponentValue>,
+    ) -> Self {
+        let base_state = baseline.map(|(_, state)| state);
+
+        let changed = state
+            .iter()
+            .filter_map(|(id, transform)| {
+                let from = base_state.and_then(|base| base.get(id));
+                TransformDelta::between(from, transform).map(|delta| (*id, delta))
+            })
+            .collect::<Vec<_>>();
+
+        let removed = base_state
+            .map(|base| {
+                base.keys()
+                    .filter(|id| !state.contains_key(id))
+                    .copied()
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+
+        Self {
+            sequence,
+            baseline: baseline.map(|(sequence, _)| sequence),
+            changed: changed.into_boxed_slice(),
+            removed: removed.into_boxed_slice(),
+            components: components.into_boxed_slice(),
+        }
+    }
+
+    /// Rebuilds the full state. Returns `None` if the baseline isn't in `history`.
+    pub fn decode(&self, history: &SnapshotHistory) -> Option<SnapshotState> {
+        let mut state = match self.baseline {
+            Some(baseline) => history.get(baseline)?.clone(),
+            None => SnapshotState::default(),
+        };
+
+        for id in self.removed.iter() {
+            state.remove(id);
+        }
+
+        for (id, delta) in self.changed.iter() {
+            let transform = delta.apply(state.get(id));
+            state.insert(*id, transform);
+        }
+
+        Some(state)
+    }
+}
+
+const FIELDS: usize = 4;
+const POSITION_FIELDS: usize = 3;
+
+/// The fields of a [`QuantizedTransform`] that changed, flagged in `mask`.
+#[derive(Debug, Clone)]
+pub struct TransformDelta {
+    mask: u8,
+    values: [u32; FIELDS],
+}
+
+impl TransformDelta {
+    pub(super) fn between(
+        from: Option<&QuantizedTransform>,
+        to: &QuantizedTransform,
+    ) -> Option<Self> {
+        let values = fields(to);
+        let mask = match from {
+            Some(from) => fields(from)
+                .iter()
+                .zip(values.iter())
+                .enumerate()
+                .filter(|(_, (a, b))| a != b)
+                .fold(0, |mask, (i, _)| mask | 1 << i),
+            None => (1 << FIELDS) - 1,
+        };
+
+        if mask == 0 {
+            return None;
+        }
+
+        Some(Self { mask, values })
+    }
+
+    /// Size once packed, not counting the entity id.
+    pub(super) fn bits(&self) -> u32 {
+        (0..FIELDS)
+            .filter(|i| self.mask & (1 << i) != 0)
+            .map(field_bits)
+            .sum::<u32>()
+            + FIELDS as u32
+    }
+
+    fn apply(&self, from: Option<&QuantizedTransform>) -> QuantizedTransform {
+        let mut values = from.map(fields).unwrap_or_default();
+        for (i, value) in values.iter_mut().enumerate() {
+            if self.mask & (1 << i) != 0 {
+                *value = self.values[i];
+            }
+        }
+
+        QuantizedTransform {
+            position: [values[0] as u16, values[1] as u16, values[2] as u16],
+            rotation: values[3],
+        }
+    }
+}
+
+fn fields(transform: &QuantizedTransform) -> [u32; FIELDS] {
+    let [x, y, z] = transform.position;
+    [x as u32, y as u32, z as u32, transform.rotation]
+}
+
+/// The mask, then each changed field: positions in 16 bits, rotation in 32.
+impl NetSerialize for TransformDelta {
+    fn net_serialize(&self, writer: &mut BitWriter) {
+        writer.write_bits(self.mask as u32, FIELDS as u32);
+        for (i, value) in self.values.iter().enumerate() {
+            if self.mask & (1 << i) != 0 {
+                writer.write_bits(*value, field_bits(i));
+            }
+        }
+    }
+
+    fn net_deserialize(reader: &mut BitReader) -> Result<Self, BitError> {
+        let mask = reader.read_bits(FIELDS as u32)? as u8;
+        let mut values = [0; FIELDS];
+        for (i, value) in values.iter_mut().enumerate() {
+            if mask & (1 << i) != 0 {
+                *value = reader.read_bits(field_bits(i))?;
+            }
+        }
+
+        Ok(Self { mask, values })
+    }
+}
+
+fn field_bits(field: usize) -> u32 {
+    if field < POSITION_FIELDS {
+        u16::BITS
+    } else {
+        u32::BITS
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transform(x: u16, rotation: u32) -> QuantizedTransform {
+        QuantizedTransform {
+            position: [x, 2, 3],
+            rotation,
+        }
+    }
+
+    fn state(entities: &[(NetId, QuantizedTransform)]) -> SnapshotState {
+        entities.iter().copied().collect()
+    }
+
+    /// Encodes `state` against `baseline` from `history`, packs and unpacks it
+    /// like the network would, then decodes it.
+    fn round_trip(
+        history: &SnapshotHistory,
+        baseline: Option<u32>,
+        state: &SnapshotState,
+    ) -> (DeltaSnapshot, Option<SnapshotState>) {
+        let baseline = baseline.map(|sequence| (sequence, history.get(sequence).unwrap()));
+        let delta = DeltaSnapshot::encode(5, baseline, state, Vec::new());
+        let received = bitpack::from_bytes::<DeltaSnapshot>(&bitpack::to_bytes(&delta)).unwrap();
+        let decoded = received.decode(history);
+        (received, decoded)
+    }
+
+    #[test]
+    fn without_a_baseline_every_entity_is_sent() {
+        let state = state(&[(1, transform(10, 0)), (2, transform(20, 7))]);
+        let (delta, decoded) = round_trip(&SnapshotHistory::default(), None, &state);
+
+        assert_eq!(delta.sequence, 5);
+        assert_eq!(delta.baseline, None);
+        assert_eq!(delta.changed.len(), 2);
+        assert!(delta.removed.is_empty());
+        assert_eq!(decoded, Some(state));
+    }
+
+    #[test]
+    fn unchanged_entities_are_left_out() {
+        let mut history = SnapshotHistory::default();
+        history.push(4, state(&[(1, transform(10, 0)), (2, transform(20, 7))]));
+
+        let state = state(&[(1, transform(10, 0)), (2, transform(20, 8))]);
+        let (delta, decoded) = round_trip(&history, Some(4), &state);
+
+        assert_eq!(delta.baseline, Some(4));
+        let changed = delta.changed.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+        assert_eq!(changed, [2]);
+        assert_eq!(decoded, Some(state));
+    }
+
+    #[test]
+    fn added_and_removed_entities_round_trip() {
+        let mut history = SnapshotHistory::default();
+        history.push(4, state(&[(1, transform(10, 0)), (2, transform(20, 7))]));
+
+        let state = state(&[(1, transform(10, 0)), (3, transform(30, 1))]);
+        let (delta, decoded) = round_trip(&history, Some(4), &state);
+
+        let changed = delta.changed.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+        assert_eq!(changed, [3]);
+        assert_eq!(&delta.removed[..], [2]);
+        assert_eq!(decoded, Some(state));
+    }
+
+    #[test]
+    fn missing_baselines_are_not_decoded() {
+        let mut sent = SnapshotHistory::default();
+        sent.push(4, state(&[(1, transform(10, 0))]));
+        let state = state(&[(1, transform(11, 0))]);
+        let delta = DeltaSnapshot::encode(5, Some((4, sent.get(4).unwrap())), &state, Vec::new());
+
+        assert_eq!(delta.decode(&SnapshotHistory::default()), None);
+    }
+
+    #[test]
+    fn old_baselines_are_forgotten() {
+        let mut history = SnapshotHistory::default();
+        for sequence in 0..=HISTORY_LEN as u32 {
+            history.push(sequence, SnapshotState::default());
+        }
+
+        assert!(history.get(0).is_none());
+        assert!(history.get(1).is_some());
+    }
+}
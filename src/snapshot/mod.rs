@@ -0,0 +1,444 @@
+use std::collections::VecDeque;
+
+use bevy::{prelude::*, utils::HashMap};
+use physics::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::Config,
+    network::*,
+    player::{LocalPlayer, PlayerInput},
+    relevancy::RelevantEntities,
+    run_criteria::{
+        client_run_criteria, game_client_exclusive_run_criteria, game_server_run_criteria,
+        server_run_criteria, RoleSystem,
+    },
+    spawn::SpawnName,
+    tick::{NetworkTick, TickAppExt, TickStage},
+    AppState,
+};
+
+mod ack;
+mod delta;
+mod priority;
+mod quantize;
+mod replicate;
+
+pub use ack::*;
+pub use delta::*;
+pub use priority::*;
+pub use quantize::*;
+pub use replicate::*;
+
+pub struct SnapshotPlugin;
+
+impl Plugin for SnapshotPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(SnapshotBuffer::default())
+            .init_resource::<ReceivedSnapshots>()
+            .init_resource::<ClientBaselines>()
+            .init_resource::<ClientAcks>()
+            .init_resource::<SnapshotAcks>()
+            .init_resource::<TransformQuantization>()
+            .init_resource::<SnapshotPriority>()
+            .init_resource::<PriorityAccumulators>()
+            .init_resource::<ReplicationRegistry>()
+            .init_resource::<ReplicatedComponents>()
+            .init_resource::<ComponentBaselines>()
+            .init_resource::<ReceivedComponents>()
+            .init_resource::<ImportedComponents>()
+            .init_resource::<SnapshotPlayback>()
+            .init_resource::<ServerSnapshotInterval>()
+            .add_packed_server_message::<DeltaSnapshot>(DeliveryMethod::UnreliableSequenced)
+            .add_server_message::<SnapshotInterval>(DeliveryMethod::ReliableOrdered)
+            .add_system_set(
+                SystemSet::on_enter(AppState::Game).with_system(clear_buffer_on_enter_game),
+            )
+            .add_system_set(
+                SystemSet::new()
+                    .with_run_criteria(server_run_criteria)
+                    .with_system(send_snapshot_interval),
+            )
+            .add_system_set(
+                SystemSet::new()
+                    .with_run_criteria(client_run_criteria)
+                    .with_system(receive_snapshot_interval.after(NetworkSystem::Receive)),
+            )
+            .add_system_set(
+                SystemSet::new()
+                    .with_run_criteria(game_server_run_criteria)
+                    .label(RoleSystem::Server)
+                    .with_system(receive_acks)
+                    .with_system(remove_disconnected_baselines),
+            )
+            .add_tick_system_set(
+                TickStage::Send,
+                SystemSet::new()
+                    .with_run_criteria(game_server_run_criteria)
+                    .after(RoleSystem::Server)
+                    .with_system(send_snapshots.label(SnapshotSystem::Send)),
+            )
+            .add_system_set(
+                SystemSet::new()
+                    .with_run_criteria(game_client_exclusive_run_criteria)
+                    .label(RoleSystem::ClientExclusive)
+                    .with_system(network_entity_transform_sync_setup)
+                    .with_system(buffer_snapshot)
+                    .with_system(lerp.label(SnapshotSystem::Interpolate)),
+            );
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemLabel)]
+pub enum SnapshotSystem {
+    /// Replicated components are encoded, ready to be sent.
+    Collect,
+    Send,
+    Interpolate,
+}
+
+/// Ticks between the server's snapshots. Sent to each client as it connects,
+/// so it plays them back at the pace they arrive.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SnapshotInterval(pub u32);
+
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub sequence: u32,
+    pub transforms: Box<[(NetId, NetworkTransform)]>,
+    pub components: Box<[ComponentValue]>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NetworkTransform {
+    pub position: Vec3,
+    pub rotation: Quat,
+}
+
+//
+// Server
+//
+
+/// Snapshots each client has acknowledged, as reported with their input.
+#[derive(Default)]
+pub struct ClientAcks(HashMap<NetId, SnapshotAcks>);
+
+impl ClientAcks {
+    pub fn get(&self, client_id: NetId) -> Option<&SnapshotAcks> {
+        self.0.get(&client_id)
+    }
+}
+
+/// What we recently sent each client, for encoding deltas against.
+#[derive(Default)]
+struct ClientBaselines(HashMap<NetId, SnapshotHistory>);
+
+fn send_snapshots(
+    mut sequence: Local<u32>,
+    tick: Res<NetworkTick>,
+    config: Res<Config>,
+    q: Query<(
+        &NetworkId,
+        &Transform,
+        &SpawnName,
+        Option<&RigidBodyPositionComponent>,
+        Option<&RigidBodyVelocityComponent>,
+    )>,
+    mut server: ResMut<Server>,
+    mut baselines: ResMut<ClientBaselines>,
+    mut accumulators: ResMut<PriorityAccumulators>,
+    mut components: ResMut<ReplicatedComponents>,
+    mut component_baselines: ResMut<ComponentBaselines>,
+    registry: Res<ReplicationRegistry>,
+    acks: Res<ClientAcks>,
+    relevant: Res<RelevantEntities>,
+    quantization: Res<TransformQuantization>,
+    priority: Res<SnapshotPriority>,
+) {
+    if tick.0 % config.snapshot_interval() != 0 {
+        return;
+    }
+
+    let mut updates = HashMap::default();
+    for (net_id, transform, name, position, velocity) in q.iter() {
+        // Rigid bodies only write back to their transform once per frame,
+        // which may be several ticks ago.
+        let position = position
+            .map(|position| position.position.translation.vector.into())
+            .unwrap_or(transform.translation);
+        let net_transform = NetworkTransform {
+            position,
+            rotation: transform.rotation,
+        };
+        let speed = velocity
+            .map(|velocity| Vec3::from(velocity.linvel).length())
+            .unwrap_or_default();
+        updates.insert(
+            net_id.value(),
+            EntityUpdate {
+                name: *name,
+                position,
+                speed,
+                transform: quantization.quantize(&net_transform),
+            },
+        );
+    }
+
+    if updates.is_empty() {
+        return;
+    }
+
+    components.retain_entities(|id| updates.contains_key(&id));
+
+    for client_id in server.player_ids().collect::<Vec<_>>() {
+        let relevant = match relevant.get(client_id) {
+            Some(relevant) => relevant,
+            None => continue,
+        };
+        let updates = updates
+            .iter()
+            .filter(|(id, _)| relevant.contains(*id))
+            .map(|(id, update)| (*id, *update))
+            .collect::<HashMap<_, _>>();
+        let viewer = updates.get(&client_id).map(|update| update.position);
+
+        let sent = baselines.0.entry(client_id).or_default();
+        let baseline = acks
+            .get(client_id)
+            .and_then(|acks| acks.latest())
+            .and_then(|acked| sent.get(acked).map(|state| (acked, state)));
+        let state = priority.select(
+            &mut accumulators,
+            client_id,
+            viewer,
+            &updates,
+            baseline.map(|(_, state)| state),
+        );
+        let components = component_baselines.select(
+            client_id,
+            *sequence,
+            acks.get(client_id),
+            &components,
+            &registry,
+            |id| updates.contains_key(&id),
+        );
+        let delta = DeltaSnapshot::encode(*sequence, baseline, &state, components);
+        sent.push(*sequence, state);
+        server.send_message_to(client_id, &delta);
+    }
+
+    *sequence = sequence.wrapping_add(1);
+}
+
+fn receive_acks(mut input_evr: EventReader<FromClient<PlayerInput>>, mut acks: ResMut<ClientAcks>) {
+    for FromClient { id, message } in input_evr.iter() {
+        acks.0.entry(*id).or_default().merge(&message.acks);
+    }
+}
+
+fn remove_disconnected_baselines(
+    mut server_evr: EventReader<ServerEvent>,
+    mut baselines: ResMut<ClientBaselines>,
+    mut acks: ResMut<ClientAcks>,
+    mut accumulators: ResMut<PriorityAccumulators>,
+    mut component_baselines: ResMut<ComponentBaselines>,
+) {
+    for event in server_evr.iter() {
+        if let ServerEvent::PlayerDisconnected(id) = event {
+            baselines.0.remove(id);
+            acks.0.remove(id);
+            accumulators.remove(*id);
+            component_baselines.remove(*id);
+        }
+    }
+}
+
+fn send_snapshot_interval(
+    mut server_evr: EventReader<ServerEvent>,
+    mut server: ResMut<Server>,
+    config: Res<Config>,
+) {
+    for event in server_evr.iter() {
+        if let ServerEvent::PlayerConnected(id) = event {
+            server.send_message_to(*id, &SnapshotInterval(config.snapshot_interval()));
+        }
+    }
+}
+
+//
+// Client
+//
+
+/// How often the server we're connected to sends snapshots.
+pub struct ServerSnapshotInterval(u32);
+
+impl ServerSnapshotInterval {
+    /// Seconds between snapshots.
+    pub fn seconds(&self) -> f32 {
+        self.0 as f32 * TICK_SECONDS
+    }
+}
+
+impl Default for ServerSnapshotInterval {
+    fn default() -> Self {
+        Self(Config::default().snapshot_interval())
+    }
+}
+
+fn receive_snapshot_interval(
+    mut interval_evr: EventReader<FromServer<SnapshotInterval>>,
+    mut interval: ResMut<ServerSnapshotInterval>,
+) {
+    for FromServer(SnapshotInterval(ticks)) in interval_evr.iter() {
+        interval.0 = (*ticks).max(1);
+    }
+}
+
+/// Snapshots the client tries to keep buffered ahead of playback.
+const BUFFER_SIZE_TARGET: u8 = 2;
+
+/// Roughly how far behind the server clients play snapshots back, in seconds.
+/// For the server's use, since clients pace themselves by what it sends.
+pub fn interpolation_delay(config: &Config) -> f32 {
+    (BUFFER_SIZE_TARGET as f32 + 1.0) * config.snapshot_seconds()
+}
+
+#[derive(Default)]
+struct SnapshotBuffer(VecDeque<Snapshot>);
+
+/// Rebuilt snapshots that later deltas may be encoded against.
+#[derive(Default)]
+struct ReceivedSnapshots(SnapshotHistory);
+
+#[derive(Default, Component)]
+struct Lerp {
+    from_pos: Vec3,
+    from_rot: Quat,
+    to_pos: Vec3,
+    to_rot: Quat,
+}
+
+fn network_entity_transform_sync_setup(
+    // The local player is predicted instead.
+    entity_q: Query<(Entity, &Transform), (Added<NetworkId>, Without<LocalPlayer>)>,
+    mut commands: Commands,
+) {
+    for (entity, transform) in entity_q.iter() {
+        commands.entity(entity).insert(Lerp {
+            from_pos: transform.translation,
+            to_pos: transform.translation,
+            ..Default::default()
+        });
+    }
+}
+
+fn clear_buffer_on_enter_game(
+    mut buffer: ResMut<SnapshotBuffer>,
+    mut received: ResMut<ReceivedSnapshots>,
+    mut received_acks: ResMut<SnapshotAcks>,
+    mut baselines: ResMut<ClientBaselines>,
+    mut acks: ResMut<ClientAcks>,
+    mut accumulators: ResMut<PriorityAccumulators>,
+    mut components: ResMut<ReplicatedComponents>,
+    mut component_baselines: ResMut<ComponentBaselines>,
+    mut received_components: ResMut<ReceivedComponents>,
+) {
+    buffer.0.clear();
+    received.0.clear();
+    received_acks.clear();
+    baselines.0.clear();
+    acks.0.clear();
+    accumulators.clear();
+    components.clear();
+    component_baselines.clear();
+    received_components.clear();
+}
+
+fn buffer_snapshot(
+    mut snapshot_evr: EventReader<FromServer<DeltaSnapshot>>,
+    mut buffer: ResMut<SnapshotBuffer>,
+    mut received: ResMut<ReceivedSnapshots>,
+    mut acks: ResMut<SnapshotAcks>,
+    quantization: Res<TransformQuantization>,
+) {
+    for FromServer(delta) in snapshot_evr.iter() {
+        let state = match delta.decode(&received.0) {
+            Some(state) => state,
+            None => {
+                println!(
+                    "[C] Dropping snapshot {}, baseline {:?} is gone",
+                    delta.sequence, delta.baseline
+                );
+                continue;
+            }
+        };
+
+        buffer.0.push_back(Snapshot {
+            sequence: delta.sequence,
+            transforms: state
+                .iter()
+                .map(|(id, transform)| (*id, quantization.dequantize(transform)))
+                .collect(),
+            components: delta.components.clone(),
+        });
+        received.0.push(delta.sequence, state);
+        acks.record(delta.sequence);
+    }
+}
+
+fn lerp(
+    mut send_rate_timer: Local<f32>,
+    mut previous_sequence: Local<u32>,
+    mut current_sequence: Local<u32>,
+    time: Res<Time>,
+    interval: Res<ServerSnapshotInterval>,
+    mut buffer: ResMut<SnapshotBuffer>,
+    mut received_components: ResMut<ReceivedComponents>,
+    mut playback: ResMut<SnapshotPlayback>,
+    mut lerp_q: Query<(&mut Transform, &mut Lerp)>,
+    entity_map: Res<NetworkEntityMap>,
+) {
+    if buffer.0.is_empty() {
+        return;
+    }
+
+    let buffer_scalar = 1.0 - (BUFFER_SIZE_TARGET as f32 - buffer.0.len() as f32) / 10.0;
+
+    *send_rate_timer += time.delta_seconds() * buffer_scalar;
+
+    let sequence_scalar = current_sequence
+        .wrapping_sub(*previous_sequence)
+        .clamp(1, 4);
+    let lerp_duration = interval.seconds() * sequence_scalar as f32;
+
+    if *send_rate_timer > lerp_duration {
+        *send_rate_timer -= lerp_duration;
+
+        if let Some(snapshot) = buffer.0.pop_front() {
+            *previous_sequence = *current_sequence;
+            *current_sequence = snapshot.sequence;
+            playback.sequence = snapshot.sequence;
+            received_components.receive(&snapshot.components);
+
+            for (_, mut lerp) in lerp_q.iter_mut() {
+                lerp.from_pos = lerp.to_pos;
+                lerp.from_rot = lerp.to_rot;
+            }
+
+            for (id, net_transform) in snapshot.transforms.iter() {
+                let entity = entity_map.entity(*id);
+                if let Some((_, mut lerp)) = entity.and_then(|entity| lerp_q.get_mut(entity).ok()) {
+                    lerp.to_pos = net_transform.position;
+                    lerp.to_rot = net_transform.rotation;
+                }
+            }
+        }
+    }
+
+    let t = *send_rate_timer / lerp_duration;
+    playback.t = t;
+    for (mut transform, lerp) in lerp_q.iter_mut() {
+        transform.translation = Vec3::lerp(lerp.from_pos, lerp.to_pos, t);
+        transform.rotation = Quat::slerp(lerp.from_rot, lerp.to_rot, t);
+    }
+}
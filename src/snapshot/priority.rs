@@ -0,0 +1,109 @@
+use bevy::{prelude::*, utils::HashMap};
+
+use super::{QuantizedTransform, SnapshotState, TransformDelta};
+use crate::{network::NetId, spawn::SpawnName};
+
+/// How entity updates compete for room in a snapshot.
+///
+/// Every snapshot, each entity's priority for a client grows by its weight,
+/// scaled down with distance from the client's player and up with speed.
+/// Entities are then packed highest priority first until `budget` bytes are
+/// used, and the ones that made it start over from zero. Entities left out keep
+/// their priority, so they win a later snapshot.
+pub struct SnapshotPriority {
+    pub budget: usize,
+    /// Weight by entity type, 1.0 if missing.
+    pub weights: HashMap<SpawnName, f32>,
+    /// Distance at which priority growth is halved.
+    pub falloff: f32,
+    /// Extra weight per unit of speed.
+    pub speed_scale: f32,
+}
+
+impl Default for SnapshotPriority {
+    fn default() -> Self {
+        Self {
+            budget: 1000,
+            weights: [(SpawnName::Player, 2.0), (SpawnName::Obstacle, 1.0)]
+                .into_iter()
+                .collect(),
+            falloff: 10.0,
+            speed_scale: 0.1,
+        }
+    }
+}
+
+/// What the server knows about an entity when prioritizing it.
+#[derive(Clone, Copy)]
+pub struct EntityUpdate {
+    pub name: SpawnName,
+    pub position: Vec3,
+    pub speed: f32,
+    pub transform: QuantizedTransform,
+}
+
+/// Accumulated priority per client, per entity.
+#[derive(Default)]
+pub struct PriorityAccumulators(HashMap<NetId, HashMap<NetId, f32>>);
+
+impl PriorityAccumulators {
+    pub fn remove(&mut self, client_id: NetId) {
+        self.0.remove(&client_id);
+    }
+
+    pub fn clear(&mut self) {
+        self.0.clear();
+    }
+}
+
+impl SnapshotPriority {
+    fn growth(&self, update: &EntityUpdate, viewer: Option<Vec3>) -> f32 {
+        let weight = self.weights.get(&update.name).copied().unwrap_or(1.0);
+        let distance = viewer
+            .map(|viewer| viewer.distance(update.position))
+            .unwrap_or(0.0);
+        weight / (1.0 + distance / self.falloff) * (1.0 + update.speed * self.speed_scale)
+    }
+
+    /// Picks the state to send `client_id` from `updates`. Entities that are
+    /// left out keep their `baseline` value, so they cost nothing in the delta
+    /// and aren't mistaken for removed.
+    pub fn select(
+        &self,
+        accumulators: &mut PriorityAccumulators,
+        client_id: NetId,
+        viewer: Option<Vec3>,
+        updates: &HashMap<NetId, EntityUpdate>,
+        baseline: Option<&SnapshotState>,
+    ) -> SnapshotState {
+        let priorities = accumulators.0.entry(client_id).or_default();
+        priorities.retain(|id, _| updates.contains_key(id));
+        for (id, update) in updates.iter() {
+            *priorities.entry(*id).or_default() += self.growth(update, viewer);
+        }
+
+        let mut order = updates.keys().copied().collect::<Vec<_>>();
+        order.sort_by(|a, b| priorities[b].total_cmp(&priorities[a]));
+
+        let mut state = SnapshotState::default();
+        let mut bits_left = self.budget * 8;
+        for id in order {
+            let from = baseline.and_then(|baseline| baseline.get(&id));
+            let to = &updates[&id].transform;
+
+            let bits = match TransformDelta::between(from, to) {
+                Some(delta) => (NetId::BITS + delta.bits()) as usize,
+                None => 0,
+            };
+            if bits <= bits_left {
+                bits_left -= bits;
+                state.insert(id, *to);
+                priorities.insert(id, 0.0);
+            } else if let Some(from) = from {
+                state.insert(id, *from);
+            }
+        }
+
+        state
+    }
+}
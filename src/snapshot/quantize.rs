@@ -0,0 +1,375 @@
+use std::f32::consts::{FRAC_1_SQRT_2, SQRT_2, TAU};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::NetworkTransform;
+
+/// The world's bounds on every axis, for positions sent in snapshots and
+/// anywhere else they're quantized.
+pub const WORLD_MIN: f32 = -32.0;
+pub const WORLD_MAX: f32 = 32.0;
+/// How precisely positions are sent, in world units.
+pub const POSITION_PRECISION: f32 = 0.001;
+
+/// How transforms are squeezed into a [`QuantizedTransform`]. Both ends must
+/// use the same settings.
+///
+/// Positions are fixed-point within `min..=max`, so each axis is off by at most
+/// `precision / 2` (positions outside the bounds are clamped to them). Each
+/// axis gets at most `u16::MAX` steps though, so when `(max - min) / precision`
+/// is more than that the error grows to [`position_error`](Self::position_error).
+/// Rotation error depends on the [`RotationQuantization`].
+#[derive(Debug, Clone, Copy)]
+pub struct TransformQuantization {
+    pub min: Vec3,
+    pub max: Vec3,
+    pub precision: f32,
+    pub rotation: RotationQuantization,
+}
+
+impl Default for TransformQuantization {
+    fn default() -> Self {
+        Self {
+            min: Vec3::splat(WORLD_MIN),
+            max: Vec3::splat(WORLD_MAX),
+            precision: POSITION_PRECISION,
+            rotation: RotationQuantization::yaw(16),
+        }
+    }
+}
+
+/// How rotations are squeezed into a `u32`, made with [`yaw`](Self::yaw) or
+/// [`smallest_three`](Self::smallest_three).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RotationQuantization {
+    kind: RotationKind,
+    bits: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RotationKind {
+    Yaw,
+    SmallestThree,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuantizedTransform {
+    pub position: [u16; 3],
+    pub rotation: u32,
+}
+
+impl TransformQuantization {
+    pub fn quantize(&self, transform: &NetworkTransform) -> QuantizedTransform {
+        let steps = self.position_steps();
+        let normalized = (transform.position - self.min) / (self.max - self.min);
+        let position = (normalized.clamp(Vec3::ZERO, Vec3::ONE) * steps).round();
+
+        QuantizedTransform {
+            position: [position.x as u16, position.y as u16, position.z as u16],
+            rotation: self.rotation.quantize(transform.rotation),
+        }
+    }
+
+    pub fn dequantize(&self, quantized: &QuantizedTransform) -> NetworkTransform {
+        let steps = self.position_steps();
+        let [x, y, z] = quantized.position;
+        let normalized = Vec3::new(x as f32, y as f32, z as f32) / steps;
+
+        NetworkTransform {
+            position: self.min + normalized.min(Vec3::ONE) * (self.max - self.min),
+            rotation: self.rotation.dequantize(quantized.rotation),
+        }
+    }
+
+    /// The most each axis of a position within bounds can be off by.
+    pub fn position_error(&self) -> Vec3 {
+        (self.max - self.min) / self.position_steps() * 0.5
+    }
+
+    fn position_steps(&self) -> Vec3 {
+        ((self.max - self.min) / self.precision)
+            .ceil()
+            .min(Vec3::splat(u16::MAX as f32))
+    }
+}
+
+impl RotationQuantization {
+    /// Only the rotation around Y is kept, in `bits` bits. The angle is off by
+    /// at most `PI / 2^bits` radians.
+    ///
+    /// # Panics
+    ///
+    /// If `bits` isn't in `1..=32`.
+    pub fn yaw(bits: u32) -> Self {
+        assert!(
+            (1..=32).contains(&bits),
+            "yaw takes 1 to 32 bits, not {}",
+            bits
+        );
+        Self {
+            kind: RotationKind::Yaw,
+            bits,
+        }
+    }
+
+    /// The three smallest quaternion components in `bits` bits each, plus
+    /// which one was dropped. Each component is off by at most
+    /// `1 / (SQRT_2 * (2^bits - 1))`.
+    ///
+    /// # Panics
+    ///
+    /// If `bits` isn't in `1..=10`, as the packed rotation has to fit in 32
+    /// bits.
+    pub fn smallest_three(bits: u32) -> Self {
+        assert!(
+            (1..=10).contains(&bits),
+            "smallest three takes 1 to 10 bits per component, not {}",
+            bits
+        );
+        Self {
+            kind: RotationKind::SmallestThree,
+            bits,
+        }
+    }
+
+    fn quantize(&self, rotation: Quat) -> u32 {
+        let bits = self.bits;
+        match self.kind {
+            RotationKind::Yaw => {
+                let forward = rotation * Vec3::Z;
+                let yaw = f32::atan2(forward.x, forward.z).rem_euclid(TAU);
+                let steps = 2f64.powi(bits as i32);
+                ((yaw as f64 / TAU as f64 * steps).round() % steps) as u32
+            }
+            RotationKind::SmallestThree => {
+                let max: u32 = (1 << bits) - 1;
+                let mut components = rotation.normalize().to_array();
+                let largest = (0..4)
+                    .max_by(|a, b| components[*a].abs().total_cmp(&components[*b].abs()))
+                    .unwrap();
+
+                // q and -q are the same rotation, so make the dropped one positive.
+                if components[largest] < 0.0 {
+                    components.iter_mut().for_each(|c| *c = -*c);
+                }
+
+                let mut packed = largest as u32;
+                for (i, c) in components.iter().enumerate() {
+                    if i == largest {
+                        continue;
+                    }
+                    let normalized = (c * SQRT_2 + 1.0) * 0.5;
+                    let value = (normalized.clamp(0.0, 1.0) * max as f32).round() as u32;
+                    packed = packed << bits | value;
+                }
+                packed
+            }
+        }
+    }
+
+    fn dequantize(&self, packed: u32) -> Quat {
+        let bits = self.bits;
+        match self.kind {
+            RotationKind::Yaw => {
+                let yaw = packed as f64 / 2f64.powi(bits as i32) * TAU as f64;
+                Quat::from_rotation_y(yaw as f32)
+            }
+            RotationKind::SmallestThree => {
+                let max: u32 = (1 << bits) - 1;
+                let largest = (packed >> (bits * 3)) as usize & 0b11;
+
+                let mut components = [0.0; 4];
+                let mut sum = 0.0;
+                let mut shift = bits * 3;
+                for (i, c) in components.iter_mut().enumerate() {
+                    if i == largest {
+                        continue;
+                    }
+                    shift -= bits;
+                    let value = (packed >> shift) & max;
+                    *c = (value as f32 / max as f32 * 2.0 - 1.0) * FRAC_1_SQRT_2;
+                    sum += *c * *c;
+                }
+                components[largest] = (1.0 - sum).max(0.0).sqrt();
+
+                Quat::from_array(components).normalize()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use super::*;
+
+    /// Slack for f32 rounding on top of the documented bounds.
+    const EPSILON: f32 = 1e-5;
+
+    fn round_trip(
+        quantization: &TransformQuantization,
+        position: Vec3,
+        rotation: Quat,
+    ) -> NetworkTransform {
+        let transform = NetworkTransform { position, rotation };
+        quantization.dequantize(&quantization.quantize(&transform))
+    }
+
+    fn yaw(rotation: Quat) -> f32 {
+        let forward = rotation * Vec3::Z;
+        f32::atan2(forward.x, forward.z)
+    }
+
+    #[test]
+    fn position_error_is_half_precision() {
+        let quantization = TransformQuantization::default();
+        assert!(quantization.position_error().max_element() <= POSITION_PRECISION * 0.5 + EPSILON);
+
+        for i in 0..=1000 {
+            let t = i as f32 / 1000.0;
+            let position = Vec3::new(
+                WORLD_MIN + (WORLD_MAX - WORLD_MIN) * t,
+                WORLD_MAX - (WORLD_MAX - WORLD_MIN) * t * t,
+                (t * 97.0).sin() * WORLD_MAX,
+            );
+            let error =
+                (round_trip(&quantization, position, Quat::IDENTITY).position - position).abs();
+            assert!(
+                error.max_element() <= POSITION_PRECISION * 0.5 + EPSILON,
+                "{} off by {}",
+                position,
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn position_error_grows_past_step_cap() {
+        let quantization = TransformQuantization {
+            min: Vec3::splat(-1000.0),
+            max: Vec3::splat(1000.0),
+            precision: 0.001,
+            ..Default::default()
+        };
+        let bound = quantization.position_error();
+        assert!(bound.x > quantization.precision * 0.5);
+
+        for i in 0..=1000 {
+            let position = Vec3::splat(-1000.0 + i as f32 * 1.9993);
+            let error =
+                (round_trip(&quantization, position, Quat::IDENTITY).position - position).abs();
+            assert!(
+                error.cmple(bound + Vec3::splat(1e-3)).all(),
+                "{} off by {}",
+                position,
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn positions_out_of_bounds_are_clamped() {
+        let quantization = TransformQuantization::default();
+        let position = Vec3::new(WORLD_MIN - 5.0, 0.0, WORLD_MAX + 5.0);
+        let clamped = round_trip(&quantization, position, Quat::IDENTITY).position;
+        assert!(
+            (clamped - Vec3::new(WORLD_MIN, 0.0, WORLD_MAX))
+                .abs()
+                .max_element()
+                <= EPSILON
+        );
+    }
+
+    #[test]
+    fn yaw_error() {
+        for bits in [8, 16, 32] {
+            let quantization = TransformQuantization {
+                rotation: RotationQuantization::yaw(bits),
+                ..Default::default()
+            };
+            let bound = PI / 2f32.powi(bits as i32);
+
+            for i in 0..720 {
+                let angle = i as f32 * 0.0087 - PI;
+                let rotation = Quat::from_rotation_y(angle);
+                let dequantized = round_trip(&quantization, Vec3::ZERO, rotation).rotation;
+                let error = (yaw(dequantized) - yaw(rotation) + PI).rem_euclid(TAU) - PI;
+                assert!(
+                    error.abs() <= bound + EPSILON,
+                    "{} bits, {} off by {}",
+                    bits,
+                    angle,
+                    error
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn smallest_three_error() {
+        let mut rng = fastrand::Rng::with_seed(31);
+        for bits in [6, 9, 10] {
+            let quantization = TransformQuantization {
+                rotation: RotationQuantization::smallest_three(bits),
+                ..Default::default()
+            };
+            let bound = 1.0 / (SQRT_2 * ((1 << bits) - 1) as f32);
+
+            for _ in 0..1000 {
+                let rotation = Quat::from_xyzw(
+                    rng.f32() * 2.0 - 1.0,
+                    rng.f32() * 2.0 - 1.0,
+                    rng.f32() * 2.0 - 1.0,
+                    rng.f32() * 2.0 - 1.0,
+                )
+                .normalize();
+                let mut dequantized = round_trip(&quantization, Vec3::ZERO, rotation).rotation;
+                if dequantized.dot(rotation) < 0.0 {
+                    dequantized = -dequantized;
+                }
+
+                let original = rotation.to_array();
+                let dequantized = dequantized.to_array();
+                let largest = (0..4)
+                    .max_by(|a, b| original[*a].abs().total_cmp(&original[*b].abs()))
+                    .unwrap();
+                for i in (0..4).filter(|i| *i != largest) {
+                    let error = (dequantized[i] - original[i]).abs();
+                    assert!(
+                        error <= bound + EPSILON,
+                        "{} bits, {} off by {}",
+                        bits,
+                        rotation,
+                        error
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn yaw_rejects_zero_bits() {
+        RotationQuantization::yaw(0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn yaw_rejects_more_than_32_bits() {
+        RotationQuantization::yaw(33);
+    }
+
+    #[test]
+    #[should_panic]
+    fn smallest_three_rejects_zero_bits() {
+        RotationQuantization::smallest_three(0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn smallest_three_rejects_more_than_10_bits() {
+        RotationQuantization::smallest_three(11);
+    }
+}
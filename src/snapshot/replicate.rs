@@ -0,0 +1,526 @@
+use std::any::TypeId;
+
+use bevy::{
+    ecs::component::TableStorage,
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+use bitpack::NetSerialize;
+
+use super::{SnapshotAcks, SnapshotSystem};
+use crate::{
+    network::*,
+    run_criteria::{game_client_exclusive_run_criteria, game_server_run_criteria, RoleSystem},
+    tick::{TickAppExt, TickStage},
+};
+
+pub type ComponentKind = u8;
+
+pub trait Replicate: Component + NetSerialize + Clone {}
+
+impl<T> Replicate for T where T: Component + NetSerialize + Clone {}
+
+/// One replicated component value, as sent in a snapshot.
+#[derive(Debug, Clone, NetSerialize)]
+pub struct ComponentValue {
+    pub kind: ComponentKind,
+    pub id: NetId,
+    pub bytes: Box<[u8]>,
+}
+
+/// Replicated component types. Both ends register the same types in the same
+/// order, so the kinds assigned at registration line up.
+#[derive(Default)]
+pub struct ReplicationRegistry {
+    kinds: HashMap<TypeId, ComponentKind>,
+    /// Kinds only sent with an entity's full state, not in snapshots.
+    state_only: HashSet<ComponentKind>,
+}
+
+impl ReplicationRegistry {
+    fn register<T: Replicate>(&mut self, in_snapshots: bool) {
+        let kind = self.kinds.len();
+        assert!(
+            kind <= ComponentKind::MAX as usize,
+            "too many replicated components"
+        );
+        let previous = self.kinds.insert(TypeId::of::<T>(), kind as ComponentKind);
+        assert!(
+            previous.is_none(),
+            "component {} replicated twice",
+            std::any::type_name::<T>()
+        );
+        if !in_snapshots {
+            self.state_only.insert(kind as ComponentKind);
+        }
+    }
+
+    pub fn in_snapshots(&self, kind: ComponentKind) -> bool {
+        !self.state_only.contains(&kind)
+    }
+
+    pub fn kind<T: Replicate>(&self) -> ComponentKind {
+        match self.kinds.get(&TypeId::of::<T>()) {
+            Some(kind) => *kind,
+            None => panic!("component {} is not replicated", std::any::type_name::<T>()),
+        }
+    }
+}
+
+pub trait ReplicateAppExt {
+    /// Sends `T` on networked entities to clients in snapshots. Clients get
+    /// each new value as its snapshot is played back.
+    fn replicate<T: Replicate>(&mut self) -> &mut Self;
+    /// Like [`replicate`](ReplicateAppExt::replicate), but clients blend from
+    /// the previous value to the new one with `interpolate` while the snapshot
+    /// plays back.
+    fn replicate_interpolated<T: Replicate>(
+        &mut self,
+        interpolate: fn(&T, &T, f32) -> T,
+    ) -> &mut Self;
+    /// Sends `T` on networked entities only as part of their full state, when
+    /// they first become relevant to a client. For state clients don't need
+    /// to see change, like what the server simulates them with.
+    fn replicate_state<T: Replicate>(&mut self) -> &mut Self;
+}
+
+impl ReplicateAppExt for App {
+    fn replicate<T: Replicate>(&mut self) -> &mut Self {
+        add_replicated::<T>(self, true)
+    }
+
+    fn replicate_interpolated<T: Replicate>(
+        &mut self,
+        interpolate: fn(&T, &T, f32) -> T,
+    ) -> &mut Self {
+        self.insert_resource(Interpolator(interpolate))
+            .replicate::<T>()
+    }
+
+    fn replicate_state<T: Replicate>(&mut self) -> &mut Self {
+        add_replicated::<T>(self, false)
+    }
+}
+
+fn add_replicated<T: Replicate>(app: &mut App, in_snapshots: bool) -> &mut App {
+    app.world
+        .get_resource_or_insert_with(ReplicationRegistry::default)
+        .register::<T>(in_snapshots);
+
+    app.add_tick_system_set(
+        TickStage::Send,
+        SystemSet::new()
+            .with_run_criteria(game_server_run_criteria)
+            .label(RoleSystem::Server)
+            .with_system(collect_replicated::<T>.label(SnapshotSystem::Collect)),
+    )
+    .add_system_set(
+        SystemSet::new()
+            .with_run_criteria(game_server_run_criteria)
+            .label(RoleSystem::Server)
+            .with_system(import_replicated::<T>),
+    )
+    .add_system_set(
+        SystemSet::new()
+            .with_run_criteria(game_client_exclusive_run_criteria)
+            .label(RoleSystem::ClientExclusive)
+            .with_system(apply_replicated::<T>.after(SnapshotSystem::Interpolate)),
+    )
+}
+
+struct Interpolator<T>(fn(&T, &T, f32) -> T);
+
+struct ReplicatedLerp<T> {
+    from: T,
+    to: T,
+}
+
+impl<T: Replicate> Component for ReplicatedLerp<T> {
+    type Storage = TableStorage;
+}
+
+//
+// Server
+//
+
+/// The current encoded value of every replicated component.
+#[derive(Default)]
+pub struct ReplicatedComponents(HashMap<(NetId, ComponentKind), Box<[u8]>>);
+
+impl ReplicatedComponents {
+    /// Every replicated component on `id`, whether sent in snapshots or not.
+    pub fn export(&self, id: NetId) -> Box<[ComponentValue]> {
+        self.0
+            .iter()
+            .filter(|((entity_id, _), _)| *entity_id == id)
+            .map(|(&(id, kind), bytes)| ComponentValue {
+                kind,
+                id,
+                bytes: bytes.clone(),
+            })
+            .collect()
+    }
+
+    pub(super) fn retain_entities(&mut self, mut f: impl FnMut(NetId) -> bool) {
+        self.0.retain(|(id, _), _| f(*id));
+    }
+
+    pub(super) fn clear(&mut self) {
+        self.0.clear();
+    }
+}
+
+/// Values for entities the server spawns from another server's state, like
+/// after a host migration. Each is given to its entity once it spawns.
+#[derive(Default)]
+pub struct ImportedComponents(HashMap<(NetId, ComponentKind), Box<[u8]>>);
+
+impl ImportedComponents {
+    pub fn import(&mut self, components: &[ComponentValue]) {
+        for value in components {
+            self.0.insert((value.id, value.kind), value.bytes.clone());
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.0.clear();
+    }
+}
+
+/// For each client, what it has of each component.
+#[derive(Default)]
+pub(super) struct ComponentBaselines(HashMap<NetId, HashMap<(NetId, ComponentKind), Baseline>>);
+
+#[derive(Default)]
+struct Baseline {
+    /// The last value put in one of the client's snapshots and which snapshot
+    /// that was, until it's acknowledged.
+    sent: Option<(u32, Box<[u8]>)>,
+    /// The latest value the client is known to have.
+    acked: Option<Box<[u8]>>,
+}
+
+impl ComponentBaselines {
+    /// Components to put in snapshot `sequence` for `client_id`: every value
+    /// the client doesn't have yet, resent until it's acknowledged.
+    pub(super) fn select(
+        &mut self,
+        client_id: NetId,
+        sequence: u32,
+        acks: Option<&SnapshotAcks>,
+        components: &ReplicatedComponents,
+        registry: &ReplicationRegistry,
+        mut relevant: impl FnMut(NetId) -> bool,
+    ) -> Vec<ComponentValue> {
+        let sent = self.0.entry(client_id).or_default();
+        sent.retain(|key, _| components.0.contains_key(key));
+
+        let mut values = Vec::new();
+        for (&(id, kind), bytes) in components.0.iter() {
+            if !registry.in_snapshots(kind) || !relevant(id) {
+                continue;
+            }
+
+            let baseline = sent.entry((id, kind)).or_default();
+            // Acks only cover a window of recent snapshots, so remember what
+            // was acked before it slides past.
+            if let Some((sent_in, _)) = &baseline.sent {
+                if acks.map_or(false, |acks| acks.contains(*sent_in)) {
+                    baseline.acked = baseline.sent.take().map(|(_, bytes)| bytes);
+                }
+            }
+            if baseline.sent.is_none() && baseline.acked.as_ref() == Some(bytes) {
+                continue;
+            }
+
+            baseline.sent = Some((sequence, bytes.clone()));
+            values.push(ComponentValue {
+                kind,
+                id,
+                bytes: bytes.clone(),
+            });
+        }
+
+        values
+    }
+
+    pub(super) fn remove(&mut self, client_id: NetId) {
+        self.0.remove(&client_id);
+    }
+
+    pub(super) fn clear(&mut self) {
+        self.0.clear();
+    }
+}
+
+fn collect_replicated<T: Replicate>(
+    registry: Res<ReplicationRegistry>,
+    mut components: ResMut<ReplicatedComponents>,
+    changed_q: Query<(&NetworkId, &T), Changed<T>>,
+    removed: RemovedComponents<T>,
+    network_id_q: Query<&NetworkId>,
+) {
+    let kind = registry.kind::<T>();
+
+    for (net_id, component) in changed_q.iter() {
+        let bytes = bitpack::to_bytes(component).into_boxed_slice();
+        components.0.insert((net_id.value(), kind), bytes);
+    }
+
+    for entity in removed.iter() {
+        if let Ok(net_id) = network_id_q.get(entity) {
+            components.0.remove(&(net_id.value(), kind));
+        }
+    }
+}
+
+fn import_replicated<T: Replicate>(
+    registry: Res<ReplicationRegistry>,
+    mut imported: ResMut<ImportedComponents>,
+    added_q: Query<(Entity, &NetworkId), Added<NetworkId>>,
+    mut commands: Commands,
+) {
+    if imported.0.is_empty() {
+        return;
+    }
+    let kind = registry.kind::<T>();
+
+    for (entity, net_id) in added_q.iter() {
+        let bytes = match imported.0.remove(&(net_id.value(), kind)) {
+            Some(bytes) => bytes,
+            None => continue,
+        };
+        match bitpack::from_bytes::<T>(&bytes) {
+            Ok(value) => {
+                commands.entity(entity).insert(value);
+            }
+            Err(err) => println!(
+                "[S] Malformed imported {} for {}: {}",
+                std::any::type_name::<T>(),
+                net_id.value(),
+                err
+            ),
+        }
+    }
+}
+
+//
+// Client
+//
+
+/// The latest value received for each replicated component. Kept around so
+/// that an entity spawned after its components arrived still gets them.
+#[derive(Default)]
+pub struct ReceivedComponents {
+    values: HashMap<(NetId, ComponentKind), Box<[u8]>>,
+    changed: HashMap<ComponentKind, Vec<NetId>>,
+}
+
+impl ReceivedComponents {
+    pub fn receive(&mut self, components: &[ComponentValue]) {
+        for value in components {
+            self.values
+                .insert((value.id, value.kind), value.bytes.clone());
+            self.changed.entry(value.kind).or_default().push(value.id);
+        }
+    }
+
+    pub(super) fn clear(&mut self) {
+        self.values.clear();
+        self.changed.clear();
+    }
+}
+
+/// How far playback is between the previous snapshot and the current one.
+#[derive(Default)]
+pub(super) struct SnapshotPlayback {
+    pub sequence: u32,
+    pub t: f32,
+}
+
+fn apply_replicated<T: Replicate>(
+    mut last_sequence: Local<u32>,
+    registry: Res<ReplicationRegistry>,
+    playback: Res<SnapshotPlayback>,
+    interpolator: Option<Res<Interpolator<T>>>,
+    mut received: ResMut<ReceivedComponents>,
+    mut commands: Commands,
+    entity_map: Res<NetworkEntityMap>,
+    added_q: Query<(Entity, &NetworkId), Added<NetworkId>>,
+    mut lerp_q: Query<(&mut T, &mut ReplicatedLerp<T>)>,
+) {
+    let kind = registry.kind::<T>();
+
+    // A new snapshot started playing, blend on from where the last one ended.
+    if playback.sequence != *last_sequence {
+        *last_sequence = playback.sequence;
+        for (_, mut lerp) in lerp_q.iter_mut() {
+            lerp.from = lerp.to.clone();
+        }
+    }
+
+    let changed = received.changed.remove(&kind).unwrap_or_default();
+    let targets = changed
+        .into_iter()
+        .filter_map(|id| Some((entity_map.entity(id)?, id)))
+        .chain(
+            added_q
+                .iter()
+                .map(|(entity, net_id)| (entity, net_id.value())),
+        );
+
+    for (entity, id) in targets {
+        let bytes = match received.values.get(&(id, kind)) {
+            Some(bytes) => bytes,
+            None => continue,
+        };
+        let value = match bitpack::from_bytes::<T>(bytes) {
+            Ok(value) => value,
+            Err(err) => {
+                println!(
+                    "[C] Malformed {} for {}: {}",
+                    std::any::type_name::<T>(),
+                    id,
+                    err
+                );
+                continue;
+            }
+        };
+
+        if interpolator.is_none() {
+            commands.entity(entity).insert(value);
+        } else if let Ok((_, mut lerp)) = lerp_q.get_mut(entity) {
+            lerp.to = value;
+        } else {
+            commands
+                .entity(entity)
+                .insert(value.clone())
+                .insert(ReplicatedLerp {
+                    from: value.clone(),
+                    to: value,
+                });
+        }
+    }
+
+    if let Some(interpolator) = interpolator {
+        for (mut component, lerp) in lerp_q.iter_mut() {
+            *component = (interpolator.0)(&lerp.from, &lerp.to, playback.t);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT: NetId = 1;
+    const ENTITY: NetId = 7;
+    const KIND: ComponentKind = 0;
+
+    fn components(bytes: &[u8]) -> ReplicatedComponents {
+        let mut components = ReplicatedComponents::default();
+        components.0.insert((ENTITY, KIND), bytes.into());
+        components
+    }
+
+    fn select(
+        baselines: &mut ComponentBaselines,
+        sequence: u32,
+        acks: &SnapshotAcks,
+        components: &ReplicatedComponents,
+    ) -> Vec<Box<[u8]>> {
+        let registry = ReplicationRegistry::default();
+        baselines
+            .select(CLIENT, sequence, Some(acks), components, &registry, |_| {
+                true
+            })
+            .into_iter()
+            .map(|value| value.bytes)
+            .collect()
+    }
+
+    fn acks(sequences: &[u32]) -> SnapshotAcks {
+        let mut acks = SnapshotAcks::default();
+        for sequence in sequences {
+            acks.record(*sequence);
+        }
+        acks
+    }
+
+    #[test]
+    fn lost_values_are_resent() {
+        let mut baselines = ComponentBaselines::default();
+        let components = components(&[1]);
+
+        assert_eq!(select(&mut baselines, 1, &acks(&[]), &components).len(), 1);
+        // Snapshot 1 never arrived.
+        assert_eq!(select(&mut baselines, 2, &acks(&[0]), &components).len(), 1);
+        assert_eq!(
+            select(&mut baselines, 3, &acks(&[0, 2]), &components).len(),
+            0
+        );
+    }
+
+    #[test]
+    fn acked_values_are_not_resent() {
+        let mut baselines = ComponentBaselines::default();
+        let components = components(&[1]);
+
+        assert_eq!(select(&mut baselines, 1, &acks(&[]), &components).len(), 1);
+        assert_eq!(select(&mut baselines, 2, &acks(&[1]), &components).len(), 0);
+        assert_eq!(
+            select(&mut baselines, 3, &acks(&[1, 2]), &components).len(),
+            0
+        );
+    }
+
+    #[test]
+    fn acked_values_stay_acked_out_of_the_window() {
+        let mut baselines = ComponentBaselines::default();
+        let components = components(&[1]);
+
+        select(&mut baselines, 1, &acks(&[]), &components);
+        assert_eq!(select(&mut baselines, 2, &acks(&[1]), &components).len(), 0);
+        for sequence in 3..100 {
+            let acks = acks(&[sequence - 1]);
+            assert!(!acks.contains(1));
+            assert_eq!(
+                select(&mut baselines, sequence, &acks, &components).len(),
+                0
+            );
+        }
+    }
+
+    #[test]
+    fn changed_values_are_sent() {
+        let mut baselines = ComponentBaselines::default();
+
+        select(&mut baselines, 1, &acks(&[]), &components(&[1]));
+        assert_eq!(
+            select(&mut baselines, 2, &acks(&[1]), &components(&[1])).len(),
+            0
+        );
+        assert_eq!(
+            select(&mut baselines, 3, &acks(&[2]), &components(&[2])),
+            vec![vec![2u8].into_boxed_slice()]
+        );
+        // Changed back before the new value was acked, so the old one isn't
+        // known to be what the client ends up with.
+        assert_eq!(
+            select(&mut baselines, 4, &acks(&[2]), &components(&[1])).len(),
+            1
+        );
+        assert_eq!(
+            select(&mut baselines, 5, &acks(&[4]), &components(&[1])).len(),
+            0
+        );
+    }
+
+    #[test]
+    fn irrelevant_values_are_skipped() {
+        let mut baselines = ComponentBaselines::default();
+        let registry = ReplicationRegistry::default();
+        let selected = baselines.select(CLIENT, 1, None, &components(&[1]), &registry, |_| false);
+        assert!(selected.is_empty());
+    }
+}
@@ -1,13 +1,15 @@
-use bevy::prelude::*;
+use bevy::{ecs::system::SystemParam, prelude::*};
+use bitpack::NetSerialize;
 use physics::prelude::*;
-use serde::{Deserialize, Serialize};
 use transport::NetId;
 
 use crate::{
     cleanup::Cleanup,
-    network::{Client, NetworkId},
+    network::{Client, NetworkId, NetworkRole},
     obstacle::ObstacleBundle,
     player::{LocalPlayer, Player},
+    roster::{PlayerRoster, RosterEvent},
+    snapshot::{ComponentValue, ReplicatedComponents, POSITION_PRECISION, WORLD_MAX, WORLD_MIN},
     AppState,
 };
 
@@ -18,33 +20,97 @@ impl Plugin for SpawnPlugin {
         app.add_system_set(
             SystemSet::on_update(AppState::Game)
                 .with_system(spawn_event)
-                .with_system(despawn_event),
+                .with_system(despawn_event)
+                .with_system(mark_local_player)
+                .with_system(tint_players),
         );
     }
 }
 
-#[derive(Debug, Clone, Copy, Component, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Component, NetSerialize)]
 pub enum SpawnName {
     Player,
     Obstacle,
 }
 
-#[derive(Debug, Clone, Copy, Component, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Component, NetSerialize)]
 pub struct Spawn {
     pub id: NetId,
     pub name: SpawnName,
+    #[net(float(min = WORLD_MIN, max = WORLD_MAX, precision = POSITION_PRECISION))]
     pub position: Vec3,
+    pub rotation: Quat,
+}
+
+/// Everything a client needs to spawn a networked entity as it is now, rather
+/// than as it was first spawned.
+#[derive(Debug, Clone, NetSerialize)]
+pub struct EntityState {
+    pub spawn: Spawn,
+    /// Every replicated component, including ones not sent in snapshots.
+    pub components: Box<[ComponentValue]>,
+}
+
+/// Exports the current state of networked entities on the server.
+#[derive(SystemParam)]
+pub struct EntityStates<'w, 's> {
+    entity_q: Query<
+        'w,
+        's,
+        (
+            &'static NetworkId,
+            &'static SpawnName,
+            &'static Transform,
+            Option<&'static RigidBodyPositionComponent>,
+        ),
+    >,
+    components: Res<'w, ReplicatedComponents>,
+}
+
+impl<'w, 's> EntityStates<'w, 's> {
+    pub fn get(&self, entity: Entity) -> Option<EntityState> {
+        let (net_id, name, transform, body) = self.entity_q.get(entity).ok()?;
+        // Rigid bodies only write back to their transform once per frame.
+        let (position, rotation) = match body {
+            Some(body) => (
+                body.position.translation.vector.into(),
+                body.position.rotation.into(),
+            ),
+            None => (transform.translation, transform.rotation),
+        };
+
+        Some(EntityState {
+            spawn: Spawn {
+                id: net_id.value(),
+                name: *name,
+                position,
+                rotation,
+            },
+            components: self.components.export(net_id.value()),
+        })
+    }
+}
+
+impl Spawn {
+    fn isometry(&self) -> Isometry<Real> {
+        Isometry::from_parts(Vector::from(self.position).into(), self.rotation.into())
+    }
 }
 
 #[derive(Component)]
 pub struct Despawn;
 
+/// The part of a player's model tinted with their roster color.
+#[derive(Component)]
+struct PlayerBody;
+
 fn spawn_event(
     spawn_q: Query<(Entity, &Spawn)>,
     mut commands: Commands,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<StandardMaterial>>,
-    client: Res<Client>,
+    mut meshes: Option<ResMut<Assets<Mesh>>>,
+    mut materials: Option<ResMut<Assets<StandardMaterial>>>,
+    role: Res<State<NetworkRole>>,
+    roster: Res<PlayerRoster>,
 ) {
     for (entity, spawn) in spawn_q.iter() {
         commands.entity(entity).despawn();
@@ -56,63 +122,30 @@ fn spawn_event(
                     .insert(GlobalTransform::identity())
                     .insert(Transform {
                         translation: spawn.position,
+                        rotation: spawn.rotation,
                         ..Default::default()
                     })
                     .insert(Player)
                     .insert(NetworkId::new(spawn.id))
                     .insert(Cleanup)
                     .insert(spawn.name)
-                    .with_children(|child| {
-                        // Capsule
-                        child.spawn_bundle(PbrBundle {
-                            mesh: meshes.add(Mesh::from(bevy::prelude::shape::Capsule::default())),
-                            material: materials.add(Color::WHITE.into()),
-                            transform: Transform {
-                                translation: Vec3::Y,
-                                ..Default::default()
-                            },
-                            ..Default::default()
-                        });
-
-                        // Eyes
-                        let eye_mesh =
-                            meshes.add(Mesh::from(bevy::prelude::shape::Icosphere::default()));
-                        let eye_material = materials.add(Color::BLACK.into());
-                        let eye_left = Vec3::new(-0.2, 1.6, 0.0) - Vec3::Z * 0.4;
-                        let eye_right = Vec3::new(-eye_left.x, eye_left.y, eye_left.z);
-                        let eye_scale = Vec3::ONE * 0.15;
-                        child.spawn_bundle(PbrBundle {
-                            mesh: eye_mesh.clone(),
-                            material: eye_material.clone(),
-                            transform: Transform {
-                                translation: eye_left,
-                                scale: eye_scale,
-                                ..Default::default()
-                            },
-                            ..Default::default()
-                        });
-                        child.spawn_bundle(PbrBundle {
-                            mesh: eye_mesh.clone(),
-                            material: eye_material.clone(),
-                            transform: Transform {
-                                translation: eye_right,
-                                scale: eye_scale,
-                                ..Default::default()
-                            },
-                            ..Default::default()
-                        });
-                    })
                     .id();
 
-                if client.get_id() == spawn.id {
-                    commands.entity(player).insert(LocalPlayer);
+                // Dedicated servers have nothing to render with.
+                if let (Some(meshes), Some(materials)) = (meshes.as_mut(), materials.as_mut()) {
+                    let color = roster
+                        .get(spawn.id)
+                        .map_or(Color::WHITE, |entry| entry.color);
+                    commands
+                        .entity(player)
+                        .with_children(|child| player_model(child, color, meshes, materials));
                 }
 
-                if client.is_host() {
+                if role.current().is_server() {
                     commands
                         .entity(player)
                         .insert_bundle(RigidBodyBundle {
-                            position: spawn.position.into(),
+                            position: spawn.isometry().into(),
                             body_type: RigidBodyType::Dynamic.into(),
                             mass_properties: RigidBodyMassPropsFlags::ROTATION_LOCKED.into(),
                             ..Default::default()
@@ -147,31 +180,26 @@ fn spawn_event(
                     .insert(GlobalTransform::identity())
                     .insert(Transform {
                         translation: spawn.position,
+                        rotation: spawn.rotation,
                         ..Default::default()
                     })
                     .insert(NetworkId::new(spawn.id))
                     .insert(Cleanup)
                     .insert(spawn.name)
-                    .with_children(|child| {
-                        child.spawn_bundle(PbrBundle {
-                            mesh: meshes.add(Mesh::from(bevy::prelude::shape::Cube::default())),
-                            material: materials.add(Color::RED.into()),
-                            transform: Transform {
-                                translation: Vec3::Y,
-                                scale: size,
-                                ..Default::default()
-                            },
-                            ..Default::default()
-                        });
-                    })
                     .id();
 
-                if client.is_host() {
+                if let (Some(meshes), Some(materials)) = (meshes.as_mut(), materials.as_mut()) {
+                    commands
+                        .entity(obstacle)
+                        .with_children(|child| obstacle_model(child, size, meshes, materials));
+                }
+
+                if role.current().is_server() {
                     commands
                         .entity(obstacle)
                         .insert_bundle(ObstacleBundle::new(spawn.position))
                         .insert_bundle(RigidBodyBundle {
-                            position: spawn.position.into(),
+                            position: spawn.isometry().into(),
                             body_type: RigidBodyType::KinematicPositionBased.into(),
                             ..Default::default()
                         })
@@ -199,6 +227,128 @@ fn spawn_event(
     }
 }
 
+fn player_model(
+    child: &mut ChildBuilder,
+    color: Color,
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<StandardMaterial>,
+) {
+    // Capsule
+    child
+        .spawn_bundle(PbrBundle {
+            mesh: meshes.add(Mesh::from(bevy::prelude::shape::Capsule::default())),
+            material: materials.add(color.into()),
+            transform: Transform {
+                translation: Vec3::Y,
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .insert(PlayerBody);
+
+    // Eyes
+    let eye_mesh = meshes.add(Mesh::from(bevy::prelude::shape::Icosphere::default()));
+    let eye_material = materials.add(Color::BLACK.into());
+    let eye_left = Vec3::new(-0.2, 1.6, 0.0) - Vec3::Z * 0.4;
+    let eye_right = Vec3::new(-eye_left.x, eye_left.y, eye_left.z);
+    let eye_scale = Vec3::ONE * 0.15;
+    child.spawn_bundle(PbrBundle {
+        mesh: eye_mesh.clone(),
+        material: eye_material.clone(),
+        transform: Transform {
+            translation: eye_left,
+            scale: eye_scale,
+            ..Default::default()
+        },
+        ..Default::default()
+    });
+    child.spawn_bundle(PbrBundle {
+        mesh: eye_mesh,
+        material: eye_material,
+        transform: Transform {
+            translation: eye_right,
+            scale: eye_scale,
+            ..Default::default()
+        },
+        ..Default::default()
+    });
+}
+
+fn obstacle_model(
+    child: &mut ChildBuilder,
+    size: Vec3,
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<StandardMaterial>,
+) {
+    child.spawn_bundle(PbrBundle {
+        mesh: meshes.add(Mesh::from(bevy::prelude::shape::Cube::default())),
+        material: materials.add(Color::RED.into()),
+        transform: Transform {
+            translation: Vec3::Y,
+            scale: size,
+            ..Default::default()
+        },
+        ..Default::default()
+    });
+}
+
+/// Recolors players whose roster entry arrived after they spawned, or changed.
+fn tint_players(
+    mut roster_evr: EventReader<RosterEvent>,
+    roster: Res<PlayerRoster>,
+    materials: Option<ResMut<Assets<StandardMaterial>>>,
+    player_q: Query<(&NetworkId, &Children), With<Player>>,
+    body_q: Query<&Handle<StandardMaterial>, With<PlayerBody>>,
+) {
+    let mut materials = match materials {
+        Some(materials) => materials,
+        None => return,
+    };
+
+    for event in roster_evr.iter() {
+        let id = match event {
+            RosterEvent::Joined(id) | RosterEvent::Changed(id) => *id,
+            RosterEvent::Left(_) => continue,
+        };
+        let color = match roster.get(id) {
+            Some(entry) => entry.color,
+            None => continue,
+        };
+
+        for (net_id, children) in player_q.iter() {
+            if net_id.value() != id {
+                continue;
+            }
+            for handle in children.iter().filter_map(|child| body_q.get(*child).ok()) {
+                if let Some(material) = materials.get_mut(handle) {
+                    if material.base_color != color {
+                        material.base_color = color;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Tags the local client's player. Its id is only known once it connects,
+/// which may be after the player spawned, like on a host taking over.
+fn mark_local_player(
+    client: Option<Res<Client>>,
+    player_q: Query<(Entity, &NetworkId), (With<Player>, Without<LocalPlayer>)>,
+    mut commands: Commands,
+) {
+    let id = match client {
+        Some(client) if client.is_connected() => client.get_id(),
+        _ => return,
+    };
+
+    for (entity, net_id) in player_q.iter() {
+        if net_id.value() == id {
+            commands.entity(entity).insert(LocalPlayer);
+        }
+    }
+}
+
 fn despawn_event(despawn_q: Query<Entity, Added<Despawn>>, mut commands: Commands) {
     for entity in despawn_q.iter() {
         commands.entity(entity).despawn_recursive();
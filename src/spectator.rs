@@ -0,0 +1,176 @@
+use bevy::prelude::*;
+
+use crate::{
+    chat::SystemMessage,
+    network::*,
+    roster::PlayerRoster,
+    run_criteria::{game_server_run_criteria, RoleSystem},
+    spawn::{Despawn, Spawn, SpawnName},
+};
+
+pub struct SpectatorPlugin;
+
+impl Plugin for SpectatorPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<SpectatorCommand>().add_system_set(
+            SystemSet::new()
+                .with_run_criteria(game_server_run_criteria)
+                .label(RoleSystem::Server)
+                .with_system(apply_spectator_commands),
+        );
+    }
+}
+
+/// Server-side, moves a client between playing and spectating. Clients ask
+/// for it with the `/spectate` and `/play` chat commands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpectatorCommand {
+    /// Gives a spectator a player.
+    Promote(NetId),
+    /// Takes a player's entity away, leaving them watching.
+    Demote(NetId),
+}
+
+fn apply_spectator_commands(
+    mut command_evr: EventReader<SpectatorCommand>,
+    mut system_message_evw: EventWriter<SystemMessage>,
+    mut roster: ResMut<PlayerRoster>,
+    entity_map: Res<NetworkEntityMap>,
+    mut commands: Commands,
+) {
+    for command in command_evr.iter() {
+        let (id, spectator) = match command {
+            SpectatorCommand::Promote(id) => (*id, false),
+            SpectatorCommand::Demote(id) => (*id, true),
+        };
+        let (ready, was_spectator) = match roster.get(id) {
+            Some(entry) => (entry.ready, entry.spectator),
+            None => continue,
+        };
+        if was_spectator == spectator {
+            continue;
+        }
+
+        roster.modify(id, |entry| entry.spectator = spectator);
+
+        // Clients that aren't in the game yet get their player, or not, when
+        // they are.
+        if ready {
+            if spectator {
+                if let Some(entity) = entity_map.entity(id) {
+                    commands.entity(entity).insert(Despawn);
+                }
+            } else {
+                commands.spawn().insert(Spawn {
+                    id,
+                    name: SpawnName::Player,
+                    position: Vec3::ZERO,
+                    rotation: Quat::IDENTITY,
+                });
+            }
+        }
+
+        let name = roster.name(id);
+        let text = if spectator {
+            format!("{} is now spectating.", name)
+        } else {
+            format!("{} joined the game.", name)
+        };
+        println!("[S] {}", text);
+        system_message_evw.send(SystemMessage(text));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::schedule::{Stage, SystemStage};
+
+    use super::*;
+    use crate::roster::RosterEntry;
+
+    const ID: NetId = 1;
+
+    fn roster(ready: bool, spectator: bool) -> PlayerRoster {
+        let mut roster = PlayerRoster::default();
+        roster.insert(
+            ID,
+            RosterEntry {
+                name: "Alice".into(),
+                color: Color::WHITE,
+                join_time: 0.0,
+                ping: 0,
+                ready,
+                spectator,
+            },
+        );
+        roster
+    }
+
+    /// Applies `command`, returning the world and the system messages sent.
+    fn apply(roster: PlayerRoster, command: SpectatorCommand) -> (World, Vec<String>) {
+        let mut world = World::new();
+        world.insert_resource(roster);
+        world.init_resource::<NetworkEntityMap>();
+        world.init_resource::<Events<SpectatorCommand>>();
+        world.init_resource::<Events<SystemMessage>>();
+        world
+            .get_resource_mut::<Events<SpectatorCommand>>()
+            .unwrap()
+            .send(command);
+
+        SystemStage::single(apply_spectator_commands).run(&mut world);
+
+        let messages = world
+            .get_resource_mut::<Events<SystemMessage>>()
+            .unwrap()
+            .drain()
+            .map(|SystemMessage(text)| text)
+            .collect();
+        (world, messages)
+    }
+
+    fn is_spectator(world: &World) -> bool {
+        let roster = world.get_resource::<PlayerRoster>().unwrap();
+        roster.get(ID).unwrap().spectator
+    }
+
+    fn spawned(world: &mut World) -> Vec<NetId> {
+        let mut spawn_q = world.query::<&Spawn>();
+        spawn_q.iter(world).map(|spawn| spawn.id).collect()
+    }
+
+    #[test]
+    fn promoted_spectators_get_a_player() {
+        let (mut world, messages) = apply(roster(true, true), SpectatorCommand::Promote(ID));
+        assert!(!is_spectator(&world));
+        assert_eq!(spawned(&mut world), [ID]);
+        assert_eq!(messages, ["Alice joined the game."]);
+    }
+
+    #[test]
+    fn demoted_players_spectate() {
+        let (mut world, messages) = apply(roster(true, false), SpectatorCommand::Demote(ID));
+        assert!(is_spectator(&world));
+        assert!(spawned(&mut world).is_empty());
+        assert_eq!(messages, ["Alice is now spectating."]);
+    }
+
+    #[test]
+    fn unready_spectators_get_a_player_later() {
+        let (mut world, messages) = apply(roster(false, true), SpectatorCommand::Promote(ID));
+        assert!(!is_spectator(&world));
+        assert!(spawned(&mut world).is_empty());
+        assert_eq!(messages.len(), 1);
+    }
+
+    #[test]
+    fn commands_that_change_nothing_are_ignored() {
+        let (mut world, messages) = apply(roster(true, false), SpectatorCommand::Promote(ID));
+        assert!(!is_spectator(&world));
+        assert!(spawned(&mut world).is_empty());
+        assert!(messages.is_empty());
+
+        let (_, messages) = apply(PlayerRoster::default(), SpectatorCommand::Demote(ID));
+        assert!(messages.is_empty());
+    }
+}
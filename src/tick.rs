@@ -0,0 +1,83 @@
+use bevy::{core::FixedTimestep, ecs::schedule::ShouldRun, prelude::*};
+use physics::prelude::*;
+
+use crate::network::{TICK_RATE, TICK_SECONDS};
+
+/// Runs the server simulation at [`TICK_RATE`], independent of the frame rate.
+///
+/// Each tick runs the [`TickStage::Simulate`], [`TickStage::Physics`] and
+/// [`TickStage::Send`] stages in order, as many times a frame as ticks are
+/// due. The physics world is only stepped here.
+pub struct TickPlugin;
+
+impl Plugin for TickPlugin {
+    fn build(&self, app: &mut App) {
+        {
+            let mut configuration = app.world.get_resource_mut::<RapierConfiguration>().unwrap();
+            configuration.timestep_mode = TimestepMode::FixedTimestep;
+        }
+        {
+            let mut integration = app
+                .world
+                .get_resource_mut::<IntegrationParameters>()
+                .unwrap();
+            integration.dt = TICK_SECONDS;
+        }
+
+        app.init_resource::<NetworkTick>()
+            .add_stage_before(
+                PhysicsStages::StepWorld,
+                TickStage::Tick,
+                Schedule::default()
+                    .with_run_criteria(FixedTimestep::step(1.0 / TICK_RATE))
+                    .with_stage(
+                        TickStage::Simulate,
+                        SystemStage::parallel()
+                            .with_system(advance_tick.exclusive_system().at_start()),
+                    )
+                    .with_stage(
+                        TickStage::Physics,
+                        SystemStage::single(step_world_system::<NoUserData>),
+                    )
+                    .with_stage(TickStage::Send, SystemStage::parallel()),
+            )
+            .stage(PhysicsStages::StepWorld, |stage: &mut SystemStage| {
+                stage.set_run_criteria(never)
+            });
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, StageLabel)]
+pub enum TickStage {
+    /// The fixed-rate schedule holding the stages below.
+    Tick,
+    /// Gameplay, before the physics step.
+    Simulate,
+    Physics,
+    /// Sending the state the tick ended with.
+    Send,
+}
+
+/// The current simulation tick, counting from startup.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NetworkTick(pub u32);
+
+pub trait TickAppExt {
+    fn add_tick_system_set(&mut self, stage: TickStage, system_set: SystemSet) -> &mut Self;
+}
+
+impl TickAppExt for App {
+    fn add_tick_system_set(&mut self, stage: TickStage, system_set: SystemSet) -> &mut Self {
+        self.stage(TickStage::Tick, |schedule: &mut Schedule| {
+            schedule.add_system_set_to_stage(stage, system_set)
+        })
+    }
+}
+
+fn advance_tick(mut tick: ResMut<NetworkTick>) {
+    tick.0 = tick.0.wrapping_add(1);
+}
+
+fn never() -> ShouldRun {
+    ShouldRun::No
+}